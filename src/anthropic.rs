@@ -1,20 +1,83 @@
 use serde::{Serialize, Serializer, ser::SerializeMap, Deserialize};
-use crate::llm::{self, LLMApi, ApiResponse, LLMApiError, Message, Role};
+use crate::llm::{self, LLMApi, ModelInfo, ApiResponse, LLMApiError, Message, Role, StreamCallback};
+use crate::http::Transport;
+
+/// Where the messages API lives unless overridden with `AnthropicApi::with_base_url`
+pub const DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnthropicApi {
     #[serde(skip_serializing)]
     secret_key: Option<String>,
     model: Model,
+    #[serde(default)]
+    max_output_tokens: Option<usize>,
+    /// Extra fields added to every request body
+    #[serde(default)]
+    params: serde_json::Map<String, serde_json::Value>,
+    /// Root of the API, `DEFAULT_BASE_URL` when not set
+    #[serde(default)]
+    base_url: Option<String>,
+    /// Whether the system prompt and the latest messages are marked for prompt caching
+    #[serde(default)]
+    prompt_cache: bool,
+    /// Receives responses as they stream in, they are only returned whole when not set
+    #[serde(skip)]
+    on_stream: Option<StreamCallback>,
+    #[serde(skip)]
+    transport: Transport,
 }
 
 impl AnthropicApi {
     pub fn new(key: String, model: Model) -> Self {
         Self {
             secret_key: Some(key), 
-            model: model, 
+            model, 
+            max_output_tokens: None, 
+            params: serde_json::Map::new(), 
+            base_url: None, 
+            prompt_cache: false, 
+            on_stream: None, 
+            transport: Transport::default(), 
         }
     }
+
+    /// Requests at most `n` output tokens instead of the model's maximum
+    pub fn with_max_output_tokens(mut self, n: usize) -> Self {
+        self.max_output_tokens = Some(llm::clamp_output_tokens(&self.model, n));
+        self
+    }
+
+    /// Adds fields the crate does not model, like `top_k`, to every request
+    pub fn with_params(mut self, params: serde_json::Map<String, serde_json::Value>) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Sends requests through a proxy or gateway at `base_url`, which gets `/v1/messages` appended
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = Some(base_url.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Caches the system prompt and the conversation up to the latest messages, so the next
+    /// request reads them at a tenth of the price. Writing the cache costs a quarter more
+    pub fn with_prompt_cache(mut self) -> Self {
+        self.prompt_cache = true;
+        self
+    }
+
+    /// Streams responses, passing their text to `on_stream` as it arrives
+    pub fn with_streaming(mut self, on_stream: StreamCallback) -> Self {
+        self.on_stream = Some(on_stream);
+        self
+    }
+
+    /// Records or replays requests instead of only sending them
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -27,8 +90,16 @@ pub enum Model {
     Opus3,
 }
 
-impl Model {
-    pub fn max_context_tokens(self) -> usize {
+impl ModelInfo for Model {
+    fn name(&self) -> &'static str {
+        match self {
+            Model::Haiku3_5 => "claude-3-5-haiku-latest", 
+            Model::Sonnet3_5 => "claude-3-5-sonnet-latest", 
+            Model::Opus3 => "claude-3-opus-latest", 
+        }
+    }
+
+    fn max_context_tokens(&self) -> usize {
         match self {
             Model::Haiku3_5 => 200_000, 
             Model::Sonnet3_5 => 200_000, 
@@ -36,21 +107,60 @@ impl Model {
         }
     }
 
-    pub fn max_output_tokens(self) -> usize {
+    fn max_output_tokens(&self) -> usize {
         match self {
             Model::Haiku3_5 => 8192, 
             Model::Sonnet3_5 => 8192, 
             Model::Opus3 => 4096, 
         }
     }
+
+    fn max_image_dimension(&self) -> u32 {
+        8000
+    }
+
+    fn max_image_bytes(&self) -> usize {
+        5 * 1024 * 1024
+    }
+
+    fn max_images(&self) -> usize {
+        100
+    }
+
+    fn input_price(&self) -> f64 {
+        match self {
+            Model::Haiku3_5 => 0.80, 
+            Model::Sonnet3_5 => 3.0, 
+            Model::Opus3 => 15.0, 
+        }
+    }
+
+    fn output_price(&self) -> f64 {
+        match self {
+            Model::Haiku3_5 => 4.0, 
+            Model::Sonnet3_5 => 15.0, 
+            Model::Opus3 => 75.0, 
+        }
+    }
+
+    fn cost(&self, usage: &llm::Usage) -> f64 {
+        // Reading from the cache costs a tenth of the input price and writing to it a quarter more
+        let read = usage.cache_read_tokens.unwrap_or(0);
+        let written = usage.cache_creation_tokens.unwrap_or(0);
+        let uncached = usage.n_input_tokens.saturating_sub(read + written);
+        let input = uncached as f64 + read as f64 * 0.1 + written as f64 * 1.25;
+        (input * self.input_price() + usage.n_output_tokens as f64 * self.output_price()) / 1_000_000.0
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
 struct AnthropicRequest {
     model: Model,
-    system: String,
+    system: Content,
     max_tokens: usize,
     messages: Vec<AnthropicMessage>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -93,18 +203,30 @@ impl From<llm::Content> for Content {
     }
 }
 
+impl Content {
+    /// Marks the end of this content as a cache breakpoint, the prompt up to it is cached
+    pub fn with_cache_breakpoint(self) -> Self {
+        let mut elems = match self {
+            Content::PureText(txt) => vec![ContentElem::Text(txt)], 
+            Content::Mixed(elems) => elems, 
+        };
+        if let Some(last) = elems.pop() {
+            elems.push(ContentElem::CacheBreakpoint(Box::new(last)));
+        }
+        Content::Mixed(elems)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ContentElem {
     Text(String), 
     Image(Image), 
+    /// Another block, with the prompt cached up to and including it
+    CacheBreakpoint(Box<ContentElem>), 
 }
 
-impl Serialize for ContentElem {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut map = serializer.serialize_map(Some(2))?;
+impl ContentElem {
+    fn serialize_entries<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
         match self {
             ContentElem::Text(txt) => {
                 map.serialize_entry("type", "text")?;
@@ -114,7 +236,22 @@ impl Serialize for ContentElem {
                 map.serialize_entry("type", "image")?;
                 map.serialize_entry("source", img)?;
             }
+            ContentElem::CacheBreakpoint(elem) => {
+                elem.serialize_entries(map)?;
+                map.serialize_entry("cache_control", &serde_json::json!({"type": "ephemeral"}))?;
+            }
         }
+        Ok(())
+    }
+}
+
+impl Serialize for ContentElem {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        self.serialize_entries(&mut map)?;
         map.end()
     }
 }
@@ -190,7 +327,7 @@ impl From<Role> for AnthropicRole {
     fn from(role: Role) -> Self {
         match role {
             Role::Assistant => AnthropicRole::Assistant,
-            Role::User => AnthropicRole::User,
+            Role::User | Role::System => AnthropicRole::User,
         }
     }
 }
@@ -203,14 +340,6 @@ pub enum AnthropicResult {
 }
 
 impl AnthropicResult {
-    pub fn is_error(&self) -> bool {
-        matches!(self, Self::Error(_))
-    }
-
-    pub fn is_success(&self) -> bool {
-        matches!(self, Self::Success(_))
-    }
-
     pub fn into_response(self) -> Result<AnthropicResponse, AnthropicError> {
         match self {
             Self::Success(response) => Ok(response),
@@ -236,6 +365,8 @@ pub struct AnthropicResponse {
 pub struct ContentItem {
     #[serde(rename = "type")]
     pub item_type: String,
+    /// Empty for blocks other than text, like thinking or tool use
+    #[serde(default)]
     pub text: String,
 }
 
@@ -246,6 +377,8 @@ pub enum StopReason {
     MaxTokens,
     StopSequence,
     ToolUse,
+    /// The model declined to continue
+    Refusal,
 }
 
 impl TryInto<llm::StopReason> for StopReason {
@@ -255,23 +388,35 @@ impl TryInto<llm::StopReason> for StopReason {
         match self {
             StopReason::EndTurn => Ok(llm::StopReason::EndTurn),
             StopReason::MaxTokens => Ok(llm::StopReason::MaxTokens),
-            StopReason::StopSequence => Err("StopSequence has no equivalent in StopReason"),
-            StopReason::ToolUse => Err("ToolUse has no equivalent in StopReason"),
+            StopReason::StopSequence => Ok(llm::StopReason::StopSequence),
+            // No tools are offered, so whatever the model wrote before using one is all there is
+            StopReason::ToolUse => Ok(llm::StopReason::EndTurn),
+            StopReason::Refusal => Err("Refusal has no equivalent in StopReason"),
         }
     }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct UsageInfo {
+    /// Input tokens after the last cache breakpoint, the cached ones are counted separately
     pub input_tokens: u32,
     pub output_tokens: u32,
+    #[serde(default)]
+    pub cache_creation_input_tokens: Option<u32>,
+    #[serde(default)]
+    pub cache_read_input_tokens: Option<u32>,
 }
 
-impl Into<llm::Usage> for UsageInfo {
-    fn into(self) -> llm::Usage {
+impl From<UsageInfo> for llm::Usage {
+    fn from(usage: UsageInfo) -> Self {
+        let cache_read = usage.cache_read_input_tokens.unwrap_or(0);
+        let cache_creation = usage.cache_creation_input_tokens.unwrap_or(0);
         llm::Usage {
-            n_input_tokens: self.input_tokens as usize, 
-            n_output_tokens: self.output_tokens as usize, 
+            n_input_tokens: (usage.input_tokens + cache_read + cache_creation) as usize, 
+            n_output_tokens: usage.output_tokens as usize, 
+            reasoning_tokens: None, 
+            cache_read_tokens: usage.cache_read_input_tokens.map(|n| n as usize), 
+            cache_creation_tokens: usage.cache_creation_input_tokens.map(|n| n as usize), 
         }
     }
 }
@@ -303,56 +448,206 @@ pub enum ErrorType {
     OverloadedError,
 }
 
-impl Into<LLMApiError> for ErrorType {
-    fn into(self) -> LLMApiError {
-        match self {
-            Self::InvalidRequestError => LLMApiError::InvalidRequestError,
-            Self::AuthenticationError => LLMApiError::AuthenticationError,
-            Self::PermissionError => LLMApiError::PermissionError,
-            Self::NotFoundError => LLMApiError::NotFoundError,
-            Self::RequestTooLarge => LLMApiError::RequestTooLarge,
-            Self::RateLimitError => LLMApiError::RateLimitExceeded,
-            Self::ApiError => LLMApiError::ApiError,
-            Self::OverloadedError => LLMApiError::OverloadedError,
+impl From<ErrorType> for LLMApiError {
+    fn from(error_type: ErrorType) -> Self {
+        match error_type {
+            ErrorType::InvalidRequestError => LLMApiError::InvalidRequestError,
+            ErrorType::AuthenticationError => LLMApiError::AuthenticationError,
+            ErrorType::PermissionError => LLMApiError::PermissionError,
+            ErrorType::NotFoundError => LLMApiError::NotFoundError,
+            ErrorType::RequestTooLarge => LLMApiError::RequestTooLarge,
+            ErrorType::RateLimitError => LLMApiError::RateLimitExceeded,
+            ErrorType::ApiError => LLMApiError::ApiError,
+            ErrorType::OverloadedError => LLMApiError::OverloadedError,
         }
     }
 }
 
+/// One server-sent event of a streamed response
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    MessageStart { message: StreamMessage }, 
+    ContentBlockStart { index: usize, content_block: ContentItem }, 
+    ContentBlockDelta { index: usize, delta: Delta }, 
+    MessageDelta { delta: MessageDelta, usage: DeltaUsage }, 
+    Error { error: AnthropicError }, 
+    /// Pings, the ends of blocks and of the message, which carry nothing needed
+    #[serde(other)]
+    Other, 
+}
+
+/// The start of a streamed response, before any content
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamMessage {
+    pub id: String,
+    pub model: String,
+    pub usage: UsageInfo,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Delta {
+    TextDelta { text: String }, 
+    /// Parts of thinking or tool use blocks
+    #[serde(other)]
+    Other, 
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageDelta {
+    pub stop_reason: Option<StopReason>,
+    pub stop_sequence: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeltaUsage {
+    /// Output tokens of the whole response so far
+    pub output_tokens: u32,
+}
+
+/// A response being put together from the events of its stream
+#[derive(Default)]
+struct MessageStream {
+    message: Option<StreamMessage>,
+    blocks: Vec<ContentItem>,
+    stop_reason: Option<StopReason>,
+    stop_sequence: Option<String>,
+    error: Option<AnthropicError>,
+    parse_error: Option<serde_json::Error>,
+}
+
+impl MessageStream {
+    fn receive(&mut self, data: &str, on_stream: &StreamCallback) {
+        let event = match serde_json::from_str(data) {
+            Ok(event) => event, 
+            Err(e) => {
+                self.parse_error.get_or_insert(e);
+                return;
+            }, 
+        };
+        match event {
+            StreamEvent::MessageStart { message } => self.message = Some(message), 
+            StreamEvent::ContentBlockStart { content_block, .. } => self.blocks.push(content_block), 
+            StreamEvent::ContentBlockDelta { index, delta: Delta::TextDelta { text } } => {
+                if let Some(block) = self.blocks.get_mut(index) {
+                    on_stream.call(Some(&text));
+                    block.text.push_str(&text);
+                }
+            }, 
+            StreamEvent::MessageDelta { delta, usage } => {
+                self.stop_reason = delta.stop_reason;
+                self.stop_sequence = delta.stop_sequence;
+                if let Some(message) = &mut self.message {
+                    message.usage.output_tokens = usage.output_tokens;
+                }
+            }, 
+            StreamEvent::Error { error } => self.error = Some(error), 
+            StreamEvent::ContentBlockDelta { .. } | StreamEvent::Other => {}, 
+        }
+    }
+
+    fn finish(self) -> Result<AnthropicResult, LLMApiError> {
+        if let Some(error) = self.error {
+            return Ok(AnthropicResult::Error(ErrorResponse { response_type: "error".to_string(), error }));
+        }
+        if let Some(e) = self.parse_error {
+            return Err(e.into());
+        }
+        // A stream that ended early has no stop reason
+        let (Some(message), Some(stop_reason)) = (self.message, self.stop_reason) else {
+            return Err(LLMApiError::Other);
+        };
+        Ok(AnthropicResult::Success(AnthropicResponse {
+            content: self.blocks,
+            id: message.id,
+            model: message.model,
+            role: AnthropicRole::Assistant,
+            stop_reason,
+            stop_sequence: self.stop_sequence,
+            message_type: "message".to_string(),
+            usage: message.usage,
+        }))
+    }
+}
+
+impl AnthropicApi {
+    /// Sends a streaming request and puts its events together into a whole response
+    fn post_streamed(&self, url: &str, headers: &[(&str, &str)], secret_key: &str, body: &serde_json::Value, on_stream: &StreamCallback) -> Result<AnthropicResult, LLMApiError> {
+        let mut stream = MessageStream::default();
+        let (status, text) = self.transport.post_streamed(url, headers, secret_key, body, |line| {
+            if let Some(data) = line.strip_prefix("data:") {
+                stream.receive(data.trim_start(), on_stream);
+            }
+        })?;
+        on_stream.call(None);
+
+        // Requests that fail before streaming get a plain json error
+        if !status.is_success() {
+            return Ok(serde_json::from_str(&text)?);
+        }
+        stream.finish()
+    }
+}
+
 impl LLMApi for AnthropicApi {
-    fn max_context_tokens(&self) -> usize {
-        self.model.max_context_tokens()
+    fn model_info(&self) -> &dyn ModelInfo {
+        &self.model
     }
     
     fn prompt(&self, system_msg: &str, msgs: impl IntoIterator<Item = Message>) -> Result<ApiResponse, LLMApiError> {
         let secret_key = self.secret_key.as_ref().ok_or(LLMApiError::AuthenticationError)?;
 
-        let msgs: Vec<AnthropicMessage> = msgs.into_iter().map(|msg| msg.into()).collect();
+        let msgs: Vec<Message> = msgs.into_iter().collect();
+        let max_tokens = llm::output_token_budget(&self.model, self.max_output_tokens, system_msg, &msgs);
+        let mut msgs: Vec<AnthropicMessage> = msgs.into_iter().map(|msg| msg.into()).collect();
+        let mut system = Content::PureText(system_msg.to_string());
+
+        if self.prompt_cache {
+            // The last user message writes the cache for the next turn and the one before it reads
+            // what the last turn wrote, which keeps within the API's limit of four breakpoints
+            system = system.with_cache_breakpoint();
+            for msg in msgs.iter_mut().rev().filter(|msg| matches!(msg.role, AnthropicRole::User)).take(2) {
+                msg.content = msg.content.clone().with_cache_breakpoint();
+            }
+        }
 
         let request_body = AnthropicRequest {
             model: self.model,
-            system: system_msg.to_string(),
-            max_tokens: self.model.max_output_tokens(), 
+            system,
+            max_tokens, 
             messages: msgs,
+            stream: self.on_stream.is_some(),
+        };
+        let mut request_body = serde_json::to_value(&request_body)?;
+        llm::merge_params(&mut request_body, &self.params);
+
+        let headers = [
+            ("x-api-key", secret_key.as_str()),
+            ("anthropic-version", "2023-06-01"),
+            ("content-type", "application/json"),
+        ];
+        let url = format!("{}/v1/messages", self.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL));
+        let result = match &self.on_stream {
+            Some(on_stream) => self.post_streamed(&url, &headers, secret_key, &request_body, on_stream)?, 
+            None => {
+                let (_, body) = self.transport.post(&url, &headers, secret_key, &request_body)?;
+                serde_json::from_str(&body)?
+            }, 
         };
-
-        let client = reqwest::blocking::Client::new();
-        let response = client
-            .post("https://api.anthropic.com/v1/messages")
-            .header("x-api-key", secret_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&request_body)
-            .send()?;
-
-        let body = response.text()?;
-        let result: AnthropicResult = serde_json::from_str(&body)?;
 
         match result {
             AnthropicResult::Success(response) => {
-                let resp = response.content
-                    .first()
-                    .map(|item| item.text.clone())
-                    .unwrap_or(String::new());
+                if matches!(response.stop_reason, StopReason::Refusal) {
+                    return Err(LLMApiError::ContentFiltered);
+                }
+
+                // Responses can be split over several text blocks
+                let resp: String = response.content
+                    .iter()
+                    .filter(|item| item.item_type == "text")
+                    .map(|item| item.text.as_str())
+                    .collect();
 
                 let stop_reason = response.stop_reason
                     .try_into()
@@ -365,6 +660,7 @@ impl LLMApi for AnthropicApi {
                         resp, 
                         stop_reason, 
                         usage, 
+                        alternatives: Vec::new(), 
                     }
                 )
             },
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+/// Remembers the output of read-only commands that succeeded so running one again does not repeat its output.
+/// Any other command may change what they would print, so it clears the cache
+#[derive(Debug, Clone)]
+pub struct OutputCache {
+    read_only: Vec<String>,
+    outputs: HashMap<String, String>,
+}
+
+impl OutputCache {
+    /// `read_only` are the commands, with or without arguments, treated as free of side effects
+    pub fn new(read_only: Vec<String>) -> Self {
+        Self {
+            read_only,
+            outputs: HashMap::new(),
+        }
+    }
+
+    pub fn default_read_only() -> Vec<String> {
+        ["ls", "cat", "pwd", "git status"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    pub fn get(&self, command: &str) -> Option<&str> {
+        self.outputs.get(command.trim()).map(String::as_str)
+    }
+
+    /// Records that `command` ran, with its output if it finished with exit code 0.
+    /// A failure, like `cat` of a missing file, is not cached since it may well succeed next time
+    pub fn record(&mut self, command: &str, output: Option<&str>, exit_code: Option<i32>) {
+        let command = command.trim();
+        if !self.is_read_only(command) {
+            self.clear();
+            return;
+        }
+
+        if let (Some(output), Some(0)) = (output, exit_code) {
+            self.outputs.insert(command.to_string(), output.to_string());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.outputs.clear();
+    }
+
+    /// Whether the command is a single read-only command, anything that could chain, redirect
+    /// or substitute other commands is not
+    fn is_read_only(&self, command: &str) -> bool {
+        if command.contains(['|', ';', '&', '>', '<', '`', '$', '\n']) {
+            return false;
+        }
+
+        self.read_only.iter().any(|read_only| {
+            command == read_only
+                || command.strip_prefix(read_only.as_str()).is_some_and(|args| args.starts_with(' '))
+        })
+    }
+}
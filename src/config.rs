@@ -0,0 +1,108 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use dirs::config_dir;
+
+/// Defaults for the command line, read from `config.toml`. Anything given as a flag or in the
+/// environment takes precedence over the file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Which API to use, as it is given to --api
+    pub api: Option<String>,
+    /// Which model to use, as it is given to --model
+    pub model: Option<String>,
+    /// Seconds a command may print nothing before it is interrupted
+    pub command_timeout: Option<u64>,
+    /// Where session logs are saved instead of the cache directory
+    pub log_dir: Option<PathBuf>,
+    /// Logs beyond this many are deleted oldest first whenever the program starts
+    pub max_log_files: Option<usize>,
+    /// Logs older than this many days are deleted whenever the program starts
+    pub max_log_age_days: Option<u64>,
+    #[serde(default)]
+    pub anthropic: ProviderConfig,
+    #[serde(default)]
+    pub openai: ProviderConfig,
+    #[serde(default)]
+    pub gemini: GeminiConfig,
+    #[serde(default)]
+    pub ollama: OllamaConfig,
+}
+
+/// Settings of a provider whose requests can go to another URL
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfig {
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeminiConfig {
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OllamaConfig {
+    /// Address of the server, as it is given to --ollama-host
+    pub host: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Failed to read config file {}: {e}", path.display()), 
+            Self::Parse(path, e) => write!(f, "Invalid config file {}: {e}", path.display()), 
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(_, e) => Some(e), 
+            Self::Parse(_, e) => Some(e), 
+        }
+    }
+}
+
+impl Config {
+    /// Where the config is read from unless another file is given, e.g. `~/.config/agentic_terminal/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("agentic_terminal").join("config.toml"))
+    }
+
+    /// Reads the config from `path`, or from the default path if it is `None`.
+    /// A missing default file is an empty config, a missing file that was asked for is an error
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true), 
+            None => match Self::default_path() {
+                Some(path) => (path, false), 
+                None => return Ok(Self::default()), 
+            }, 
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text, 
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(Self::default()), 
+            Err(e) => return Err(ConfigError::Io(path, e)), 
+        };
+        Self::parse(&text).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+}
@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use crate::llm::{LLMApi, ModelInfo, ApiResponse, LLMApiError, Message};
+use crate::anthropic::AnthropicApi;
+use crate::openai::OAIApi;
+use crate::gemini::GeminiApi;
+use crate::ollama::OllamaApi;
+use crate::http::Transport;
+
+/// Any of the supported backends, for when the backend is only known at runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AnyApi {
+    Anthropic(AnthropicApi),
+    OpenAI(OAIApi),
+    Gemini(GeminiApi),
+    Ollama(OllamaApi),
+}
+
+impl AnyApi {
+    /// Requests at most `n` output tokens instead of the model's maximum
+    pub fn with_max_output_tokens(self, n: usize) -> Self {
+        match self {
+            AnyApi::Anthropic(api) => AnyApi::Anthropic(api.with_max_output_tokens(n)), 
+            AnyApi::OpenAI(api) => AnyApi::OpenAI(api.with_max_output_tokens(n)), 
+            AnyApi::Gemini(api) => AnyApi::Gemini(api.with_max_output_tokens(n)), 
+            AnyApi::Ollama(api) => AnyApi::Ollama(api.with_max_output_tokens(n)), 
+        }
+    }
+
+    /// Adds fields the crate does not model to every request
+    pub fn with_params(self, params: serde_json::Map<String, serde_json::Value>) -> Self {
+        match self {
+            AnyApi::Anthropic(api) => AnyApi::Anthropic(api.with_params(params)), 
+            AnyApi::OpenAI(api) => AnyApi::OpenAI(api.with_params(params)), 
+            AnyApi::Gemini(api) => AnyApi::Gemini(api.with_params(params)), 
+            AnyApi::Ollama(api) => AnyApi::Ollama(api.with_params(params)), 
+        }
+    }
+
+    /// Records or replays requests instead of only sending them
+    pub fn with_transport(self, transport: Transport) -> Self {
+        match self {
+            AnyApi::Anthropic(api) => AnyApi::Anthropic(api.with_transport(transport)), 
+            AnyApi::OpenAI(api) => AnyApi::OpenAI(api.with_transport(transport)), 
+            AnyApi::Gemini(api) => AnyApi::Gemini(api.with_transport(transport)), 
+            AnyApi::Ollama(api) => AnyApi::Ollama(api.with_transport(transport)), 
+        }
+    }
+}
+
+impl LLMApi for AnyApi {
+    fn prompt(&self, system_msg: &str, msgs: impl IntoIterator<Item = Message>) -> Result<ApiResponse, LLMApiError> {
+        match self {
+            AnyApi::Anthropic(api) => api.prompt(system_msg, msgs),
+            AnyApi::OpenAI(api) => api.prompt(system_msg, msgs),
+            AnyApi::Gemini(api) => api.prompt(system_msg, msgs),
+            AnyApi::Ollama(api) => api.prompt(system_msg, msgs),
+        }
+    }
+
+    fn model_info(&self) -> &dyn ModelInfo {
+        match self {
+            AnyApi::Anthropic(api) => api.model_info(),
+            AnyApi::OpenAI(api) => api.model_info(),
+            AnyApi::Gemini(api) => api.model_info(),
+            AnyApi::Ollama(api) => api.model_info(),
+        }
+    }
+}
+
+/// A chain of backends that moves on to the next one when the current one keeps failing,
+/// e.g. because the provider is down or the context no longer fits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FallbackApi {
+    apis: Vec<AnyApi>,
+    active: usize,
+}
+
+impl FallbackApi {
+    pub fn new(primary: AnyApi, fallbacks: Vec<AnyApi>) -> Self {
+        let mut apis = vec![primary];
+        apis.extend(fallbacks);
+        Self {
+            apis,
+            active: 0,
+        }
+    }
+
+    /// The backend requests currently go to
+    pub fn active(&self) -> &AnyApi {
+        &self.apis[self.active]
+    }
+}
+
+impl LLMApi for FallbackApi {
+    fn prompt(&self, system_msg: &str, msgs: impl IntoIterator<Item = Message>) -> Result<ApiResponse, LLMApiError> {
+        self.active().prompt(system_msg, msgs)
+    }
+
+    fn model_info(&self) -> &dyn ModelInfo {
+        self.active().model_info()
+    }
+
+    fn fall_back(&mut self, error: &LLMApiError) -> bool {
+        let is_recoverable = matches!(
+            error,
+            LLMApiError::NetworkError(_)
+            | LLMApiError::RequestTooLarge
+            | LLMApiError::RateLimitExceeded
+            | LLMApiError::ApiError
+            | LLMApiError::OverloadedError
+        );
+
+        if !is_recoverable || self.active + 1 >= self.apis.len() {
+            return false;
+        }
+        self.active += 1;
+        true
+    }
+}
@@ -0,0 +1,421 @@
+use serde::{Serialize, Deserialize};
+use crate::llm::{self, LLMApi, ModelInfo, ApiResponse, LLMApiError, Message, Role};
+use crate::http::Transport;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeminiApi {
+    #[serde(skip_serializing)]
+    secret_key: Option<String>,
+    model: Model,
+    #[serde(default)]
+    max_output_tokens: Option<usize>,
+    /// Extra fields added to every request body
+    #[serde(default)]
+    params: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip)]
+    transport: Transport,
+}
+
+impl GeminiApi {
+    pub fn new(key: String, model: Model) -> Self {
+        Self {
+            secret_key: Some(key),
+            model,
+            max_output_tokens: None,
+            params: serde_json::Map::new(),
+            transport: Transport::default(),
+        }
+    }
+
+    /// Requests at most `n` output tokens instead of the model's maximum
+    pub fn with_max_output_tokens(mut self, n: usize) -> Self {
+        self.max_output_tokens = Some(llm::clamp_output_tokens(&self.model, n));
+        self
+    }
+
+    /// Adds fields the crate does not model, like `safetySettings`, to every request
+    pub fn with_params(mut self, params: serde_json::Map<String, serde_json::Value>) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Records or replays requests instead of only sending them
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Model {
+    #[serde(rename = "gemini-1.5-pro")]
+    Gemini1_5Pro,
+    #[serde(rename = "gemini-1.5-flash")]
+    Gemini1_5Flash,
+    #[serde(rename = "gemini-2.0-flash")]
+    Gemini2_0Flash,
+}
+
+impl ModelInfo for Model {
+    fn name(&self) -> &'static str {
+        match self {
+            Model::Gemini1_5Pro => "gemini-1.5-pro",
+            Model::Gemini1_5Flash => "gemini-1.5-flash",
+            Model::Gemini2_0Flash => "gemini-2.0-flash",
+        }
+    }
+
+    fn max_context_tokens(&self) -> usize {
+        match self {
+            Model::Gemini1_5Pro => 2_097_152,
+            Model::Gemini1_5Flash => 1_048_576,
+            Model::Gemini2_0Flash => 1_048_576,
+        }
+    }
+
+    fn max_output_tokens(&self) -> usize {
+        8192
+    }
+
+    fn max_image_dimension(&self) -> u32 {
+        // Larger images are scaled down by the API anyway
+        3072
+    }
+
+    fn max_image_bytes(&self) -> usize {
+        // Inline data counts towards the 20 MB limit of the whole request
+        20 * 1024 * 1024
+    }
+
+    fn max_images(&self) -> usize {
+        3000
+    }
+
+    /// Prices of prompts up to 128k tokens, longer ones cost twice as much on 1.5
+    fn input_price(&self) -> f64 {
+        match self {
+            Model::Gemini1_5Pro => 1.25,
+            Model::Gemini1_5Flash => 0.075,
+            Model::Gemini2_0Flash => 0.10,
+        }
+    }
+
+    fn output_price(&self) -> f64 {
+        match self {
+            Model::Gemini1_5Pro => 5.0,
+            Model::Gemini1_5Flash => 0.30,
+            Model::Gemini2_0Flash => 0.40,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest {
+    system_instruction: GeminiContent,
+    contents: Vec<GeminiContent>,
+    generation_config: GenerationConfig,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    max_output_tokens: usize,
+}
+
+/// A turn of the conversation, or the system instruction which has no role
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeminiContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<GeminiRole>,
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+impl From<llm::Message> for GeminiContent {
+    fn from(msg: llm::Message) -> Self {
+        let parts = match msg.content {
+            llm::Content::Single(c) => vec![c.into()],
+            llm::Content::Multiple(cs) => cs.into_iter().map(|c| c.into()).collect(),
+        };
+        Self {
+            role: Some(msg.role.into()),
+            parts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Part {
+    Text(String),
+    InlineData(Blob),
+}
+
+impl Part {
+    fn text(&self) -> Option<&str> {
+        match self {
+            Part::Text(text) => Some(text),
+            Part::InlineData(_) => None,
+        }
+    }
+}
+
+impl From<llm::ContentItem> for Part {
+    fn from(content: llm::ContentItem) -> Self {
+        match content {
+            llm::ContentItem::Text(text) => Part::Text(text),
+            llm::ContentItem::Image(image) => Part::InlineData(image.into()),
+        }
+    }
+}
+
+/// Base64 encoded bytes sent along with the request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Blob {
+    pub mime_type: MediaType,
+    pub data: String,
+}
+
+impl From<llm::Image> for Blob {
+    fn from(image: llm::Image) -> Self {
+        Self {
+            mime_type: image.image_type.into(),
+            data: image.data,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MediaType {
+    #[serde(rename = "image/jpeg")]
+    Jpeg,
+    #[serde(rename = "image/png")]
+    Png,
+    #[serde(rename = "image/gif")]
+    Gif,
+    #[serde(rename = "image/webp")]
+    Webp,
+}
+
+impl From<llm::ImageType> for MediaType {
+    fn from(image_type: llm::ImageType) -> Self {
+        match image_type {
+            llm::ImageType::Jpeg => MediaType::Jpeg,
+            llm::ImageType::Png => MediaType::Png,
+            llm::ImageType::Gif => MediaType::Gif,
+            llm::ImageType::Webp => MediaType::Webp,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeminiRole {
+    Model,
+    User,
+}
+
+impl From<Role> for GeminiRole {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Assistant => GeminiRole::Model,
+            Role::User | Role::System => GeminiRole::User,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum GeminiResult {
+    Error(ErrorResponse),
+    Success(GeminiResponse),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiResponse {
+    /// Empty when the prompt itself was blocked, see `prompt_feedback`
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    #[serde(default)]
+    pub prompt_feedback: Option<PromptFeedback>,
+    pub usage_metadata: UsageMetadata,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    /// Missing when the candidate was blocked before it produced anything
+    #[serde(default)]
+    pub content: Option<GeminiContent>,
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptFeedback {
+    #[serde(default)]
+    pub block_reason: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FinishReason {
+    Stop,
+    MaxTokens,
+    Safety,
+    Recitation,
+    Blocklist,
+    ProhibitedContent,
+    Spii,
+    #[serde(other)]
+    Other,
+}
+
+impl TryInto<llm::StopReason> for FinishReason {
+    type Error = &'static str;
+
+    fn try_into(self) -> Result<llm::StopReason, Self::Error> {
+        match self {
+            FinishReason::Stop => Ok(llm::StopReason::EndTurn),
+            FinishReason::MaxTokens => Ok(llm::StopReason::MaxTokens),
+            FinishReason::Safety
+            | FinishReason::Recitation
+            | FinishReason::Blocklist
+            | FinishReason::ProhibitedContent
+            | FinishReason::Spii => Err("Filtered responses have no equivalent in StopReason"),
+            FinishReason::Other => Err("Other has no equivalent in StopReason"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u32,
+    #[serde(default)]
+    pub candidates_token_count: u32,
+    #[serde(default)]
+    pub cached_content_token_count: Option<u32>,
+    /// Tokens the model spent thinking, not included in `candidates_token_count`
+    #[serde(default)]
+    pub thoughts_token_count: Option<u32>,
+}
+
+impl From<UsageMetadata> for llm::Usage {
+    fn from(usage: UsageMetadata) -> Self {
+        let thoughts = usage.thoughts_token_count.unwrap_or(0);
+        llm::Usage {
+            n_input_tokens: usage.prompt_token_count as usize,
+            n_output_tokens: (usage.candidates_token_count + thoughts) as usize,
+            reasoning_tokens: usage.thoughts_token_count.map(|n| n as usize),
+            cache_read_tokens: usage.cached_content_token_count.map(|n| n as usize),
+            // Only explicitly created caches are used, this crate never creates one
+            cache_creation_tokens: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorResponse {
+    pub error: GeminiError,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GeminiError {
+    pub code: u16,
+    pub message: String,
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+impl From<GeminiError> for LLMApiError {
+    fn from(error: GeminiError) -> Self {
+        match error.status.as_deref() {
+            Some("INVALID_ARGUMENT" | "FAILED_PRECONDITION") => LLMApiError::InvalidRequestError,
+            Some("UNAUTHENTICATED") => LLMApiError::AuthenticationError,
+            Some("PERMISSION_DENIED") => LLMApiError::PermissionError,
+            Some("NOT_FOUND") => LLMApiError::NotFoundError,
+            Some("RESOURCE_EXHAUSTED") => LLMApiError::RateLimitExceeded,
+            Some("INTERNAL") => LLMApiError::ApiError,
+            Some("UNAVAILABLE") => LLMApiError::OverloadedError,
+            _ => match reqwest::StatusCode::from_u16(error.code) {
+                Ok(status) => status.into(),
+                Err(_) => LLMApiError::Other,
+            },
+        }
+    }
+}
+
+impl LLMApi for GeminiApi {
+    fn model_info(&self) -> &dyn ModelInfo {
+        &self.model
+    }
+
+    fn prompt(&self, system_msg: &str, msgs: impl IntoIterator<Item = Message>) -> Result<ApiResponse, LLMApiError> {
+        let secret_key = self.secret_key.as_ref().ok_or(LLMApiError::AuthenticationError)?;
+
+        let msgs: Vec<Message> = msgs.into_iter().collect();
+        let max_tokens = llm::output_token_budget(&self.model, self.max_output_tokens, system_msg, &msgs);
+
+        let request_body = GeminiRequest {
+            system_instruction: GeminiContent {
+                role: None,
+                parts: vec![Part::Text(system_msg.to_string())],
+            },
+            contents: msgs.into_iter().map(|msg| msg.into()).collect(),
+            generation_config: GenerationConfig {
+                max_output_tokens: max_tokens,
+            },
+        };
+        let mut request_body = serde_json::to_value(&request_body)?;
+        llm::merge_params(&mut request_body, &self.params);
+
+        let url = format!("https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent", self.model.name());
+        let headers = [
+            ("x-goog-api-key", secret_key.as_str()),
+            ("content-type", "application/json"),
+        ];
+        let (_, body) = self.transport.post(&url, &headers, secret_key, &request_body)?;
+        let result: GeminiResult = serde_json::from_str(&body)?;
+
+        match result {
+            GeminiResult::Success(response) => {
+                if response.prompt_feedback.is_some_and(|feedback| feedback.block_reason.is_some()) {
+                    return Err(LLMApiError::ContentFiltered);
+                }
+                let candidate = response.candidates
+                    .into_iter()
+                    .next()
+                    .ok_or(LLMApiError::EmptyResponse)?;
+
+                let stop_reason = match candidate.finish_reason {
+                    Some(FinishReason::Safety | FinishReason::Recitation | FinishReason::Blocklist
+                        | FinishReason::ProhibitedContent | FinishReason::Spii) => return Err(LLMApiError::ContentFiltered),
+                    Some(finish_reason) => finish_reason.try_into().map_err(|_| LLMApiError::Other)?,
+                    None => llm::StopReason::EndTurn,
+                };
+
+                let resp = candidate.content
+                    .map(|content| content.parts.iter().filter_map(Part::text).collect())
+                    .unwrap_or_default();
+
+                let usage = response.usage_metadata.into();
+
+                Ok(
+                    ApiResponse {
+                        resp,
+                        stop_reason,
+                        usage,
+                        alternatives: Vec::new(),
+                    }
+                )
+            },
+            GeminiResult::Error(err) => Err(err.error.into())
+        }
+    }
+}
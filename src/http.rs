@@ -0,0 +1,154 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::llm::LLMApiError;
+use crate::log::{redact_value, Redactor};
+
+/// How a backend's requests reach the provider
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    #[default]
+    Network,
+    /// Sends requests and saves every exchange, with the secret key redacted, and with the recording's
+    /// redactor applied when it has one
+    Record(Recording),
+    /// Answers requests with the exchanges a `Record` transport saved, in order, without the network
+    Replay(Recording),
+}
+
+/// A directory of numbered exchanges, one file per request
+#[derive(Debug, Clone)]
+pub struct Recording {
+    dir: PathBuf,
+    next: Arc<AtomicUsize>,
+    redactor: Option<Redactor>,
+}
+
+/// One request and the raw response to it
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    url: String,
+    request: Value,
+    status: u16,
+    response: String,
+}
+
+impl Recording {
+    fn new(dir: PathBuf, redactor: Option<Redactor>) -> Self {
+        Self {
+            dir,
+            next: Arc::new(AtomicUsize::new(0)),
+            redactor,
+        }
+    }
+
+    fn next_path(&self) -> PathBuf {
+        let n = self.next.fetch_add(1, Ordering::SeqCst);
+        self.dir.join(format!("{n:04}.json"))
+    }
+}
+
+impl Transport {
+    /// Records into `dir`, replacing any earlier recording there. With a `redactor` the secrets it finds
+    /// are scrubbed from the saved requests and responses too, like from the session's log
+    pub fn record(dir: PathBuf, redactor: Option<Redactor>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        // Exchanges left from a longer earlier run would otherwise be replayed after this one's
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let numbered = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| !stem.is_empty() && stem.chars().all(|c| c.is_ascii_digit()));
+            let is_exchange = numbered && path.extension().is_some_and(|ext| ext == "json");
+            if is_exchange {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(Self::Record(Recording::new(dir, redactor)))
+    }
+
+    pub fn replay(dir: PathBuf) -> Self {
+        Self::Replay(Recording::new(dir, None))
+    }
+
+    /// Posts a json body, returning the status and raw body of the response
+    pub(crate) fn post(&self, url: &str, headers: &[(&str, &str)], secret_key: &str, body: &Value) -> Result<(StatusCode, String), LLMApiError> {
+        self.post_streamed(url, headers, secret_key, body, |_| {})
+    }
+
+    /// Like `post`, but passes each line of the response to `on_line` as soon as it arrives,
+    /// e.g. the events of a server-sent event stream. Replayed responses are passed line by line too
+    pub(crate) fn post_streamed(&self, url: &str, headers: &[(&str, &str)], secret_key: &str, body: &Value, on_line: impl FnMut(&str)) -> Result<(StatusCode, String), LLMApiError> {
+        match self {
+            Transport::Network => send(url, headers, body, on_line), 
+            Transport::Record(recording) => {
+                let (status, response) = send(url, headers, body, on_line)?;
+                let mut exchange = Exchange {
+                    url: url.to_string(),
+                    request: serde_json::from_str(&redact(&body.to_string(), secret_key))?,
+                    status: status.as_u16(),
+                    response: redact(&response, secret_key),
+                };
+                if let Some(redactor) = &recording.redactor {
+                    redact_value(&mut exchange.request, redactor);
+                    exchange.response = redactor.redact(&exchange.response);
+                }
+                let path = recording.next_path();
+                if let Err(e) = fs::write(&path, serde_json::to_string_pretty(&exchange)?) {
+                    eprintln!("Failed to record exchange to {}: {e}", path.display());
+                }
+                Ok( (status, response) )
+            }, 
+            Transport::Replay(recording) => {
+                let path = recording.next_path();
+                let exchange: Exchange = match fs::read_to_string(&path) {
+                    Ok(text) => serde_json::from_str(&text)?, 
+                    Err(e) => {
+                        eprintln!("No recorded exchange at {}: {e}", path.display());
+                        return Err(LLMApiError::Other);
+                    }, 
+                };
+                let status = StatusCode::from_u16(exchange.status).map_err(|_| LLMApiError::Other)?;
+                exchange.response.lines().for_each(on_line);
+                Ok( (status, exchange.response) )
+            }, 
+        }
+    }
+}
+
+fn send(url: &str, headers: &[(&str, &str)], body: &Value, mut on_line: impl FnMut(&str)) -> Result<(StatusCode, String), LLMApiError> {
+    let client = reqwest::blocking::Client::new();
+    let mut request = client.post(url);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .json(body)
+        .send()?;
+
+    let status = response.status();
+    let mut text = String::new();
+    for line in BufReader::new(response).lines() {
+        // The body is read as it arrives, so network errors surface as io errors
+        let line = line.map_err(|e| match e.into_inner().map(|inner| inner.downcast::<reqwest::Error>()) {
+            Some(Ok(e)) => LLMApiError::NetworkError(*e), 
+            _ => LLMApiError::Other, 
+        })?;
+        on_line(&line);
+        text.push_str(&line);
+        text.push('\n');
+    }
+    Ok( (status, text) )
+}
+
+fn redact(text: &str, secret_key: &str) -> String {
+    match secret_key.is_empty() {
+        true => text.to_string(), 
+        false => text.replace(secret_key, "<redacted>"), 
+    }
+}
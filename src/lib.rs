@@ -0,0 +1,16 @@
+pub mod llm;
+pub mod anthropic;
+pub mod openai;
+pub mod gemini;
+pub mod ollama;
+pub mod fallback;
+pub mod terminal;
+pub mod log;
+pub mod config;
+pub mod policy;
+pub mod cache;
+pub mod transfer;
+pub mod http;
+pub mod session;
+pub mod tui;
+pub mod replay;
@@ -2,12 +2,48 @@ use serde::{Deserialize, Serialize};
 use std::{thread, time};
 use std::fmt;
 use std::error::Error;
+use std::cmp::Reverse;
 use std::iter;
+use std::sync::Arc;
 
 
 pub trait LLMApi: Serialize {
     fn prompt(&self, system_msg: &str, msgs: impl IntoIterator<Item = Message>) -> Result<ApiResponse, LLMApiError>;
+    fn model_info(&self) -> &dyn ModelInfo;
+
+    /// Called when a request keeps failing, a backend with a fallback switches to it
+    /// and returns true to have the request retried
+    fn fall_back(&mut self, _error: &LLMApiError) -> bool {
+        false
+    }
+}
+
+/// Metadata about a backend's model that is independent of the backend
+pub trait ModelInfo {
+    /// Identifier of the model in the provider's API
+    fn name(&self) -> &str;
     fn max_context_tokens(&self) -> usize;
+    fn max_output_tokens(&self) -> usize;
+    /// Largest width or height in pixels of an image the provider accepts
+    fn max_image_dimension(&self) -> u32;
+    /// Largest base64 encoded image in bytes the provider accepts
+    fn max_image_bytes(&self) -> usize;
+    /// Most images the provider accepts in a single request
+    fn max_images(&self) -> usize;
+    /// Price in USD per million input tokens
+    fn input_price(&self) -> f64;
+    /// Price in USD per million output tokens
+    fn output_price(&self) -> f64;
+
+    /// Tokens `text` takes for this model, by default about four characters per token
+    fn estimate_tokens(&self, text: &str) -> usize {
+        estimate_text_tokens(text)
+    }
+
+    /// Cost in USD of a request with the given usage
+    fn cost(&self, usage: &Usage) -> f64 {
+        (usage.n_input_tokens as f64 * self.input_price() + usage.n_output_tokens as f64 * self.output_price()) / 1_000_000.0
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -15,18 +51,152 @@ pub struct ApiResponse {
     pub resp: String, 
     pub stop_reason: StopReason, 
     pub usage: Usage, 
+    /// Other complete responses when the backend sampled several candidates
+    pub alternatives: Vec<String>, 
+}
+
+type OnStream = dyn Fn(Option<&str>) + Send + Sync;
+
+/// Receives a streamed response as it arrives, each piece of its text and then `None` once it is complete
+#[derive(Clone)]
+pub struct StreamCallback(Arc<OnStream>);
+
+impl StreamCallback {
+    pub fn new(f: impl Fn(Option<&str>) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn call(&self, text: Option<&str>) {
+        (self.0)(text)
+    }
+}
+
+impl fmt::Debug for StreamCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StreamCallback")
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 pub enum StopReason {
     EndTurn, 
     MaxTokens, 
+    /// The response hit one of the request's stop sequences, it may or may not be complete
+    StopSequence, 
+}
+
+/// Where the tokens of a context go, estimated from its length
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContextReport {
+    /// The model's commands, including other responses like masking or viewing an image
+    pub command_tokens: usize, 
+    pub thought_tokens: usize, 
+    /// Terminal output and anything else fed back to the model, apart from images
+    pub output_tokens: usize, 
+    /// The system prompt and the session's notices
+    pub system_tokens: usize, 
+    pub image_tokens: usize, 
+    pub n_masked: usize, 
+    /// Tokens the masked messages would take if they were sent
+    pub masked_tokens: usize, 
 }
 
+impl ContextReport {
+    pub fn total_tokens(&self) -> usize {
+        self.command_tokens + self.thought_tokens + self.output_tokens + self.system_tokens + self.image_tokens
+    }
+}
+
+impl fmt::Display for ContextReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total_tokens().max(1);
+        let rows = [
+            ("Commands", self.command_tokens), 
+            ("Thoughts", self.thought_tokens), 
+            ("Outputs", self.output_tokens), 
+            ("System", self.system_tokens), 
+            ("Images", self.image_tokens), 
+        ];
+        for (name, tokens) in rows {
+            writeln!(f, "{name:<10}{tokens:>9} tokens ({:.0}%)", tokens as f64 * 100.0 / total as f64)?;
+        }
+        writeln!(f, "{:<10}{:>9} tokens", "Total", self.total_tokens())?;
+        write!(f, "Masked {} messages, saving {} tokens", self.n_masked, self.masked_tokens)
+    }
+}
+
+/// Rough cost of a task before running it, from the system prompt and assumptions about each turn
 #[derive(Debug, Clone)]
+pub struct CostEstimate {
+    pub system_tokens: usize, 
+    pub n_turns: usize, 
+    /// Tokens each turn adds to the context, the command and its output
+    pub tokens_per_turn: usize, 
+    /// Tokens the model outputs per turn
+    pub output_tokens_per_turn: usize, 
+}
+
+impl CostEstimate {
+    pub fn new(system_msg: &str, n_turns: usize, tokens_per_turn: usize, output_tokens_per_turn: usize) -> Self {
+        Self {
+            system_tokens: estimate_text_tokens(system_msg), 
+            n_turns, 
+            tokens_per_turn, 
+            output_tokens_per_turn, 
+        }
+    }
+
+    /// Usage of the given turn, counted from 0, which resends the system prompt and every earlier turn
+    pub fn turn_usage(&self, turn: usize) -> Usage {
+        Usage {
+            n_input_tokens: self.system_tokens + turn * self.tokens_per_turn, 
+            n_output_tokens: self.output_tokens_per_turn, 
+            ..Usage::default()
+        }
+    }
+
+    /// Usage summed over all turns
+    pub fn total_usage(&self) -> Usage {
+        (0..self.n_turns)
+            .map(|turn| self.turn_usage(turn))
+            .fold(Usage::default(), |total, usage| Usage {
+                n_input_tokens: total.n_input_tokens + usage.n_input_tokens, 
+                n_output_tokens: total.n_output_tokens + usage.n_output_tokens, 
+                ..Usage::default()
+            })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Usage {
     pub n_input_tokens: usize, 
     pub n_output_tokens: usize, 
+    /// Output tokens the model spent reasoning before its response, included in `n_output_tokens`.
+    /// `None` when the backend does not report them
+    pub reasoning_tokens: Option<usize>, 
+    /// Input tokens read from the provider's prompt cache, included in `n_input_tokens`
+    pub cache_read_tokens: Option<usize>, 
+    /// Input tokens written to the provider's prompt cache, included in `n_input_tokens`
+    pub cache_creation_tokens: Option<usize>, 
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} input tokens", self.n_input_tokens)?;
+        let cache = [
+            self.cache_read_tokens.map(|n| format!("{n} read from cache")), 
+            self.cache_creation_tokens.map(|n| format!("{n} written to cache")), 
+        ];
+        let cache: Vec<String> = cache.into_iter().flatten().collect();
+        if !cache.is_empty() {
+            write!(f, " ({})", cache.join(", "))?;
+        }
+        write!(f, ", {} output tokens", self.n_output_tokens)?;
+        if let Some(reasoning) = self.reasoning_tokens {
+            write!(f, " ({reasoning} reasoning)")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -41,6 +211,10 @@ pub enum LLMApiError {
     RateLimitExceeded,
     ApiError,
     OverloadedError,
+    /// The model finished without any content
+    EmptyResponse,
+    /// The provider's content filter stopped the response or the model refused
+    ContentFiltered,
     Other,
 }
 
@@ -72,6 +246,8 @@ impl fmt::Display for LLMApiError {
             LLMApiError::RateLimitExceeded => write!(f, "Rate limit exceeded"),
             LLMApiError::ApiError => write!(f, "API error"),
             LLMApiError::OverloadedError => write!(f, "Service overloaded"),
+            LLMApiError::EmptyResponse => write!(f, "The model returned no content"),
+            LLMApiError::ContentFiltered => write!(f, "The response was filtered"),
             LLMApiError::Other => write!(f, "Unknown error"),
         }
     }
@@ -89,18 +265,58 @@ impl Error for LLMApiError {
 
 
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LLM<Api: LLMApi> {
     api: Api, 
     system_msg: String, 
     messages: Vec<MaskableMessage>, 
+    /// Text of a message beyond this many bytes is cut off when it is added
+    #[serde(skip)]
+    max_message_bytes: Option<usize>, 
+    #[serde(skip)]
+    auto_mask: Option<AutoMask>, 
+    /// Fraction of the context window beyond which `compact` masks old messages
+    #[serde(skip)]
+    auto_compact: Option<f32>, 
+}
+
+/// Masks large messages a few turns after they are added, unless the model retains them
+#[derive(Debug, Clone)]
+struct AutoMask {
+    over_tokens: usize, 
+    after_turns: usize, 
+    turn: usize, 
+    /// Ids of large messages that are not yet masked and the turn they were added in
+    pending: Vec<(usize, usize)>, 
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum LLMResponse {
     Command(String),
+    /// Runs `command`, then `on_success` if it exits with 0 or `on_failure` otherwise, without waiting for the model
+    Conditional {
+        command: String, 
+        #[serde(default)]
+        on_success: Option<String>, 
+        #[serde(default)]
+        on_failure: Option<String>, 
+    }, 
+    Stream(String),
+    Continue, 
+    Stop, 
+    /// Types a line into the streamed command, e.g. the answer to a prompt from `read -p`
+    Input(String), 
     LLMSee(String),
     MaskContent(usize),
+    /// Shows a masked message once without unmasking it
+    Recall(usize), 
+    /// Keeps a large message from being masked automatically
+    Retain(usize), 
+    /// Writes a file from base64 encoded bytes, for binary files the pty would mangle
+    PutFile { path: String, base64: String }, 
+    /// Hands a file to the caller base64 encoded instead of printing it
+    GetFile(String), 
     UserControl, 
     AgentControl, 
     Exit,
@@ -133,30 +349,203 @@ pub enum LLMResponse {
     }
 }*/
 
+impl LLMResponse {
+    /// Readable rendering for display, e.g. `runs: ls -la` instead of the json
+    pub fn describe(&self) -> String {
+        match self {
+            LLMResponse::Command(cmd) if is_thought_command(cmd) => format!("thinks: {}", cmd.trim_start()[1..].trim()), 
+            LLMResponse::Command(cmd) => format!("runs: {cmd}"), 
+            LLMResponse::Conditional { command, on_success, on_failure } => {
+                let mut description = format!("runs: {command}");
+                if let Some(cmd) = on_success {
+                    description += &format!(", on success: {cmd}");
+                }
+                if let Some(cmd) = on_failure {
+                    description += &format!(", on failure: {cmd}");
+                }
+                description
+            }, 
+            LLMResponse::Stream(cmd) => format!("streams: {cmd}"), 
+            LLMResponse::Continue => "continues the stream".to_string(), 
+            LLMResponse::Stop => "stops the stream".to_string(), 
+            LLMResponse::Input(line) => format!("types: {line}"), 
+            LLMResponse::LLMSee(path) => format!("views image: {path}"), 
+            LLMResponse::MaskContent(id) => format!("masks: {id}"), 
+            LLMResponse::Recall(id) => format!("recalls: {id}"), 
+            LLMResponse::Retain(id) => format!("retains: {id}"), 
+            LLMResponse::PutFile { path, base64 } => format!("writes file: {path} ({} base64 characters)", base64.len()), 
+            LLMResponse::GetFile(path) => format!("retrieves file: {path}"), 
+            LLMResponse::UserControl => "hands control to the user".to_string(), 
+            LLMResponse::AgentControl => "takes back control".to_string(), 
+            LLMResponse::Exit => "exits".to_string(), 
+        }
+    }
+}
+
 impl<Api: LLMApi> LLM<Api> {
     pub fn new(api: Api, system_msg: String) -> Self {
         Self {
-            api: api, 
-            system_msg: system_msg, 
+            api, 
+            system_msg, 
             messages: Vec::new(), 
+            max_message_bytes: None, 
+            auto_mask: None, 
+            auto_compact: None, 
         }
     }
 
-    pub fn max_context_tokens(&self) -> usize {
-        self.api.max_context_tokens()
+    /// Masks messages added later that are estimated at over `over_tokens` once `after_turns` turns
+    /// have passed, off when `None`. Turns are counted with `end_turn`
+    pub fn set_auto_mask(&mut self, over_tokens_after_turns: Option<(usize, usize)>) {
+        self.auto_mask = over_tokens_after_turns.map(|(over_tokens, after_turns)| AutoMask {
+            over_tokens, 
+            after_turns, 
+            turn: 0, 
+            pending: Vec::new(), 
+        });
+    }
+
+    /// Keeps a message from being masked automatically, returning whether it was going to be
+    pub fn retain_message(&mut self, id: usize) -> bool {
+        let Some(auto_mask) = &mut self.auto_mask else {
+            return false;
+        };
+        let n_pending = auto_mask.pending.len();
+        auto_mask.pending.retain(|(pending_id, _)| *pending_id != id);
+        auto_mask.pending.len() < n_pending
+    }
+
+    /// Counts a turn of the model, masking the large messages whose retention window is over
+    /// and returning their ids
+    pub fn end_turn(&mut self) -> Vec<usize> {
+        let Some(auto_mask) = &mut self.auto_mask else {
+            return Vec::new();
+        };
+        auto_mask.turn += 1;
+        let (due, pending) = auto_mask.pending
+            .iter()
+            .partition(|(_, added)| auto_mask.turn - added > auto_mask.after_turns);
+        auto_mask.pending = pending;
+
+        due
+            .into_iter()
+            .map(|(id, _): (usize, usize)| id)
+            .filter(|&id| !self.messages[id].local && !self.messages[id].is_masked && self.mask_message(id))
+            .collect()
+    }
+
+    /// Has `compact` mask messages once the estimated prompt is over `threshold` of the
+    /// context window, e.g. 0.8, off when `None`
+    pub fn set_auto_compact(&mut self, threshold: Option<f32>) {
+        self.auto_compact = threshold;
+    }
+
+    /// Masks messages until the estimated prompt fits under the auto compact threshold, returning
+    /// their ids. Images go first, oldest first, then the largest other messages. Pinned messages
+    /// and those of the current turn are never masked
+    pub fn compact(&mut self) -> Vec<usize> {
+        let Some(threshold) = self.auto_compact else {
+            return Vec::new();
+        };
+        let model = self.api.model_info();
+        let budget = (model.max_context_tokens() as f64 * threshold as f64) as usize;
+        let mut tokens = self.estimated_prompt_tokens();
+        if tokens <= budget {
+            return Vec::new();
+        }
+
+        // The current turn starts at the model's last response
+        let turn_start = self.messages
+            .iter()
+            .rposition(|msg| matches!(msg.msg.role, Role::Assistant))
+            .unwrap_or(self.messages.len());
+        let mut candidates: Vec<(usize, bool, usize)> = self.messages[..turn_start]
+            .iter()
+            .filter(|msg| !msg.is_masked && !msg.pinned && !msg.local)
+            .filter_map(|msg| {
+                let msg_with_id = msg.to_message_with_id()?;
+                let msg_tokens = estimate_input_tokens(model, "", std::slice::from_ref(&msg_with_id));
+                Some( (msg.id, msg.msg.content.has_image(), msg_tokens) )
+            })
+            .collect();
+        // Images first, oldest first, then the largest with the oldest first among equals
+        candidates.sort_by_key(|&(id, has_image, msg_tokens)| match has_image {
+            true => (false, Reverse(0), id), 
+            false => (true, Reverse(msg_tokens), id), 
+        });
+
+        let mut masked_ids = Vec::new();
+        for (id, _, msg_tokens) in candidates {
+            if tokens <= budget {
+                break;
+            }
+            if self.mask_message(id) {
+                tokens = tokens.saturating_sub(msg_tokens);
+                masked_ids.push(id);
+            }
+        }
+        masked_ids.sort();
+        masked_ids
+    }
+
+    /// Cuts off the text of any message added later beyond `max_bytes`, so one huge message cannot fill the context
+    pub fn set_max_message_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_message_bytes = max_bytes;
+    }
+
+    pub fn model_info(&self) -> &dyn ModelInfo {
+        self.api.model_info()
+    }
+
+    /// Tokens the next request would take, estimated locally before it is sent
+    pub fn estimated_prompt_tokens(&self) -> usize {
+        let msgs: Vec<Message> = self.messages
+            .iter()
+            .filter_map(|msg| msg.to_message_with_id())
+            .collect();
+        estimate_input_tokens(self.api.model_info(), &self.system_msg, &msgs)
+    }
+
+    pub fn add_msg(&mut self, mut msg: Message) {
+        if let Some(max_bytes) = self.max_message_bytes {
+            if let Some(n_bytes) = msg.content.truncate_text(max_bytes) {
+                msg.content.extend(format!("\n[Truncated, the message was {n_bytes} bytes and messages are limited to {max_bytes}]").into());
+            }
+        }
+        self.push_msg(msg);
+
+        if let Some(auto_mask) = &mut self.auto_mask {
+            let msg = &self.messages[self.messages.len() - 1].msg;
+            let tokens = estimate_text_tokens_of(&msg.content) + count_images(&msg.content) * TOKENS_PER_IMAGE;
+            if matches!(msg.role, Role::User) && tokens > auto_mask.over_tokens {
+                auto_mask.pending.push( (self.messages.len() - 1, auto_mask.turn) );
+            }
+        }
     }
 
-    pub fn add_msg(&mut self, msg: Message) {
+    /// Adds a message as is, for a reply that is still being received or parsed
+    fn push_msg(&mut self, msg: Message) {
         let id = self.next_msg_id();
         self.messages.push(
             MaskableMessage {
-                id: id, 
+                id, 
                 is_masked: false, 
-                msg: msg, 
+                pinned: false, 
+                local: false, 
+                kind: MessageKind::classify(&msg), 
+                msg, 
             }
         )
     }
 
+    /// Adds a message that is never masked, for reference material or notices that must stay in context
+    pub fn add_pinned_msg(&mut self, msg: Message) {
+        self.add_msg(msg);
+        if let Some(msg) = self.messages.last_mut() {
+            msg.pinned = true;
+        }
+    }
+
     pub fn num_msgs(&self) -> usize {
         self.messages.len()
     }
@@ -177,27 +566,106 @@ impl<Api: LLMApi> LLM<Api> {
         self.messages.len()
     }
 
-    pub fn mask_message(&mut self, id: usize) {
-        self.messages[id].is_masked = true;
+    /// Adds a message that is saved in the log but never sent to the model, e.g. a supervisor's note
+    pub fn add_local_msg(&mut self, msg: Message) {
+        self.add_msg(msg);
+        if let Some(msg) = self.messages.last_mut() {
+            msg.local = true;
+        }
+    }
+
+    /// Masks a message unless it is pinned, returning whether it is now masked.
+    /// There is nothing to mask for an id past the last message
+    pub fn mask_message(&mut self, id: usize) -> bool {
+        let Some(msg) = self.messages.get_mut(id) else {
+            return false;
+        };
+        if !msg.pinned {
+            msg.is_masked = true;
+        }
+        msg.is_masked
+    }
+
+    /// Estimated tokens the context currently spends on each kind of message and what masking saved
+    pub fn context_report(&self) -> ContextReport {
+        let mut report = ContextReport {
+            system_tokens: estimate_text_tokens(&self.system_msg), 
+            ..ContextReport::default()
+        };
+
+        for msg in self.messages.iter().filter(|msg| !msg.local) {
+            let msg_with_id = msg.to_message_with_id_no_mask();
+            let text_tokens = estimate_text_tokens_of(&msg_with_id.content);
+            let image_tokens = count_images(&msg_with_id.content) * TOKENS_PER_IMAGE;
+
+            if msg.is_masked {
+                report.n_masked += 1;
+                report.masked_tokens += text_tokens + image_tokens;
+                continue;
+            }
+
+            report.image_tokens += image_tokens;
+            let tokens = match (msg.msg.role, msg.kind) {
+                (Role::System, _) => &mut report.system_tokens, 
+                (_, MessageKind::Command) => &mut report.command_tokens, 
+                (_, MessageKind::Thought) => &mut report.thought_tokens, 
+                (_, MessageKind::Result) => &mut report.output_tokens, 
+            };
+            *tokens += text_tokens;
+        }
+        report
+    }
+
+    /// Masks the oldest unmasked image messages until at most `max_images` remain, returning the masked ids
+    pub fn mask_excess_images(&mut self, max_images: usize) -> Vec<usize> {
+        let image_ids: Vec<usize> = self.messages
+            .iter()
+            .filter(|msg| !msg.is_masked && !msg.pinned && msg.msg.content.has_image())
+            .map(|msg| msg.id)
+            .collect();
+
+        let n_excess = image_ids.len().saturating_sub(max_images);
+        let masked_ids = image_ids[..n_excess].to_vec();
+        for &id in &masked_ids {
+            self.mask_message(id);
+        }
+        masked_ids
     }
 
     fn prompt_partial_output(&mut self) -> Result<ApiResponse, LLMApiError> {
-        match self.api.prompt(&self.system_msg, self.messages.iter().filter_map(|msg| msg.to_message_with_id())) {
-            Ok(resp) => {
-                Ok(resp)
-            }, 
-            Err(err) => {
-                self.messages.pop();
-                Err(err)
-            }, 
+        self.api.prompt(&self.system_msg, self.messages.iter().filter_map(|msg| msg.to_message_with_id()))
+    }
+
+    /// Lets the backend switch to a fallback after an error it could not recover from
+    fn fall_back(&mut self, err: &LLMApiError) -> bool {
+        let switched = self.api.fall_back(err);
+        if switched {
+            eprintln!("{err}, falling back to {}", self.api.model_info().name());
         }
+        switched
+    }
+
+    /// Prompts the model, the reply holds several responses when the model output more than one json
+    pub fn prompt(&mut self, timeout: time::Duration) -> Result<(Result<Vec<LLMResponse>, serde_json::Error>, Usage), LLMApiError> {
+        self.prompt_with(timeout, |_| 0)
     }
 
-    pub fn prompt(&mut self, timeout: time::Duration) -> Result<(Result<LLMResponse, serde_json::Error>, Usage), LLMApiError> {
+    /// Like `prompt`, but `select` picks which of several sampled candidates becomes the reply
+    pub fn prompt_with(&mut self, timeout: time::Duration, select: impl FnMut(&[String]) -> usize) -> Result<(Result<Vec<LLMResponse>, serde_json::Error>, Usage), LLMApiError> {
+        let (output, usage) = self.prompt_text_with(timeout, select)?;
+        Ok( (parse_responses(&output), usage) )
+    }
+
+    /// Prompts the model and returns its raw reply without parsing it as an `LLMResponse`
+    pub fn prompt_text(&mut self, timeout: time::Duration) -> Result<(String, Usage), LLMApiError> {
+        self.prompt_text_with(timeout, |_| 0)
+    }
+
+    fn prompt_text_with(&mut self, timeout: time::Duration, mut select: impl FnMut(&[String]) -> usize) -> Result<(String, Usage), LLMApiError> {
         let mut error_start_time: Option<time::Instant> = None;
 
         if self.messages.is_empty() {
-            self.add_msg(
+            self.push_msg(
                 Message {
                     role: Role::User,
                     content: "".into(),
@@ -206,9 +674,18 @@ impl<Api: LLMApi> LLM<Api> {
         }
 
         let num_orig_msgs = self.messages.len();
+        let mut retried_empty = false;
         
         loop {
-            match self.prompt_partial_output() {
+            let result = match self.prompt_partial_output() {
+                // Only a reply with nothing at all is empty, a continuation may rightly add nothing
+                Ok(resp) if self.messages.len() == num_orig_msgs && resp.resp.trim().is_empty() && resp.alternatives.is_empty() => {
+                    Err(LLMApiError::EmptyResponse)
+                }, 
+                result => result, 
+            };
+
+            match result {
                 Ok(resp) => {
                     // Reset error timer on success
                     error_start_time = None;
@@ -216,10 +693,13 @@ impl<Api: LLMApi> LLM<Api> {
                     match resp.stop_reason {
                         StopReason::EndTurn => {
                             if self.messages.len() == num_orig_msgs {
-                                self.add_msg(
+                                let mut candidates = resp.alternatives;
+                                candidates.insert(0, resp.resp);
+                                let selected = select(&candidates).min(candidates.len() - 1);
+                                self.push_msg(
                                     Message {
                                         role: Role::Assistant,
-                                        content: resp.resp.into(),
+                                        content: candidates.swap_remove(selected).into(),
                                     }
                                 );
                             } else {
@@ -229,36 +709,29 @@ impl<Api: LLMApi> LLM<Api> {
                                     .extend_with_content(resp.resp.into());
                             }
 
-                            let msg = self.messages.pop().unwrap();
-                            let output_string = <String>::try_from(&msg.msg.content).unwrap();
-                            let trimmed = trim_id_prefix(&output_string);
-                            self.add_msg(
-                                Message {
-                                    role: Role::Assistant, 
-                                    content: trimmed.into(), 
-                                }
-                            );
-
-                            return Ok( (serde_json::from_str(trimmed), resp.usage) );
+                            return Ok( (self.finish_response(), resp.usage) );
                         },
                         StopReason::MaxTokens => {
-                            if self.messages.len() == num_orig_msgs {
-                                self.add_msg(
-                                    Message {
-                                        role: Role::Assistant,
-                                        content: resp.resp.into(),
-                                    }
-                                );
-                            } else {
-                                self.messages
-                                    .last_mut()
-                                    .unwrap()
-                                    .extend_with_content(resp.resp.into());
-                            }
+                            self.append_partial_response(resp.resp, num_orig_msgs);
                             
                             thread::sleep(time::Duration::from_millis(200));
                             continue;
-                        }
+                        },
+                        StopReason::StopSequence => {
+                            self.append_partial_response(resp.resp, num_orig_msgs);
+
+                            // A stop sequence may cut the response short, it is only done if it is already a valid response
+                            let is_complete = self.messages
+                                .last()
+                                .and_then(|msg| String::try_from(&msg.msg.content).ok())
+                                .is_some_and(|txt| parse_responses(trim_id_prefix(&txt)).is_ok());
+                            if is_complete {
+                                return Ok( (self.finish_response(), resp.usage) );
+                            }
+
+                            thread::sleep(time::Duration::from_millis(200));
+                            continue;
+                        },
                     }
                 },
                 Err(err) => {
@@ -270,6 +743,10 @@ impl<Api: LLMApi> LLM<Api> {
                             
                             // Check if we've exceeded timeout since first error
                             if start_time.elapsed() >= timeout {
+                                if self.fall_back(&err) {
+                                    error_start_time = None;
+                                    continue;
+                                }
                                 self.messages.truncate(num_orig_msgs);
                                 return Err(err);
                             }
@@ -277,7 +754,15 @@ impl<Api: LLMApi> LLM<Api> {
                             thread::sleep(time::Duration::from_secs(1));
                             continue;
                         },
+                        // Empty completions are often a fluke, so one more attempt is made
+                        LLMApiError::EmptyResponse if !retried_empty => {
+                            retried_empty = true;
+                            continue;
+                        },
                         _ => {
+                            if self.fall_back(&err) {
+                                continue;
+                            }
                             self.messages.truncate(num_orig_msgs);
                             return Err(err)
                         },
@@ -286,9 +771,40 @@ impl<Api: LLMApi> LLM<Api> {
             }
         }
     }
+
+    /// Starts the assistant message for a response that is not finished yet, or adds to it
+    fn append_partial_response(&mut self, text: String, num_orig_msgs: usize) {
+        if self.messages.len() == num_orig_msgs {
+            self.push_msg(
+                Message {
+                    role: Role::Assistant,
+                    content: text.into(),
+                }
+            );
+        } else {
+            self.messages
+                .last_mut()
+                .unwrap()
+                .extend_with_content(text.into());
+        }
+    }
+
+    /// Replaces the finished assistant message with its text without an id prefix, returning the text
+    fn finish_response(&mut self) -> String {
+        let msg = self.messages.pop().unwrap();
+        let output_string = <String>::try_from(&msg.msg.content).unwrap();
+        let trimmed = trim_id_prefix(&output_string).to_string();
+        self.add_msg(
+            Message {
+                role: Role::Assistant, 
+                content: trimmed.as_str().into(), 
+            }
+        );
+        trimmed
+    }
 }
 
-fn trim_id_prefix(input: &str) -> &str {
+pub(crate) fn trim_id_prefix(input: &str) -> &str {
     // Find the position after ">>" if it exists
     if let Some(pos) = input.find(">>") {
         let prefix = &input[..pos];
@@ -301,13 +817,275 @@ fn trim_id_prefix(input: &str) -> &str {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "MaskableMessageRepr")]
 pub struct MaskableMessage {
     id: usize, 
     is_masked: bool,
+    /// Pinned messages, like reference material or the task, are never masked
+    pinned: bool, 
+    /// Local messages are kept in the log but never sent to the model
+    local: bool, 
+    kind: MessageKind, 
     msg: Message,
 }
 
+// Logs written before messages were tagged have no kind, so it is inferred on load
+#[derive(Deserialize)]
+struct MaskableMessageRepr {
+    id: usize, 
+    is_masked: bool,
+    #[serde(default)]
+    pinned: bool, 
+    #[serde(default)]
+    local: bool, 
+    #[serde(default)]
+    kind: Option<MessageKind>, 
+    msg: Message,
+}
+
+impl From<MaskableMessageRepr> for MaskableMessage {
+    fn from(repr: MaskableMessageRepr) -> Self {
+        Self {
+            id: repr.id, 
+            is_masked: repr.is_masked, 
+            pinned: repr.pinned, 
+            local: repr.local, 
+            kind: repr.kind.unwrap_or_else(|| MessageKind::classify(&repr.msg)), 
+            msg: repr.msg, 
+        }
+    }
+}
+
+/// What a message represents in the session, so reasoning can be told apart from actions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageKind {
+    /// An assistant response that acts on the terminal or the session
+    Command, 
+    /// An assistant `:` comment used to think out loud
+    Thought, 
+    /// Anything fed back to the assistant, e.g. terminal output
+    Result, 
+}
+
+impl MessageKind {
+    pub fn classify(msg: &Message) -> Self {
+        match msg.role {
+            Role::User | Role::System => MessageKind::Result, 
+            Role::Assistant => {
+                let is_thought = parse_response(msg)
+                    .is_some_and(|resp| matches!(resp, LLMResponse::Command(cmd) if is_thought_command(&cmd)));
+
+                match is_thought {
+                    true => MessageKind::Thought, 
+                    false => MessageKind::Command, 
+                }
+            }, 
+        }
+    }
+}
+
+/// Parses a reply as a single response, or leniently as several json values in a row since models
+/// sometimes output e.g. a thought followed by a command
+pub fn parse_responses(s: &str) -> Result<Vec<LLMResponse>, serde_json::Error> {
+    let s = strip_code_fence(s);
+    let error = match serde_json::from_str(s) {
+        Ok(resp) => return Ok(vec![resp]), 
+        Err(e) => e, 
+    };
+
+    match serde_json::Deserializer::from_str(s).into_iter().collect::<Result<Vec<LLMResponse>, _>>() {
+        Ok(resps) if resps.len() > 1 => Ok(resps), 
+        _ => Err(error), 
+    }
+}
+
+/// The body of a markdown code fence around the whole of `s`, e.g. ```json ... ```, or `s` if it is not fenced.
+/// Fences inside the fence are stripped too
+pub fn strip_code_fence(s: &str) -> &str {
+    let mut text = s.trim();
+    while let Some(fenced) = text.strip_prefix("```") {
+        let Some(fenced) = fenced.trim_end().strip_suffix("```") else {
+            break;
+        };
+        // The first line is a language tag like json, unless the fence is on a single line
+        text = match fenced.split_once('\n') {
+            Some((_, body)) => body.trim(), 
+            None => fenced.trim(), 
+        };
+    }
+    text
+}
+
+/// Fixes common ways models mangle the json format: a code fence that is not closed, prose before or
+/// after it, smart quotes, raw newlines or tabs in strings and trailing commas.
+/// Returns `None` if there was nothing to repair, the result may still not be valid
+pub fn repair_json(s: &str) -> Option<String> {
+    let text = strip_code_fence(s);
+
+    // Smart quotes are only replaced when they are the only quotes, otherwise they may be meant literally
+    let text = match text.contains('"') {
+        true => text.to_string(), 
+        false => text.replace(['\u{201c}', '\u{201d}'], "\""), 
+    };
+
+    // Prose around a json object or string
+    let start = text.find(['{', '"']);
+    let end = match start.map(|i| text.as_bytes()[i]) {
+        Some(b'{') => text.rfind('}'), 
+        _ => text.rfind('"'), 
+    };
+    let text = match (start, end) {
+        (Some(start), Some(end)) if start < end => &text[start..=end], 
+        _ => text.as_str(), 
+    };
+
+    let mut repaired = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (in_string, c) {
+            (true, _) if escaped => {
+                escaped = false;
+                repaired.push(c);
+            }, 
+            (true, '\\') => {
+                escaped = true;
+                repaired.push(c);
+            }, 
+            (true, '\n') => repaired.push_str("\\n"), 
+            (true, '\r') => repaired.push_str("\\r"), 
+            (true, '\t') => repaired.push_str("\\t"), 
+            (_, '"') => {
+                in_string = !in_string;
+                repaired.push(c);
+            }, 
+            (false, ',') => {
+                let rest = chars.clone().find(|c| !c.is_whitespace());
+                if !matches!(rest, Some('}' | ']')) {
+                    repaired.push(c);
+                }
+            }, 
+            _ => repaired.push(c), 
+        }
+    }
+
+    (repaired != s).then_some(repaired)
+}
+
+/// The response an assistant message holds, `None` for invalid output or other messages
+fn parse_response(msg: &Message) -> Option<LLMResponse> {
+    match msg.role {
+        Role::Assistant => String::try_from(&msg.content)
+            .ok()
+            .and_then(|txt| serde_json::from_str(strip_code_fence(trim_id_prefix(&txt))).ok()), 
+        Role::User | Role::System => None, 
+    }
+}
+
+/// Adds extra parameters to a request body, fields the request already sets are never replaced
+pub(crate) fn merge_params(body: &mut serde_json::Value, params: &serde_json::Map<String, serde_json::Value>) {
+    if let Some(body) = body.as_object_mut() {
+        for (key, value) in params {
+            body.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+/// Flat token cost assumed for an image, whatever its size
+const TOKENS_PER_IMAGE: usize = 1600;
+
+/// Token count of a request estimated with the model's tokenizer, with a flat cost per image
+fn estimate_input_tokens(model: &dyn ModelInfo, system_msg: &str, msgs: &[Message]) -> usize {
+    let text_tokens = |content: &Content| -> usize {
+        content.items()
+            .iter()
+            .map(|item| match item {
+                ContentItem::Text(txt) => model.estimate_tokens(txt), 
+                ContentItem::Image(_) => 0, 
+            })
+            .sum()
+    };
+    model.estimate_tokens(system_msg) + msgs
+        .iter()
+        .map(|msg| text_tokens(&msg.content) + count_images(&msg.content) * TOKENS_PER_IMAGE)
+        .sum::<usize>()
+}
+
+fn estimate_text_tokens(txt: &str) -> usize {
+    txt.len() / 4
+}
+
+fn estimate_text_tokens_of(content: &Content) -> usize {
+    content.items()
+        .iter()
+        .map(|item| match item {
+            ContentItem::Text(txt) => estimate_text_tokens(txt), 
+            ContentItem::Image(_) => 0, 
+        })
+        .sum()
+}
+
+fn count_images(content: &Content) -> usize {
+    content.items()
+        .iter()
+        .filter(|item| matches!(item, ContentItem::Image(_)))
+        .count()
+}
+
+/// Output tokens to request, `requested` or the model's maximum, reduced when the input
+/// would leave less room than that in the context window
+pub(crate) fn output_token_budget(model: &dyn ModelInfo, requested: Option<usize>, system_msg: &str, msgs: &[Message]) -> usize {
+    let max_tokens = requested
+        .unwrap_or(model.max_output_tokens())
+        .min(model.max_output_tokens());
+    let available = model.max_context_tokens()
+        .saturating_sub(estimate_input_tokens(model, system_msg, msgs))
+        .max(1);
+
+    if available < max_tokens {
+        eprintln!("Warning: requesting only {available} output tokens, the input nearly fills the context window of {}", model.name());
+        return available;
+    }
+    max_tokens
+}
+
+/// Clamps an output token override to the model's maximum, warning when it is too large
+pub(crate) fn clamp_output_tokens(model: &dyn ModelInfo, max_tokens: usize) -> usize {
+    if max_tokens > model.max_output_tokens() {
+        eprintln!("Warning: {} allows at most {} output tokens, not {max_tokens}", model.name(), model.max_output_tokens());
+        return model.max_output_tokens();
+    }
+    max_tokens
+}
+
+/// Whether a command is a `:` no-op whose arguments are only the model's thoughts
+pub fn is_thought_command(cmd: &str) -> bool {
+    let cmd = cmd.trim_start();
+    cmd == ":" || cmd.starts_with(": ")
+}
+
 impl MaskableMessage {
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn is_masked(&self) -> bool {
+        self.is_masked
+    }
+
     pub fn extend_with_content(&mut self, content: Content) {
         self.msg.extend_with_content(content);
     }
@@ -316,8 +1094,13 @@ impl MaskableMessage {
         &self.msg
     }
 
+    /// The parsed response of an assistant message, `None` if it is not valid
+    pub fn response(&self) -> Option<LLMResponse> {
+        parse_response(&self.msg)
+    }
+
     pub fn to_message_with_id(&self) -> Option<Message> {
-        match self.is_masked {
+        match self.is_masked || self.local {
             true => None, 
             false => Some(self.to_message_with_id_no_mask()), 
         }
@@ -325,7 +1108,11 @@ impl MaskableMessage {
 
     pub fn to_message_with_id_no_mask(&self) -> Message {
         let id = self.id;
-        let id_msg = format!("{id}>>");
+        // Marked in the text too, since not every backend has a role for them
+        let id_msg = match self.msg.role {
+            Role::System => format!("{id}>>System: "), 
+            _ => format!("{id}>>"), 
+        };
         let content_with_id = match &self.msg.content {
             Content::Single(c) => {
                 match c {
@@ -364,6 +1151,9 @@ impl Message {
 pub enum Role {
     Assistant, 
     User, 
+    /// Notes the session itself adds, like warnings, as opposed to terminal output.
+    /// Backends without such a role send them as user messages
+    System, 
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -372,14 +1162,60 @@ pub enum Content {
     Multiple(Vec<ContentItem>), 
 }
 
-impl Content {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Content::Single(c) => c.to_string(), 
+            Content::Single(c) => write!(f, "{c}"), 
             Content::Multiple(cs) => cs
                                     .iter()
-                                    .map(|c| c.to_string())
-                                    .collect(), 
+                                    .try_for_each(|c| write!(f, "{c}")), 
+        }
+    }
+}
+
+impl Content {
+    pub fn items(&self) -> &[ContentItem] {
+        match self {
+            Content::Single(c) => std::slice::from_ref(c), 
+            Content::Multiple(cs) => cs, 
+        }
+    }
+
+    /// Cuts the text down to `max_bytes` in total, returning how long it was if it was longer
+    pub fn truncate_text(&mut self, max_bytes: usize) -> Option<usize> {
+        let texts = match self {
+            Content::Single(c) => std::slice::from_mut(c), 
+            Content::Multiple(cs) => cs.as_mut_slice(), 
+        };
+        let n_bytes = texts
+            .iter()
+            .map(|item| match item {
+                ContentItem::Text(txt) => txt.len(), 
+                ContentItem::Image(_) => 0, 
+            })
+            .sum::<usize>();
+        if n_bytes <= max_bytes {
+            return None;
+        }
+
+        let mut remaining = max_bytes;
+        for item in texts {
+            if let ContentItem::Text(txt) = item {
+                let end = (0..=remaining.min(txt.len()))
+                    .rev()
+                    .find(|&i| txt.is_char_boundary(i))
+                    .unwrap_or(0);
+                txt.truncate(end);
+                remaining -= end;
+            }
+        }
+        Some(n_bytes)
+    }
+
+    pub fn has_image(&self) -> bool {
+        match self {
+            Content::Single(c) => matches!(c, ContentItem::Image(_)), 
+            Content::Multiple(cs) => cs.iter().any(|c| matches!(c, ContentItem::Image(_))), 
         }
     }
 
@@ -458,11 +1294,11 @@ pub enum ContentItem {
     Image(Image), 
 }
 
-impl ContentItem {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for ContentItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ContentItem::Text(txt) => txt.to_string(), 
-            ContentItem::Image(img) => img.to_string(), 
+            ContentItem::Text(txt) => write!(f, "{txt}"), 
+            ContentItem::Image(img) => write!(f, "{img}"), 
         }
     }
 }
@@ -503,9 +1339,9 @@ pub struct Image {
     pub data: String, 
 }
 
-impl Image {
-    pub fn to_string(&self) -> String {
-        format!("<{} image>", self.image_type.extension())
+impl fmt::Display for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} image>", self.image_type.extension())
     }
 }
 
@@ -528,9 +1364,11 @@ pub enum ImageType {
 
 
 
-use std::path::Path;
-use std::fs::File;
-use std::io::{self, Read, BufReader};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{Read, BufReader};
+use std::ops::RangeInclusive;
+use std::process::Command;
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 
 #[derive(Debug)]
@@ -538,6 +1376,17 @@ pub enum ImageLoadError {
     FileError(std::io::Error),
     UnsupportedExtension,
     NoExtension,
+    DecodeError(image::ImageError),
+    /// The request for an image URL failed, timed out or was answered with an error status
+    DownloadError(reqwest::Error),
+    /// The image at a URL is larger than is downloaded
+    DownloadTooLarge { max_bytes: usize },
+    /// Neither the downloaded bytes nor the Content-Type header are a supported image format
+    UnsupportedContentType(String),
+    /// pdftoppm could not be run or failed to render the PDF
+    PdfError(String),
+    /// Even downscaled, the image is larger than the provider accepts
+    TooLarge { n_bytes: usize, max_bytes: usize },
 }
 
 impl fmt::Display for ImageLoadError {
@@ -546,6 +1395,12 @@ impl fmt::Display for ImageLoadError {
             Self::FileError(e) => write!(f, "Failed to read file: {}", e),
             Self::UnsupportedExtension => write!(f, "Unsupported image extension"),
             Self::NoExtension => write!(f, "File has no extension"),
+            Self::DecodeError(e) => write!(f, "Failed to decode image: {}", e),
+            Self::DownloadError(e) => write!(f, "Failed to download image: {}", e),
+            Self::DownloadTooLarge { max_bytes } => write!(f, "Image is larger than the {} bytes that are downloaded", max_bytes),
+            Self::UnsupportedContentType(content_type) => write!(f, "Unsupported image type {}", content_type),
+            Self::PdfError(e) => write!(f, "Failed to render PDF: {}", e),
+            Self::TooLarge { n_bytes, max_bytes } => write!(f, "Image is {} bytes encoded, the model accepts at most {}", n_bytes, max_bytes),
         }
     }
 }
@@ -554,6 +1409,8 @@ impl Error for ImageLoadError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             Self::FileError(e) => Some(e),
+            Self::DecodeError(e) => Some(e),
+            Self::DownloadError(e) => Some(e),
             _ => None,
         }
     }
@@ -565,6 +1422,18 @@ impl From<std::io::Error> for ImageLoadError {
     }
 }
 
+impl From<reqwest::Error> for ImageLoadError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::DownloadError(err)
+    }
+}
+
+impl From<image::ImageError> for ImageLoadError {
+    fn from(err: image::ImageError) -> Self {
+        Self::DecodeError(err)
+    }
+}
+
 impl ImageType {
     pub fn extension(&self) -> &'static str {
         match self {
@@ -575,6 +1444,40 @@ impl ImageType {
         }
     }
     
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageType::Jpeg => "image/jpeg",
+            ImageType::Png => "image/png",
+            ImageType::Gif => "image/gif",
+            ImageType::Webp => "image/webp",
+        }
+    }
+    
+    /// Detects the format from the signature at the start of the file, which unlike the
+    /// extension cannot be wrong
+    pub fn from_magic_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some(Self::Png),
+            // JFIF and EXIF files alike start with a start of image marker followed by another marker
+            [0xff, 0xd8, 0xff, ..] => Some(Self::Jpeg),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Self::Gif),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    /// Reads a Content-Type header like `image/png; charset=binary`
+    fn from_mime_type(mime_type: &str) -> Option<Self> {
+        let mime_type = mime_type.split(';').next().unwrap_or("").trim();
+        match mime_type.to_lowercase().as_str() {
+            "image/jpeg" | "image/jpg" => Some(Self::Jpeg),
+            "image/png" => Some(Self::Png),
+            "image/gif" => Some(Self::Gif),
+            "image/webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
     fn from_extension(ext: &str) -> Option<Self> {
         match ext.to_lowercase().as_str() {
             "jpg" | "jpeg" => Some(Self::Jpeg),
@@ -586,22 +1489,28 @@ impl ImageType {
     }
 }
 
+/// Program from poppler that renders PDF pages to images
+pub const PDF_RENDERER: &str = "pdftoppm";
+
 impl Image {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ImageLoadError> {
         let path = path.as_ref();
         
-        // Get image type from extension
-        let image_type = path.extension()
-            .ok_or(ImageLoadError::NoExtension)?
-            .to_str()
-            .and_then(ImageType::from_extension)
-            .ok_or(ImageLoadError::UnsupportedExtension)?;
-        
         // Read file with buffered reader
         let file = File::open(path)?;
         let mut reader = BufReader::new(file);
         let mut buffer = Vec::new();
         reader.read_to_end(&mut buffer)?;
+
+        // Get image type from the file's signature, or from the extension if it has none we know
+        let image_type = match ImageType::from_magic_bytes(&buffer) {
+            Some(image_type) => image_type,
+            None => path.extension()
+                .ok_or(ImageLoadError::NoExtension)?
+                .to_str()
+                .and_then(ImageType::from_extension)
+                .ok_or(ImageLoadError::UnsupportedExtension)?,
+        };
         
         // Convert to base64
         let data = BASE64.encode(&buffer);
@@ -611,6 +1520,148 @@ impl Image {
             data,
         })
     }
+
+    /// Downloads an image, giving up after `timeout` or once it turns out larger than `max_bytes`.
+    /// The type comes from the image's signature, or from the Content-Type header if it has none we know
+    pub fn from_url(url: &str, max_bytes: usize, timeout: std::time::Duration) -> Result<Self, ImageLoadError> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .build()?;
+        let response = client.get(url).send()?.error_for_status()?;
+        if response.content_length().is_some_and(|n_bytes| n_bytes > max_bytes as u64) {
+            return Err(ImageLoadError::DownloadTooLarge { max_bytes });
+        }
+        let content_type = response.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+
+        // The length header may be missing or wrong, so stop reading one byte past the limit
+        let mut buffer = Vec::new();
+        response.take(max_bytes as u64 + 1).read_to_end(&mut buffer)?;
+        if buffer.len() > max_bytes {
+            return Err(ImageLoadError::DownloadTooLarge { max_bytes });
+        }
+
+        let image_type = ImageType::from_magic_bytes(&buffer)
+            .or_else(|| ImageType::from_mime_type(&content_type))
+            .ok_or(ImageLoadError::UnsupportedContentType(content_type))?;
+        Ok(Image {
+            image_type,
+            data: BASE64.encode(&buffer),
+        })
+    }
+
+    /// Renders `pages` of a PDF, numbered from 1, to a PNG each with poppler's pdftoppm.
+    /// Pages past the end of the document are left out
+    pub fn from_pdf<P: AsRef<Path>>(path: P, pages: RangeInclusive<u32>) -> Result<Vec<Self>, ImageLoadError> {
+        Self::from_pdf_with(Path::new(PDF_RENDERER), path, pages)
+    }
+
+    /// Like `from_pdf`, rendering with `renderer`, a program taking pdftoppm's arguments
+    pub fn from_pdf_with<P: AsRef<Path>>(renderer: &Path, path: P, pages: RangeInclusive<u32>) -> Result<Vec<Self>, ImageLoadError> {
+        let out_dir = std::env::temp_dir().join(format!("agentic_terminal_pdf_{}", uuid::Uuid::now_v7()));
+        fs::create_dir_all(&out_dir)?;
+        let images = Self::render_pdf(renderer, path.as_ref(), pages, &out_dir);
+        let _ = fs::remove_dir_all(&out_dir);
+        images
+    }
+
+    fn render_pdf(renderer: &Path, path: &Path, pages: RangeInclusive<u32>, out_dir: &Path) -> Result<Vec<Self>, ImageLoadError> {
+        // 100 dpi keeps a letter page at 850x1100, readable without being downscaled much
+        let output = Command::new(renderer)
+            .args(["-png", "-r", "100"])
+            .args(["-f", &pages.start().to_string(), "-l", &pages.end().to_string()])
+            .arg(path)
+            .arg(out_dir.join("page"))
+            .output()
+            .map_err(|e| ImageLoadError::PdfError(format!("could not run {}, is poppler installed? {e}", renderer.display())))?;
+        if !output.status.success() {
+            return Err(ImageLoadError::PdfError(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+
+        // Page numbers in the names are zero padded, so they sort in order
+        let mut page_paths = fs::read_dir(out_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, _>>()?;
+        page_paths.sort();
+        page_paths.into_iter().map(Self::from_file).collect()
+    }
+
+    /// Reads an image like `from_file`, downscaled so neither side is longer than `max_dimension`,
+    /// for screenshots that would cost far more tokens at full resolution. Smaller images are kept as they are
+    pub fn from_file_scaled<P: AsRef<Path>>(path: P, max_dimension: u32) -> Result<Self, ImageLoadError> {
+        Self::from_file(path)?.scaled(max_dimension)
+    }
+
+    /// Downscales the image so neither side is longer than `max_dimension`, if it is larger
+    pub fn scaled(self, max_dimension: u32) -> Result<Self, ImageLoadError> {
+        let (bytes, width, height) = self.decode_dimensions()?;
+        if width.max(height) <= max_dimension {
+            return Ok(self);
+        }
+
+        let original = image::load_from_memory(&bytes)?;
+        self.encode_resized(&original, max_dimension)
+    }
+
+    /// Downscales the image until it fits the model's limits on dimensions and size.
+    /// Returns a note on what was done if the image had to be changed
+    pub fn fit_to_limits(self, model: &dyn ModelInfo) -> Result<(Self, Option<String>), ImageLoadError> {
+        let max_dimension = model.max_image_dimension();
+        let max_bytes = model.max_image_bytes();
+
+        let (bytes, width, height) = self.decode_dimensions()?;
+        if width.max(height) <= max_dimension && self.data.len() <= max_bytes {
+            return Ok( (self, None) );
+        }
+
+        let original = image::load_from_memory(&bytes)?;
+        let mut bound = max_dimension.min(width.max(height));
+        // Each pass halves the area until the encoded image is small enough
+        for _ in 0..8 {
+            let resized = self.encode_resized(&original, bound)?;
+            if resized.data.len() <= max_bytes {
+                let (_, new_width, new_height) = resized.decode_dimensions()?;
+                let note = format!(
+                    "Image downscaled from {width}x{height} to {new_width}x{new_height} to fit the limits of {}",
+                    model.name(),
+                );
+                return Ok( (resized, Some(note)) );
+            }
+            bound = bound * 7 / 10;
+        }
+
+        Err(ImageLoadError::TooLarge { n_bytes: self.data.len(), max_bytes })
+    }
+
+    /// The image's raw bytes along with its width and height, read from its header
+    fn decode_dimensions(&self) -> Result<(Vec<u8>, u32, u32), ImageLoadError> {
+        let bytes = BASE64.decode(&self.data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let (width, height) = image::ImageReader::new(std::io::Cursor::new(&bytes))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        Ok( (bytes, width, height) )
+    }
+
+    /// Resizes `original` to fit in a `bound` square and encodes it, JPEGs stay JPEGs and
+    /// everything else becomes a PNG
+    fn encode_resized(&self, original: &image::DynamicImage, bound: u32) -> Result<Self, ImageLoadError> {
+        let resized = original.resize(bound, bound, image::imageops::FilterType::Triangle);
+        let (image_type, format) = match self.image_type {
+            ImageType::Jpeg => (ImageType::Jpeg, image::ImageFormat::Jpeg),
+            _ => (ImageType::Png, image::ImageFormat::Png),
+        };
+        let mut encoded = std::io::Cursor::new(Vec::new());
+        match image_type {
+            ImageType::Jpeg => resized.to_rgb8().write_to(&mut encoded, format)?,
+            _ => resized.write_to(&mut encoded, format)?,
+        }
+
+        Ok(Image { image_type, data: BASE64.encode(encoded.into_inner()) })
+    }
 }
 
 
@@ -620,31 +1671,83 @@ impl Image {
 
 
 
-fn output_examples() -> [LLMResponse; 6] {
+fn output_examples() -> [LLMResponse; 15] {
     [
         LLMResponse::Command("echo \"hello\"".to_string()),
+        LLMResponse::Conditional { command: "cargo build".to_string(), on_success: Some("cargo test".to_string()), on_failure: None },
+        LLMResponse::Stream("tail -f server.log".to_string()),
+        LLMResponse::Continue, 
+        LLMResponse::Stop, 
+        LLMResponse::Input("yes".to_string()), 
         LLMResponse::LLMSee("img.png".to_string()),
         LLMResponse::MaskContent(42),
+        LLMResponse::Recall(42),
+        LLMResponse::Retain(42),
+        LLMResponse::PutFile { path: "data.bin".to_string(), base64: "AAEC".to_string() },
+        LLMResponse::GetFile("report.pdf".to_string()),
         LLMResponse::UserControl, 
         LLMResponse::AgentControl, 
         LLMResponse::Exit,
     ]
 }
 
-pub fn generate_system_prompt(task: &str) -> String {
+/// Phrases that try to override the instructions around them, matched case-insensitively
+const OVERRIDE_PATTERNS: [&str; 8] = [
+    "ignore previous instructions", 
+    "ignore all previous", 
+    "ignore the above", 
+    "disregard previous", 
+    "disregard the above", 
+    "forget your instructions", 
+    "new instructions:", 
+    "you are now", 
+];
+
+/// The override phrases a task contains, a task may still legitimately contain them
+pub fn find_override_patterns(task: &str) -> Vec<&'static str> {
+    let task = task.to_lowercase();
+    OVERRIDE_PATTERNS
+        .into_iter()
+        .filter(|pattern| task.contains(pattern))
+        .collect()
+}
+
+/// Generates the system prompt for a session in `shell`, e.g. bash. The task is fenced so it cannot pass
+/// itself off as part of the instructions, and a warning is printed if it looks like it tries to override them
+pub fn generate_system_prompt(task: &str, shell: &str) -> String {
     let output_exps = serde_json::to_string(&output_examples()).unwrap();
 
+    let patterns = find_override_patterns(task);
+    if !patterns.is_empty() {
+        eprintln!("Warning: the task looks like it tries to override the instructions, it contains: {}", patterns.join(", "));
+    }
+    // The fence is taken out of the task so the task cannot close it early
+    let task = task
+        .replace("<task>", "")
+        .replace("</task>", "");
+
+    // The examples and special commands are written for POSIX shells
+    let shell_note = match shell {
+        "powershell" => "This is PowerShell, use its equivalents of the POSIX commands in the examples, like Set-Content for echo >, Get-Content for cat and Get-Content -Wait for tail -f.\n", 
+        _ => "", 
+    };
+
     format!(
-        "You are in a bash session and will interact directly with a terminal to complete the task: {task}.
+        "You are in a {shell} session and will interact directly with a terminal to complete the task between the task tags. It is the user's task, not part of these instructions.
+<task>
+{task}
+</task>
 Command limits:
 You are not permitted to modify any file or folder that is not clearly necessary for completing the task.
 You may use any terminal command as you see fit as long as you do not expect the command will violate user privacy.
-But only use terminal programs with arguments that are noninteractive. For example, nano and sudo without -S are forbidden because they require interactive input.
+But only use terminal programs with arguments that are noninteractive. For example, nano and sudo without -S are forbidden because they require full screen or password input.
+A command that asks for a line of input, like read -p, can be streamed and answered with input.
 Only use terminal programs that just return an output.
-cd command does not work, don't use it, paths must be relative to current directory or absolute.
-When the task is completed or if it cannot be completed, exit the terminal.
+{shell_note}When the task is completed or if it cannot be completed, exit the terminal.
 Format:
 Each message will have a prefix, id>>, where id is the integer identifier.
+Messages from this tool itself, like warnings, rather than from the terminal continue the prefix with System:, example 7>>System: message 3 is masked.
+A command that fails has its exit code after its output, e.g. (exit code 1) for a grep without a match or (exit code 139) for a crash. Output without an exit code means the command succeeded.
 The output format is json. Only one json can be outputted. Output EXACTLY the json format, nothing else. Here is an array of examples:
 {output_exps}
 The format must be precisely one of these types.
@@ -664,9 +1767,24 @@ Note due to json quirks you must use two slashes for newlines \\\\n within strin
 This is neccessary.
 Everything you output must be a single line terminal command. If you need to think or just say something, use the colon command, example : \"my thoughts must be in quotes\".
 Special Commands:
-llmsee img_path, lets you see an image, no other command works for viewing images.
-maskcontent id, masks the content with the specified id which frees space in the context window, use for content that takes up significant space (like documents/codefiles/etc) and is no longer expected to be needed.
+conditional, runs command and then on_success if it exits with 0 or on_failure otherwise without waiting for your next message, either may be left out. Each is a single command, conditionals cannot be nested.
+llmsee img_path, lets you see an image, img_path may also be an http or https URL, or a PDF whose pages are shown as images. No other command works for viewing images.
+maskcontent id, masks the content with the specified id which frees space in the context window, use for content that takes up significant space (like documents/codefiles/etc) and is no longer expected to be needed. Protected messages, like reference material and control switch notices, cannot be masked.
 Be especially aggressive with this for images as they take up significant context, often only a single image is needed in the entire context at a time.
+recall id, shows the content of a masked message once as a new message, the original stays masked and is not unmasked. Mask the new message again once you have what you needed.
+retain id, keeps a large message from being masked automatically. Some sessions mask messages over a size limit a few turns after they arrive, retain those you will still need.
+stream command, runs a single line command whose output is long or never ends (tail -f, long test suites) and shows it to you in chunks.
+continue and stop, after each chunk of a streamed command continue reads more and stop interrupts it, any other response also stops it.
+input line, after a chunk of a streamed command types the line into it, for a command waiting on a prompt. Example:
+LLM: 5>>{{\"Stream\":\"read -p 'Overwrite? ' answer; echo $answer\"}}
+Terminal: 6>>Overwrite? 
+Command still running, respond Continue to read more, Input to type a line into it, or Stop to interrupt it.
+LLM: 7>>{{\"Input\":\"yes\"}}
+Terminal: 8>>yes
+A streamed command is interrupted automatically once it has run for too long in total.
+putfile path base64, writes a file from base64 encoded bytes, use it instead of echo or printf for binary files.
+getfile path, hands a file to the user base64 encoded, use it to deliver binary results like a generated pdf. You do not see its contents.
+Relative paths are resolved against the shell's working directory. Files can only be transferred within the transfer directory and their size is limited.
 usercontrol, hands control to the user, use this if you cannot do something yourself, for example don't know passcode.
 agentcontrol, hands back control to you, you never call this.
 Context:
@@ -1,29 +1,402 @@
-use std::path::PathBuf;
-use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufWriter, Write};
 use std::fs::File;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use dirs::cache_dir;
+use regex::Regex;
 use uuid::Uuid;
-use super::llm::{LLM, LLMApi};
+use serde::{Deserialize, Serialize};
+use super::llm::{LLM, LLMApi, MaskableMessage, MessageKind, Role, ContentItem};
+use super::terminal::TerminalLike;
 
-pub fn save_session_log(llm: &LLM<impl LLMApi>) -> std::io::Result<()> {
-    let log_dir = get_log_dir();
+/// Information about a session that is not part of the conversation itself
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionMetadata {
+    /// Identifier of the model the session ran with
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Plaintext recap produced by the model when the session ended
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Whether the user rather than the model was issuing commands
+    #[serde(default)]
+    pub user_control: bool,
+    /// Number of assistant turns taken so far
+    #[serde(default)]
+    pub n_steps: usize,
+    /// Estimated spend in USD on model requests so far
+    #[serde(default)]
+    pub total_cost: f64,
+    /// Most input tokens any request used, as counted by the provider
+    #[serde(default)]
+    pub peak_input_tokens: usize,
+    /// Input tokens of all requests so far, cached ones included
+    #[serde(default)]
+    pub total_input_tokens: usize,
+    /// Output tokens of all requests so far
+    #[serde(default)]
+    pub total_output_tokens: usize,
+    /// Where the session ran, captured when it started
+    #[serde(default)]
+    pub environment: Option<Environment>,
+}
+
+/// Environment variables recorded in the log, secrets among them are redacted
+const RECORDED_VARS: [&str; 6] = ["PATH", "HOME", "USER", "SHELL", "LANG", "TERM"];
+
+/// The system a session ran on, to reproduce or debug it later
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Environment {
+    /// Output of `uname -srm` where the shell runs, or the host's OS and architecture if that is unavailable
+    pub os: String,
+    /// First line of the session shell's `--version`, or its path if it has none
+    pub shell: String,
+    /// The shell's working directory when the session started, in its container if there is one
+    pub working_dir: Option<PathBuf>,
+    pub command_timeout_ms: u64,
+    /// Variables of the agent's own environment, not the shell's
+    pub env_vars: BTreeMap<String, String>,
+}
+
+impl Environment {
+    /// Captures the environment of `terminal`'s shell, which may run in a container rather than on the host
+    pub fn capture(terminal: &mut impl TerminalLike, command_timeout: Duration) -> Self {
+        let os = terminal.program_output(&["uname", "-srm"])
+            .unwrap_or_else(|| format!("{} {}", std::env::consts::OS, std::env::consts::ARCH));
+        let shell = terminal.shell().to_string();
+        let shell = terminal.program_output(&[&shell, "--version"])
+            .and_then(|version| version.lines().next().map(str::to_string))
+            .unwrap_or(shell);
+        let working_dir = terminal.cwd().map(PathBuf::from);
+
+        let env_vars = RECORDED_VARS
+            .iter()
+            .filter_map(|&name| {
+                let value = std::env::var(name).ok()?;
+                let value = match is_secret_var(name) {
+                    true => "<redacted>".to_string(), 
+                    false => value, 
+                };
+                Some( (name.to_string(), value) )
+            })
+            .collect();
+
+        Self {
+            os,
+            shell,
+            working_dir,
+            command_timeout_ms: command_timeout.as_millis() as u64,
+            env_vars,
+        }
+    }
+}
+
+fn is_secret_var(name: &str) -> bool {
+    ["KEY", "TOKEN", "SECRET", "PASSWORD"]
+        .iter()
+        .any(|marker| name.contains(marker))
+}
+
+#[derive(Serialize)]
+struct SessionLog<'a, Api: LLMApi> {
+    #[serde(flatten)]
+    llm: &'a LLM<Api>,
+    metadata: &'a SessionMetadata,
+}
+
+/// A saved session read back without its backend, e.g. to replay it
+#[derive(Debug, Clone, Deserialize)]
+pub struct SavedSession {
+    pub system_msg: String,
+    pub messages: Vec<MaskableMessage>,
+    #[serde(default)]
+    pub metadata: SessionMetadata,
+}
+
+pub fn load_session_log(path: &Path) -> std::io::Result<SavedSession> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Saves the session as json in `log_dir`, or the default log directory if it is `None`,
+/// returning the path of the log file. With a `redactor` every text in the log is scrubbed of secrets
+pub fn save_session_log(llm: &LLM<impl LLMApi>, metadata: &SessionMetadata, log_dir: Option<&Path>, redactor: Option<&Redactor>) -> std::io::Result<PathBuf> {
+    let log_dir = get_log_dir(log_dir)?;
     let uuid = Uuid::now_v7();
     let log_path = log_dir.join(format!("{}.json", uuid));
-    
-    let file = File::create(log_path)?;
+
+    let file = File::create(&log_path)?;
     let mut writer = BufWriter::new(file);
-    let serialized = serde_json::to_string_pretty(llm)
+    let log = SessionLog { llm, metadata };
+    let mut log = serde_json::to_value(&log)
+        .expect("Failed to serialize LLM state");
+    if let Some(redactor) = redactor {
+        redact_value(&mut log, redactor);
+    }
+    let serialized = serde_json::to_string_pretty(&log)
         .expect("Failed to serialize LLM state");
-    
+
     writer.write_all(serialized.as_bytes())?;
     writer.flush()?;
-    Ok(())
+    Ok(log_path)
+}
+
+/// Scrubs secrets from text before it is saved, by default API keys, tokens, passwords and
+/// private keys. A pattern with a group named `secret` only has that group replaced, so
+/// `API_KEY=sk-...` keeps its name and loses its value
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self::new(Self::default_patterns())
+    }
+}
+
+impl Redactor {
+    pub fn new(patterns: Vec<Regex>) -> Self {
+        Self { patterns }
+    }
+
+    pub fn default_patterns() -> Vec<Regex> {
+        [
+            // AWS access key ids
+            r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+            // OpenAI and Anthropic keys, Google API keys, GitHub tokens
+            r"\bsk-[A-Za-z0-9_-]{20,}",
+            r"\bAIza[0-9A-Za-z_-]{35}",
+            r"\bgh[pousr]_[A-Za-z0-9]{36,}",
+            r"(?i)\bbearer\s+(?P<secret>[A-Za-z0-9._~+/-]+=*)",
+            // Assignments like API_KEY=..., export GITHUB_TOKEN="..." or password: ...
+            r#"(?i)\b[A-Z0-9_]*(?:API_?KEY|TOKEN|SECRET|PASSWORD|PASSWD)(?:_[A-Z0-9_]*)?\b\s*[=:]\s*["']?(?P<secret>[^\s"']+)"#,
+            r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?-----END [A-Z ]*PRIVATE KEY-----",
+        ]
+            .into_iter()
+            .map(|pattern| Regex::new(pattern).expect("default redaction pattern is invalid"))
+            .collect()
+    }
+
+    /// Also scrubs whatever `pattern` matches
+    pub fn with_pattern(mut self, pattern: Regex) -> Self {
+        self.patterns.push(pattern);
+        self
+    }
+
+    pub fn redact(&self, content: &str) -> String {
+        self.patterns.iter().fold(content.to_string(), |content, pattern| {
+            pattern.replace_all(&content, |caps: &regex::Captures| {
+                let matched = caps.get(0).expect("group 0 is the whole match");
+                match caps.name("secret") {
+                    Some(secret) => format!(
+                        "{}<redacted>{}",
+                        &content[matched.start()..secret.start()],
+                        &content[secret.end()..matched.end()],
+                    ), 
+                    None => "<redacted>".to_string(), 
+                }
+            }).into_owned()
+        })
+    }
 }
 
-fn get_log_dir() -> PathBuf {
-    let mut path = cache_dir().unwrap_or_else(|| PathBuf::from("~/.cache"));
-    path.push("agentic_terminal");
-    path.push("logs");
-    std::fs::create_dir_all(&path).expect("Failed to create log directory");
-    path
-}
\ No newline at end of file
+/// Scrubs `content` of secrets with the default patterns
+pub fn redact(content: &str) -> String {
+    static DEFAULT: OnceLock<Redactor> = OnceLock::new();
+    DEFAULT.get_or_init(Redactor::default).redact(content)
+}
+
+/// Redacts every string in a serialized log or request, keys are left alone
+pub(crate) fn redact_value(value: &mut serde_json::Value, redactor: &Redactor) {
+    match value {
+        serde_json::Value::String(text) => *text = redactor.redact(text), 
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|item| redact_value(item, redactor)), 
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(|field| redact_value(field, redactor)), 
+        _ => (), 
+    }
+}
+
+/// File formats a session transcript can be exported as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    Plain, 
+    Markdown, 
+    /// A self-contained page, images are embedded as data URLs when their data is available
+    Html, 
+}
+
+impl TranscriptFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TranscriptFormat::Plain => "txt", 
+            TranscriptFormat::Markdown => "md", 
+            TranscriptFormat::Html => "html", 
+        }
+    }
+}
+
+/// Renders the conversation as a human readable transcript, masked messages are kept and marked
+pub fn render_transcript(llm: &LLM<impl LLMApi>, format: TranscriptFormat) -> String {
+    let msgs = (0..llm.num_msgs()).filter_map(|id| llm.get_msg(id));
+    match format {
+        TranscriptFormat::Plain => msgs
+            .map(|msg| format!("{}: {}\n", msg_heading(msg), msg.get_message().content))
+            .collect(), 
+        TranscriptFormat::Markdown => std::iter::once("# Session transcript\n\n".to_string())
+            .chain(msgs.map(render_markdown_msg))
+            .collect(), 
+        TranscriptFormat::Html => format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Session transcript</title>\n<style>{HTML_STYLE}</style>\n</head>\n<body>\n<h1>Session transcript</h1>\n{}</body>\n</html>\n",
+            msgs.map(render_html_msg).collect::<String>()
+        ), 
+    }
+}
+
+/// Writes the transcript of the session to `path`, scrubbed of secrets with a `redactor`
+pub fn save_transcript(llm: &LLM<impl LLMApi>, format: TranscriptFormat, path: &Path, redactor: Option<&Redactor>) -> std::io::Result<()> {
+    let transcript = render_transcript(llm, format);
+    match redactor {
+        Some(redactor) => std::fs::write(path, redactor.redact(&transcript)), 
+        None => std::fs::write(path, transcript), 
+    }
+}
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; max-width: 60em; margin: auto; } \
+section { border-left: 4px solid #888; margin: 1em 0; padding: 0 1em; } \
+section.assistant { border-color: #36c; } \
+section.system { border-color: #a3a; } \
+section.masked { opacity: 0.5; } \
+pre { white-space: pre-wrap; background: #f4f4f4; padding: 0.5em; } \
+img { max-width: 100%; }";
+
+/// The id, speaker and masked marker shown above every message
+pub(crate) fn msg_heading(msg: &MaskableMessage) -> String {
+    let speaker = match (msg.get_message().role, msg.kind()) {
+        (Role::Assistant, MessageKind::Thought) => "LLM thinks", 
+        (Role::Assistant, _) => "LLM", 
+        (Role::User, _) => "Terminal", 
+        (Role::System, _) => "System", 
+    };
+    let speaker = match msg.is_local() {
+        true => "Note", 
+        false => speaker, 
+    };
+    match msg.is_masked() {
+        true => format!("{} {speaker} [masked]", msg.id()), 
+        false => format!("{} {speaker}", msg.id()), 
+    }
+}
+
+fn render_markdown_msg(msg: &MaskableMessage) -> String {
+    let mut out = format!("## {}\n\n", msg_heading(msg));
+    for item in msg.get_message().content.items() {
+        match item {
+            ContentItem::Text(txt) => {
+                // The fence must be longer than any run of backticks inside the text
+                let longest_run = txt
+                    .split(|c| c != '`')
+                    .map(str::len)
+                    .max()
+                    .unwrap_or(0);
+                let fence = "`".repeat(longest_run.max(2) + 1);
+                out += &format!("{fence}\n{txt}\n{fence}\n\n");
+            }, 
+            ContentItem::Image(img) => out += &format!("*{img}*\n\n"), 
+        }
+    }
+    out
+}
+
+fn render_html_msg(msg: &MaskableMessage) -> String {
+    let role_class = match msg.get_message().role {
+        Role::Assistant => "assistant", 
+        Role::User => "user", 
+        Role::System => "system", 
+    };
+    let class = match msg.is_masked() {
+        true => format!("{role_class} masked"), 
+        false => role_class.to_string(), 
+    };
+
+    let mut out = format!("<section class=\"{class}\">\n<h2>{}</h2>\n", escape_html(&msg_heading(msg)));
+    for item in msg.get_message().content.items() {
+        match item {
+            ContentItem::Text(txt) => out += &format!("<pre>{}</pre>\n", escape_html(txt)), 
+            // Image data is not kept in saved logs, so it may be missing
+            ContentItem::Image(img) if img.data.is_empty() => out += &format!("<p><em>{}</em></p>\n", escape_html(&img.to_string())), 
+            ContentItem::Image(img) => out += &format!("<img src=\"data:{};base64,{}\">\n", img.image_type.mime_type(), img.data), 
+        }
+    }
+    out + "</section>\n"
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Deletes the oldest logs in `log_dir`, or the default log directory, beyond the newest `max_files`
+/// and those older than `max_age`, along with their transcripts. Returns the paths of the deleted logs.
+/// Files not named after a session are left alone
+pub fn prune(log_dir: Option<&Path>, max_files: Option<usize>, max_age: Option<Duration>) -> std::io::Result<Vec<PathBuf>> {
+    let log_dir = get_log_dir(log_dir)?;
+    let mut logs: Vec<(Uuid, PathBuf)> = std::fs::read_dir(&log_dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            let uuid = Uuid::parse_str(path.file_stem()?.to_str()?).ok()?;
+            Some( (uuid, path) )
+        })
+        .collect();
+    // Log names are UUIDv7s, which start with their creation time, so this sorts them oldest first
+    logs.sort();
+
+    let now = SystemTime::now();
+    let n_excess = max_files.map_or(0, |max_files| logs.len().saturating_sub(max_files));
+    let mut pruned = Vec::new();
+    for (i, (uuid, path)) in logs.into_iter().enumerate() {
+        let too_old = max_age.is_some_and(|max_age| {
+            created_at(&uuid).is_some_and(|created| now.duration_since(created).is_ok_and(|age| age > max_age))
+        });
+        if i >= n_excess && !too_old {
+            continue;
+        }
+
+        std::fs::remove_file(&path)?;
+        for format in [TranscriptFormat::Plain, TranscriptFormat::Markdown, TranscriptFormat::Html] {
+            match std::fs::remove_file(path.with_extension(format.extension())) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e), 
+                _ => (), 
+            }
+        }
+        pruned.push(path);
+    }
+    Ok(pruned)
+}
+
+/// When the session a log is named after started
+fn created_at(uuid: &Uuid) -> Option<SystemTime> {
+    let (secs, nanos) = uuid.get_timestamp()?.to_unix();
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+fn get_log_dir(log_dir: Option<&Path>) -> std::io::Result<PathBuf> {
+    let path = match log_dir {
+        Some(dir) => dir.to_path_buf(), 
+        None => {
+            let mut path = cache_dir().unwrap_or_else(|| PathBuf::from("~/.cache"));
+            path.push("agentic_terminal");
+            path.push("logs");
+            path
+        }, 
+    };
+    std::fs::create_dir_all(&path)?;
+    Ok(path)
+}
@@ -1,26 +1,39 @@
-use clap::{Parser, ValueEnum};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap::parser::ValueSource;
+use regex::Regex;
 use std::env;
-use std::{thread, time};
+use std::time;
+use std::thread;
+use std::fs;
 use std::io;
-use std::io::Write;
+use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-mod llm;
-mod anthropic;
-mod openai;
-mod terminal;
-mod log;
-
-use crate::llm::*;
-use crate::anthropic::AnthropicApi;
-use crate::openai::OAIApi;
-use crate::terminal::*;
-use crate::log::save_session_log;
+use agentic_terminal::llm::*;
+use agentic_terminal::anthropic::{self, AnthropicApi};
+use agentic_terminal::openai::{self, OAIApi};
+use agentic_terminal::gemini::{self, GeminiApi};
+use agentic_terminal::ollama::{self, OllamaApi};
+use agentic_terminal::fallback::{AnyApi, FallbackApi};
+use agentic_terminal::http::Transport;
+use agentic_terminal::terminal::*;
+use agentic_terminal::policy::{chain_hooks, confirm_hook, pattern_hook, SudoPolicy};
+use agentic_terminal::log::{self, load_session_log, Redactor, TranscriptFormat};
+use agentic_terminal::replay::replay;
+use agentic_terminal::cache::OutputCache;
+use agentic_terminal::config::Config;
+use agentic_terminal::session::{CandidateSelection, Session, SessionKind, SessionOptions};
 
 #[derive(Clone, Debug, ValueEnum)]
 enum ApiChoice {
     Anthropic,
     #[value(name = "openai")]
     OpenAI,
+    Gemini,
+    /// A local or self-hosted Ollama server, see --ollama-host
+    Ollama,
 }
 
 #[derive(Clone, Debug, ValueEnum)]
@@ -41,26 +54,539 @@ enum ModelChoice {
     O1Mini,
     #[value(name = "o1-preview")]
     O1Preview,
+    #[value(name = "gemini-1.5-pro")]
+    Gemini1_5Pro,
+    #[value(name = "gemini-1.5-flash")]
+    Gemini1_5Flash,
+    #[value(name = "gemini-2.0-flash")]
+    Gemini2_0Flash,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum ImageDetailChoice {
+    Low,
+    High,
+    Auto,
+}
+
+impl ImageDetailChoice {
+    fn to_openai_detail(&self) -> openai::ImageDetail {
+        match self {
+            ImageDetailChoice::Low => openai::ImageDetail::Low,
+            ImageDetailChoice::High => openai::ImageDetail::High,
+            ImageDetailChoice::Auto => openai::ImageDetail::Auto,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ReasoningEffortChoice {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffortChoice {
+    fn to_openai_effort(self) -> openai::ReasoningEffort {
+        match self {
+            ReasoningEffortChoice::Low => openai::ReasoningEffort::Low,
+            ReasoningEffortChoice::Medium => openai::ReasoningEffort::Medium,
+            ReasoningEffortChoice::High => openai::ReasoningEffort::High,
+        }
+    }
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum TranscriptFormatChoice {
+    Plain,
+    Markdown,
+    Html,
+}
+
+impl TranscriptFormatChoice {
+    fn to_transcript_format(&self) -> TranscriptFormat {
+        match self {
+            TranscriptFormatChoice::Plain => TranscriptFormat::Plain,
+            TranscriptFormatChoice::Markdown => TranscriptFormat::Markdown,
+            TranscriptFormatChoice::Html => TranscriptFormat::Html,
+        }
+    }
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum SudoChoice {
+    Rewrite,
+    Reject,
+}
+
+impl SudoChoice {
+    fn to_sudo_policy(&self) -> SudoPolicy {
+        match self {
+            SudoChoice::Rewrite => SudoPolicy::Rewrite,
+            SudoChoice::Reject => SudoPolicy::Reject,
+        }
+    }
 }
 
 /// CLI tool for interacting with LLM APIs
 #[derive(Parser, Debug)]
 #[command(name = "agentic-terminal")]
 #[command(about = "Manifest thy will by granting an LLM agentic access to a bash session.", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Cli {
-    /// The task to perform
-    task: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The task to perform, `-` reads it from stdin
+    #[arg(required_unless_present_any = ["replay", "tasks_file", "task_file"])]
+    task: Option<String>,
+
+    /// Read the task from this file instead, for long tasks or ones kept for reuse
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["task", "tasks_file"])]
+    task_file: Option<PathBuf>,
+
+    /// Run each task in this file in its own session, one per line or a JSON array of strings,
+    /// and report how each went at the end
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["task", "replay"])]
+    tasks_file: Option<PathBuf>,
+
+    /// Stop the batch at the first task that fails instead of moving on to the next.
+    /// Tasks already running in parallel are finished
+    #[arg(long, requires = "tasks_file")]
+    stop_on_failure: bool,
+
+    /// Run up to this many tasks of the batch at once, each in its own shell. Needs --non-interactive,
+    /// and the sessions are not shown as they run, only their logs and the report are kept
+    #[arg(long, value_name = "N", default_value_t = 1, requires = "tasks_file", conflicts_with = "tui")]
+    parallel: usize,
+
+    /// Print a saved session log instead of starting a new session
+    #[arg(long, value_name = "LOG")]
+    replay: Option<PathBuf>,
+
+    /// Go through the replayed session one turn at a time, optionally re-running its commands
+    #[arg(long, requires = "replay")]
+    step: bool,
+
+    /// Extra instructions appended to the system prompt, can be repeated
+    #[arg(long = "prompt-suffix", value_name = "TEXT", global = true)]
+    prompt_suffixes: Vec<String>,
+
+    /// File whose contents are appended to the system prompt after any --prompt-suffix, can be repeated
+    #[arg(long = "prompt-suffix-file", value_name = "PATH", global = true)]
+    prompt_suffix_files: Vec<PathBuf>,
+
+    /// Save every raw request and response to numbered files in this directory, with the key redacted
+    #[arg(long, value_name = "DIR", conflicts_with = "replay_http")]
+    record_http: Option<PathBuf>,
+
+    /// Answer requests with the responses saved by --record-http, in order, instead of using the network
+    #[arg(long, value_name = "DIR")]
+    replay_http: Option<PathBuf>,
+
+    /// Read defaults from this file instead of the config.toml in the user's config directory,
+    /// e.g. ~/.config/agentic_terminal/config.toml. Flags and environment variables take precedence
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+
+    /// Defaults read from the config file
+    #[arg(skip)]
+    config_file: Config,
 
     /// Which API to use
-    #[arg(long, value_enum, default_value_t = ApiChoice::Anthropic)]
+    #[arg(long, value_enum, default_value_t = ApiChoice::Anthropic, global = true)]
     api: ApiChoice,
 
-    /// Which model to use (defaults to claude-3-5-sonnet-latest for Anthropic or gpt-4o for OpenAI)
+    /// Which model to use, defaults to claude-3-5-sonnet-latest for Anthropic, gpt-4o for OpenAI
+    /// and gemini-1.5-pro for Gemini. With Ollama it is any model the server has, e.g. llama3.1:8b
+    #[arg(long, value_name = "MODEL", global = true)]
+    model: Option<String>,
+
+    /// Send requests to this URL instead of the provider's own, for OpenAI compatible services
+    /// like Groq or LiteLLM, or a proxy in front of Anthropic. It replaces `https://api.openai.com/v1`
+    /// or `https://api.anthropic.com`
+    #[arg(long, value_name = "URL")]
+    base_url: Option<String>,
+
+    /// Cache the system prompt and the conversation with Anthropic models, so each turn only pays
+    /// full price for what is new. The other providers cache on their own
+    #[arg(long)]
+    cache: bool,
+
+    /// Stream responses from Anthropic models and show them on stderr as they arrive
+    #[arg(long)]
+    stream_responses: bool,
+
+    /// Address of the Ollama server used with --api ollama
+    #[arg(long, value_name = "URL", default_value = ollama::DEFAULT_HOST)]
+    ollama_host: String,
+
+    /// Context window of the Ollama model in tokens, the server is asked to keep this many
+    #[arg(long, value_name = "N", default_value_t = 8192)]
+    ollama_context_tokens: usize,
+
+    /// Print where the context's tokens went and its peak usage when the session ends
+    #[arg(long)]
+    context_window_report: bool,
+
+    /// Ask the model for a plaintext summary of what it did before the session ends
+    #[arg(long)]
+    summarize_on_exit: bool,
+
+    /// Automatically mask the oldest images once more than this many are in context (unlimited by default)
+    #[arg(long, value_name = "N")]
+    max_images: Option<usize>,
+
+    /// Scale images the model views down so their longest side is at most this many pixels,
+    /// e.g. 1568 for Anthropic's effective limit. Images are always scaled to fit the model's own limits
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_image_dim: Option<u32>,
+
+    /// Pages of a PDF the model views with llmsee, rendered with poppler's pdftoppm, e.g. 3 or 2-6.
+    /// No more pages than the model accepts images are sent at once
+    #[arg(long, value_name = "RANGE", default_value = "1-5", value_parser = parse_page_range)]
+    pdf_pages: RangeInclusive<u32>,
+
+    /// Program that renders PDF pages instead of pdftoppm, it is given pdftoppm's arguments
+    #[arg(long, value_name = "PATH", default_value = PDF_RENDERER)]
+    pdf_renderer: PathBuf,
+
+    /// Mask messages estimated at over this many tokens, like a huge command output, once the model
+    /// had a chance to act on them unless it retains them
+    #[arg(long, value_name = "TOKENS")]
+    auto_mask_over: Option<usize>,
+
+    /// Turns of the model a large message stays in context before it is masked automatically
+    #[arg(long, value_name = "N", default_value_t = 1, requires = "auto_mask_over")]
+    auto_mask_after: usize,
+
+    /// Show what the model would run without running it, it is told each command was not executed
+    /// and keeps planning from there. Files it sends are not written either
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Show each command before it runs and wait for it to be approved, rejected with a reason
+    /// for the model, or edited. Commands blocked by --allow/--deny are not shown
+    #[arg(long, conflicts_with_all = ["non_interactive", "tui"])]
+    confirm: bool,
+
+    /// Stop the session once the model has taken this many turns, the log is saved as usual
+    #[arg(long, value_name = "N")]
+    max_iterations: Option<usize>,
+
+    /// Before each request, mask old images and then the largest old messages while the context is
+    /// estimated at over this fraction of the window, 0.8 when given without a value
+    #[arg(long, value_name = "FRACTION", num_args = 0..=1, default_missing_value = "0.8", value_parser = parse_fraction)]
+    auto_compact: Option<f32>,
+
+    /// Resolution at which OpenAI models view images, low cuts the token cost of vision turns
+    #[arg(long, value_enum, default_value_t = ImageDetailChoice::Auto)]
+    image_detail: ImageDetailChoice,
+
+    /// Sampling temperature of OpenAI models, from 0 to 2, not used by o1 models
+    #[arg(long, value_name = "T", conflicts_with = "top_p")]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling of OpenAI models, only tokens within this top probability mass are
+    /// considered. Not used by o1 models
+    #[arg(long, value_name = "P")]
+    top_p: Option<f32>,
+
+    /// How much o1 models reason before answering, higher is slower and costs more output tokens.
+    /// Other models ignore it
     #[arg(long, value_enum)]
-    model: Option<ModelChoice>,
+    reasoning_effort: Option<ReasoningEffortChoice>,
+
+    /// Seconds a command may print nothing before it is interrupted and the model gets its partial output
+    #[arg(long, value_name = "SECS")]
+    command_timeout: Option<u64>,
+
+    /// Seconds to wait for the shell to start before giving up
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    startup_timeout: u64,
+
+    /// Seconds a timed out command gets after Ctrl+C, and then after SIGTERM, before it is sent
+    /// SIGTERM, and then SIGKILL
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    interrupt_timeout: u64,
+
+    /// Run the shell in a throwaway container of this image instead of on the host
+    #[arg(long, value_name = "IMAGE")]
+    docker: Option<String>,
+
+    /// Program that runs the container, e.g. podman
+    #[arg(long, value_name = "PROGRAM", default_value = "docker", requires = "docker")]
+    container_runtime: String,
+
+    /// Host directory mounted read-write into the container at the same path and used as its working directory
+    #[arg(long, value_name = "DIR", requires = "docker")]
+    mount: Option<PathBuf>,
+
+    /// Seconds to wait for the container's shell, including pulling the image
+    #[arg(long, value_name = "SECS", default_value_t = 300, requires = "docker")]
+    container_startup_timeout: u64,
+
+    /// Shell to run commands in, in the container with --docker. Bash, zsh and sh like dash are supported,
+    /// on Windows PowerShell, either powershell.exe or pwsh.exe
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_SHELL)]
+    shell: String,
+
+    /// Shell options set for the session, e.g. "-euo pipefail". With -e the shell exits on the
+    /// first failing command and is restarted in the same working directory, losing its variables
+    #[arg(long, value_name = "OPTS", allow_hyphen_values = true)]
+    bash_opts: Option<String>,
+
+    /// Drop the middle of command output longer than this many bytes, keeping its start and end, 0 to keep it whole
+    #[arg(long, value_name = "BYTES", default_value_t = 16 * 1024)]
+    max_output: usize,
+
+    /// Cut lines of command output longer than this many characters, e.g. from a minified file, 0 to keep them whole
+    #[arg(long, value_name = "CHARS", default_value_t = 10_000)]
+    max_line_chars: usize,
+
+    /// Keep the shell's PROMPT_COMMAND, by default it is unset since its output would end up in command output
+    #[arg(long)]
+    keep_prompt_command: bool,
+
+    /// Keep ANSI escape sequences like colors in command output, by default they are stripped before the model sees it
+    #[arg(long)]
+    keep_ansi: bool,
+
+    /// Locale the shell runs in: utf8 for C.UTF-8 or en_US.UTF-8 if installed, inherit to keep
+    /// the current one, or a locale name like de_DE.UTF-8
+    #[arg(long, value_name = "LOCALE", default_value = "utf8")]
+    locale: String,
+
+    /// Environment variable exported into the shell, e.g. GIT_AUTHOR_NAME=bot, can be repeated.
+    /// The value is taken literally, nothing in it is expanded
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+
+    /// Maximum number of characters shown to the model per chunk of a streamed command
+    #[arg(long, value_name = "CHARS", default_value_t = 4000)]
+    stream_chunk_size: usize,
+
+    /// Seconds after which a streamed command is interrupted, however often the model continues it
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    stream_deadline: u64,
+
+    /// Check every this many seconds that the shell still responds and restart it if it does not
+    #[arg(long, value_name = "SECS")]
+    liveness_interval: Option<u64>,
+
+    /// Number the lines of command output shown to the model, which helps it refer to lines but costs tokens
+    #[arg(long)]
+    number_output: bool,
+
+    /// Follow each command's output with the shell's working directory, to help the model keep track of where it is
+    #[arg(long)]
+    show_cwd: bool,
+
+    /// Follow each finished command's output with how long it ran, to help the model notice slow commands
+    #[arg(long)]
+    show_duration: bool,
+
+    /// Cut off the text of any single message, including output, references and typed input, beyond this many bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 256 * 1024)]
+    max_message_bytes: usize,
+
+    /// Ask the model to rephrase up to this many times in a row when a content filter stops its response, instead of exiting
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    filter_retries: usize,
+
+    /// Never wait for user input, fail immediately when a human would be needed
+    #[arg(long)]
+    non_interactive: bool,
+
+    /// Only run commands starting with this prefix, can be repeated
+    #[arg(long = "allow", value_name = "PREFIX")]
+    allow: Vec<String>,
+
+    /// Never run commands containing this pattern, can be repeated
+    #[arg(long = "deny", value_name = "PATTERN")]
+    deny: Vec<String>,
+
+    /// File loaded as pinned reference material that masking never removes, can be repeated
+    #[arg(long = "reference-file", value_name = "PATH")]
+    reference_files: Vec<PathBuf>,
+
+    /// Also save a readable transcript in this format next to the session log
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    transcript_format: Option<TranscriptFormatChoice>,
+
+    /// Directory session logs and transcripts are saved in, created if missing. Defaults to the
+    /// config's log_dir, else agentic_terminal/logs in the user's cache directory
+    #[arg(long, value_name = "DIR", env = "AGENTIC_TERMINAL_LOG_DIR", global = true)]
+    log_dir: Option<PathBuf>,
+
+    /// Scrub API keys, bearer tokens, passwords and private keys from the log, the transcript and
+    /// the --record-http exchanges before they are saved, e.g. ones the model printed with env
+    #[arg(long)]
+    redact: bool,
+
+    /// Regex of another secret to scrub with --redact, can be repeated. Only a group named
+    /// secret is replaced if the regex has one, e.g. 'db_url=(?P<secret>\S+)'
+    #[arg(long = "redact-pattern", value_name = "REGEX", requires = "redact", value_parser = parse_regex)]
+    redact_patterns: Vec<Regex>,
+
+    /// Number of candidate responses to sample per step, multiplies output cost. Only supported by OpenAI
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    candidates: u32,
+
+    /// Pick between sampled candidates yourself instead of taking the first valid one
+    #[arg(long, requires = "candidates", conflicts_with = "non_interactive")]
+    pick_candidate: bool,
+
+    /// What to do with sudo commands that could wait for a password: add -n so they fail fast, or reject them
+    #[arg(long, value_enum, default_value_t = SudoChoice::Rewrite)]
+    sudo: SudoChoice,
+
+    /// Show the session in a full screen view with scrollback and a status line
+    #[arg(long, conflicts_with = "pick_candidate")]
+    tui: bool,
+
+    /// Tell the model which commands you ran while the session was paused with Ctrl+C
+    #[arg(long)]
+    report_paused_commands: bool,
+
+    /// Repeat the output of read-only commands (ls, cat, pwd, git status) instead of running them again
+    #[arg(long)]
+    cache_output: bool,
+
+    /// Command treated as read-only by the output cache instead of the defaults, can be repeated
+    #[arg(long = "cache-command", value_name = "COMMAND", requires = "cache_output")]
+    cache_commands: Vec<String>,
+
+    /// Show the cost of each turn and the running total next to the model's messages
+    #[arg(long)]
+    show_cost: bool,
+
+    /// Model to switch to when requests keep failing or the context no longer fits, can be repeated
+    /// to form a chain. Its provider's key is read from ANTHROPIC_API_KEY, OPENAI_API_KEY or GEMINI_API_KEY, else API_KEY
+    #[arg(long = "fallback-model", value_enum, value_name = "MODEL")]
+    fallback_models: Vec<ModelChoice>,
+
+    /// Maximum number of tokens the model may output per response, clamped to what the model allows
+    #[arg(long, value_name = "N")]
+    max_output_tokens: Option<usize>,
+
+    /// Show the tokens each turn used below the model's messages, including cached and reasoning tokens
+    #[arg(long)]
+    verbose_usage: bool,
+
+    /// Show the model's responses as the raw json instead of e.g. "LLM runs: ls -la"
+    #[arg(long)]
+    debug_raw: bool,
+
+    /// JSON object whose fields are added to every request, e.g. '{"top_k": 40}'.
+    /// Fields the request already sets, like messages, are never replaced
+    #[arg(long, value_name = "JSON")]
+    model_params: Option<String>,
+
+    /// Directory the model can put files in and retrieve them from with putfile and getfile
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    transfer_root: PathBuf,
+
+    /// Largest file in bytes the model can put or retrieve
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024)]
+    max_transfer_size: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Estimate what a task will cost with the chosen model without running anything
+    Estimate {
+        /// The task to estimate
+        task: String,
+
+        /// Number of turns the task is assumed to take
+        #[arg(long, value_name = "N", default_value_t = 20)]
+        turns: usize,
+
+        /// Tokens each turn is assumed to add to the context, the command and its output
+        #[arg(long, value_name = "N", default_value_t = 500)]
+        tokens_per_turn: usize,
+
+        /// Tokens the model is assumed to output per turn
+        #[arg(long, value_name = "N", default_value_t = 150)]
+        output_tokens_per_turn: usize,
+    },
+    /// Delete old session logs and their transcripts
+    PruneLogs {
+        /// Keep only this many of the newest logs
+        #[arg(long, value_name = "N", required_unless_present = "max_age_days")]
+        max_files: Option<usize>,
+
+        /// Delete logs older than this many days
+        #[arg(long, value_name = "DAYS")]
+        max_age_days: Option<u64>,
+    },
+}
+
+impl Cli {
+    /// The chosen model, or the API's default. Ollama's models are not known in advance, see `ollama_model`
+    fn model_choice(&self) -> Result<ModelChoice, String> {
+        let Some(name) = &self.model else {
+            return match self.api {
+                ApiChoice::Anthropic => Ok(ModelChoice::Sonnet3_5),
+                ApiChoice::OpenAI => Ok(ModelChoice::GPT4O),
+                ApiChoice::Gemini => Ok(ModelChoice::Gemini1_5Pro),
+                ApiChoice::Ollama => Err("--api ollama needs a --model, e.g. llama3.1".to_string()),
+            };
+        };
+        ModelChoice::from_str(name, false).map_err(|_| {
+            let names: Vec<String> = ModelChoice::value_variants()
+                .iter()
+                .filter_map(|model| model.to_possible_value())
+                .map(|value| value.get_name().to_string())
+                .collect();
+            format!("Unknown model {name}, possible models are {}", names.join(", "))
+        })
+    }
+
+    /// The model named with --model on the Ollama server
+    fn ollama_model(&self) -> Result<ollama::Model, String> {
+        let name = self.model.as_deref().ok_or("--api ollama needs a --model, e.g. llama3.1")?;
+        Ok(ollama::Model::new(name, self.ollama_context_tokens))
+    }
+
+    /// The system prompt for a task with the --prompt-suffix and --prompt-suffix-file instructions appended
+    fn system_prompt(&self, task: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let shell = ShellKind::of(&self.shell).map_or("bash", |kind| kind.name());
+        let mut system_prompt = generate_system_prompt(task, shell);
+        let transfer_root = self.transfer_root.canonicalize().unwrap_or_else(|_| self.transfer_root.clone());
+        system_prompt.push_str(&format!("\nThe transfer directory is {}.", transfer_root.display()));
+        for suffix in &self.prompt_suffixes {
+            system_prompt.push('\n');
+            system_prompt.push_str(suffix.trim());
+        }
+        for path in &self.prompt_suffix_files {
+            let suffix = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read prompt suffix file {}: {e}", path.display()))?;
+            system_prompt.push('\n');
+            system_prompt.push_str(suffix.trim());
+        }
+        Ok(system_prompt)
+    }
 }
 
 impl ModelChoice {
+    fn model_info(&self) -> &'static dyn ModelInfo {
+        match self {
+            ModelChoice::Haiku3_5 => &anthropic::Model::Haiku3_5,
+            ModelChoice::Sonnet3_5 => &anthropic::Model::Sonnet3_5,
+            ModelChoice::Opus3 => &anthropic::Model::Opus3,
+            ModelChoice::GPT4O => &openai::Model::GPT4O,
+            ModelChoice::GPT4OMini => &openai::Model::GPT4OMini,
+            ModelChoice::O1 => &openai::Model::O1,
+            ModelChoice::O1Mini => &openai::Model::O1Mini,
+            ModelChoice::O1Preview => &openai::Model::O1Preview,
+            ModelChoice::Gemini1_5Pro => &gemini::Model::Gemini1_5Pro,
+            ModelChoice::Gemini1_5Flash => &gemini::Model::Gemini1_5Flash,
+            ModelChoice::Gemini2_0Flash => &gemini::Model::Gemini2_0Flash,
+        }
+    }
+
     fn to_anthropic_model(&self) -> Option<anthropic::Model> {
         match self {
             ModelChoice::Haiku3_5 => Some(anthropic::Model::Haiku3_5),
@@ -80,31 +606,178 @@ impl ModelChoice {
             _ => None,
         }
     }
+
+    fn to_gemini_model(&self) -> Option<gemini::Model> {
+        match self {
+            ModelChoice::Gemini1_5Pro => Some(gemini::Model::Gemini1_5Pro),
+            ModelChoice::Gemini1_5Flash => Some(gemini::Model::Gemini1_5Flash),
+            ModelChoice::Gemini2_0Flash => Some(gemini::Model::Gemini2_0Flash),
+            _ => None,
+        }
+    }
+
+    /// Builds a fallback backend for this model, using the provider's own key variable if it is set,
+    /// then its key in the config file
+    fn to_fallback_api(&self, config: &Config, image_detail: openai::ImageDetail, n_candidates: u32) -> Result<AnyApi, Box<dyn std::error::Error>> {
+        let api_key = |provider_var: &str, section: &str, config_key: &Option<String>| match env_api_key(provider_var)? {
+            Some(api_key) => Ok(api_key), 
+            None => match config_api_key(section, config_key.as_deref())? {
+                Some(api_key) => Ok(api_key), 
+                None => env_api_key("API_KEY")?
+                    .ok_or_else(|| format!("Please set the environment variable {provider_var} or API_KEY")), 
+            }, 
+        };
+
+        if let Some(model) = self.to_anthropic_model() {
+            return Ok(AnyApi::Anthropic(AnthropicApi::new(api_key("ANTHROPIC_API_KEY", "anthropic", &config.anthropic.api_key)?, model)));
+        }
+        if let Some(model) = self.to_gemini_model() {
+            return Ok(AnyApi::Gemini(GeminiApi::new(api_key("GEMINI_API_KEY", "gemini", &config.gemini.api_key)?, model)));
+        }
+
+        let model = self.to_openai_model()
+            .ok_or_else(|| format!("Invalid fallback model: {:?}", self))?;
+        let oai_api = OAIApi::new(api_key("OPENAI_API_KEY", "openai", &config.openai.api_key)?, model)
+            .with_image_detail(image_detail)
+            .with_candidates(n_candidates);
+        Ok(AnyApi::OpenAI(oai_api))
+    }
 }
 
-enum LLMKind {
-    AnthropicLLM(LLM<AnthropicApi>),
-    OpenAILLM(LLM<OAIApi>),
+/// Parses the command line and fills in what it leaves out from the config file
+fn parse_cli() -> Result<Cli, Box<dyn std::error::Error>> {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config = Config::load(cli.config.as_deref()).map_err(|e| e.to_string())?;
+    apply_config(&mut cli, &matches, &config)?;
+    cli.config_file = config;
+    Ok(cli)
 }
 
-impl LLMKind {
-    fn apply<FA, FO, R>(
-        &mut self,
-        f_anthropic: FA,
-        f_openai: FO
-    ) -> R
-    where
-        FA: FnOnce(&mut LLM<AnthropicApi>) -> R,
-        FO: FnOnce(&mut LLM<OAIApi>) -> R,
-    {
-        match self {
-            LLMKind::AnthropicLLM(llm) => f_anthropic(llm),
-            LLMKind::OpenAILLM(llm) => f_openai(llm),
-        }
+/// Sets every option the command line left at its default to the config's value, if it has one
+fn apply_config(cli: &mut Cli, matches: &ArgMatches, config: &Config) -> Result<(), String> {
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    if let (false, Some(api)) = (given("api"), &config.api) {
+        cli.api = ApiChoice::from_str(api, true)
+            .map_err(|_| format!("Unknown api {api} in the config file"))?;
+    }
+    if cli.model.is_none() {
+        cli.model.clone_from(&config.model);
+    }
+    if cli.base_url.is_none() {
+        cli.base_url = match cli.api {
+            ApiChoice::Anthropic => config.anthropic.base_url.clone(), 
+            ApiChoice::OpenAI => config.openai.base_url.clone(), 
+            ApiChoice::Gemini | ApiChoice::Ollama => None, 
+        };
+    }
+    if let (false, Some(host)) = (given("ollama_host"), &config.ollama.host) {
+        cli.ollama_host.clone_from(host);
+    }
+    if cli.command_timeout.is_none() {
+        cli.command_timeout = config.command_timeout;
+    }
+    if cli.log_dir.is_none() {
+        cli.log_dir.clone_from(&config.log_dir);
     }
+    Ok(())
 }
 
+/// The redactor --redact asks for, with the --redact-pattern patterns added to the defaults
+fn redactor(cli: &Cli) -> Option<Redactor> {
+    cli.redact.then(|| {
+        cli.redact_patterns
+            .iter()
+            .fold(Redactor::default(), |redactor, pattern| redactor.with_pattern(pattern.clone()))
+    })
+}
 
+/// Reads an API key from the environment variable `var`, trimmed of the whitespace and newlines
+/// pasting tends to bring along. A key that is set but blank is an error rather than a failed request later
+fn env_api_key(var: &str) -> Result<Option<String>, String> {
+    match env::var(var) {
+        Ok(api_key) if api_key.trim().is_empty() => Err(format!("The environment variable {var} is set but empty")), 
+        Ok(api_key) => Ok(Some(api_key.trim().to_string())), 
+        Err(env::VarError::NotPresent) => Ok(None), 
+        Err(env::VarError::NotUnicode(_)) => Err(format!("The environment variable {var} is not valid unicode")), 
+    }
+}
+
+/// Trims an API key from the `[section]` of the config file like `env_api_key`, a blank one is an error as well
+fn config_api_key(section: &str, api_key: Option<&str>) -> Result<Option<String>, String> {
+    match api_key.map(str::trim) {
+        Some("") => Err(format!("The api_key in the [{section}] section of the config file is empty")), 
+        api_key => Ok(api_key.map(str::to_string)), 
+    }
+}
+
+/// Parses a `--env` variable, the value is everything after the first `=` and may contain more of them
+fn parse_env_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())), 
+        _ => Err(format!("expected KEY=VALUE, got {var:?}")), 
+    }
+}
+
+/// Parses a range of pages like `3` or `2-6`, numbered from 1
+fn parse_page_range(s: &str) -> Result<RangeInclusive<u32>, String> {
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    match (first.trim().parse::<u32>(), last.trim().parse::<u32>()) {
+        (Ok(first), Ok(last)) if first >= 1 && first <= last => Ok(first..=last), 
+        _ => Err(format!("expected a page like 3 or a range like 2-6, counting from 1, got {s:?}")), 
+    }
+}
+
+fn parse_regex(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| e.to_string())
+}
+
+fn days(n: u64) -> time::Duration {
+    time::Duration::from_secs(n * 24 * 60 * 60)
+}
+
+/// Parses a fraction greater than 0 and at most 1
+fn parse_fraction(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(fraction), 
+        _ => Err(format!("expected a number greater than 0 and at most 1, got {s:?}")), 
+    }
+}
+
+/// Parses `--model-params`, which must be an object that leaves the conversation itself alone
+fn parse_model_params(json: &str) -> Result<serde_json::Map<String, serde_json::Value>, Box<dyn std::error::Error>> {
+    let params = match serde_json::from_str(json) {
+        Ok(serde_json::Value::Object(params)) => params, 
+        Ok(_) => return Err("--model-params must be a JSON object".into()), 
+        Err(e) => return Err(format!("--model-params is not valid JSON: {e}").into()), 
+    };
+
+    if let Some(key) = ["model", "system", "messages"].into_iter().find(|key| params.contains_key(*key)) {
+        return Err(format!("--model-params cannot set {key}").into());
+    }
+    Ok(params)
+}
+
+/// Prints the estimated cost of a task along with everything it assumes
+fn print_estimate(estimate: &CostEstimate, model: &dyn ModelInfo) {
+    let first_turn = estimate.turn_usage(0);
+    let total = estimate.total_usage();
+
+    println!("Model: {} (${:.2} per million input tokens, ${:.2} per million output tokens)", model.name(), model.input_price(), model.output_price());
+    println!("System prompt: ~{} tokens", estimate.system_tokens);
+    println!("First turn: ~{} input and {} output tokens, ${:.4}", first_turn.n_input_tokens, first_turn.n_output_tokens, model.cost(&first_turn));
+    println!("Total over {} turns: ~{} input and {} output tokens, ${:.2}", estimate.n_turns, total.n_input_tokens, total.n_output_tokens, model.cost(&total));
+    println!();
+    println!("Assumptions, adjust them with the options of this subcommand:");
+    println!("- The task takes {} turns (--turns), real tasks can take far fewer or many more", estimate.n_turns);
+    println!("- Each turn adds {} tokens of command and output to the context (--tokens-per-turn), which every later turn resends", estimate.tokens_per_turn);
+    println!("- The model outputs {} tokens per turn (--output-tokens-per-turn)", estimate.output_tokens_per_turn);
+    println!("- Tokens are counted as about four characters each, images, masking, prompt caching, retries and candidates are ignored");
+    if estimate.turn_usage(estimate.n_turns.saturating_sub(1)).n_input_tokens > model.max_context_tokens() {
+        println!("- The context outgrows the model's {} tokens before the last turn, so a real session would have to mask messages", model.max_context_tokens());
+    }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     /*let s = r#"{"Command":"echo -e 'Mewtwo\nRayquaza\nGroudon\nKyogre\nArceus' > strongest_pokemon.txt"}"#;
@@ -177,255 +850,482 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 
 
-    let cli = Cli::parse();
+    let cli = parse_cli()?;
+
+    if let Some(path) = &cli.replay {
+        let log = load_session_log(path)?;
+        let command_timeout = cli.command_timeout.map_or(SessionOptions::default().command_timeout, time::Duration::from_secs);
+        return replay(&log, cli.step, command_timeout);
+    }
+    // Pruning at startup is only done when the config asks for it, never by default
+    let config = &cli.config_file;
+    if config.max_log_files.is_some() || config.max_log_age_days.is_some() {
+        let max_age = config.max_log_age_days.map(days);
+        if let Err(e) = log::prune(cli.log_dir.as_deref(), config.max_log_files, max_age) {
+            eprintln!("Failed to prune old logs: {e}");
+        }
+    }
+    if let Some(Command::PruneLogs { max_files, max_age_days }) = &cli.command {
+        let pruned = log::prune(cli.log_dir.as_deref(), *max_files, max_age_days.map(days))?;
+        println!("Deleted {} log(s)", pruned.len());
+        return Ok(());
+    }
+    if let Some(Command::Estimate { task, turns, tokens_per_turn, output_tokens_per_turn }) = &cli.command {
+        let estimate = CostEstimate::new(&cli.system_prompt(task)?, *turns, *tokens_per_turn, *output_tokens_per_turn);
+        match cli.api {
+            ApiChoice::Ollama => print_estimate(&estimate, &cli.ollama_model()?), 
+            _ => print_estimate(&estimate, cli.model_choice()?.model_info()), 
+        }
+        return Ok(());
+    }
+    // Requests are numbered across all tasks when recording or replaying them
+    let transport = match (&cli.record_http, &cli.replay_http) {
+        (Some(dir), _) => Transport::record(dir.clone(), redactor(&cli))?, 
+        (_, Some(dir)) => Transport::replay(dir.clone()), 
+        (None, None) => Transport::Network, 
+    };
+    let (primary_api, fallback_apis) = build_apis(&cli, &transport)?;
+
+    // Ctrl+C pauses the current session between steps so the user can look around in its shell
+    let current_pause = Arc::new(Mutex::new(Arc::new(AtomicBool::new(false))));
+    if !cli.tui && !cli.non_interactive {
+        let current_pause = current_pause.clone();
+        ctrlc::set_handler(move || {
+            let pause = current_pause.lock().expect("pause handle poisoned").clone();
+            if pause.swap(true, Ordering::SeqCst) {
+                // Pressed again before the pause took effect
+                std::process::exit(130);
+            }
+            eprintln!("\nPausing after the current step, press Ctrl+C again to quit.");
+        })?;
+    }
+
+    let Some(tasks_file) = &cli.tasks_file else {
+        let task = load_task(&cli)?;
+        let mut session = build_session(&cli, &task, primary_api, fallback_apis)?;
+        *current_pause.lock().expect("pause handle poisoned") = session.pause_handle();
+        let result = run_session(&cli, &mut session);
+        let metadata = session.metadata();
+        println!(
+            "Used {} input and {} output tokens, ~${:.2}",
+            metadata.total_input_tokens, metadata.total_output_tokens, metadata.total_cost,
+        );
+        save_log(&mut session);
+        return result;
+    };
+
+    if cli.parallel > 1 && !cli.non_interactive {
+        return Err("--parallel needs --non-interactive, the sessions cannot share the keyboard".into());
+    }
+    let tasks = load_tasks(tasks_file)?;
+    let start = time::Instant::now();
+
+    // Workers take the next task until none are left, or one failed with --stop-on-failure
+    let next_task = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let reports = Mutex::new((0..tasks.len()).map(|_| None).collect::<Vec<Option<TaskReport>>>());
+    thread::scope(|scope| {
+        for _ in 0..cli.parallel.clamp(1, tasks.len()) {
+            scope.spawn(|| {
+                while !stopped.load(Ordering::SeqCst) {
+                    let i = next_task.fetch_add(1, Ordering::SeqCst);
+                    let Some(task) = tasks.get(i) else {
+                        break;
+                    };
 
-    // Get the appropriate model based on API choice
-    let model_choice = cli.model.unwrap_or(match cli.api {
-        ApiChoice::Anthropic => ModelChoice::Sonnet3_5,
-        ApiChoice::OpenAI => ModelChoice::GPT4O,
+                    println!("Task {}/{}: {task}", i + 1, tasks.len());
+                    let report = run_task(&cli, task, primary_api.clone(), fallback_apis.clone(), &current_pause);
+                    match &report.error {
+                        Some(e) => println!("Task {}/{} failed after {:.0}s: {e}", i + 1, tasks.len(), report.duration.as_secs_f64()), 
+                        None => println!("Task {}/{} finished after {:.0}s", i + 1, tasks.len(), report.duration.as_secs_f64()), 
+                    }
+                    if report.error.is_some() && cli.stop_on_failure && !stopped.swap(true, Ordering::SeqCst) {
+                        eprintln!("Stopping the batch after task {} failed", i + 1);
+                    }
+                    reports.lock().expect("batch reports poisoned")[i] = Some(report);
+                }
+            });
+        }
     });
 
-    // Prepare the system prompt
-    let system_prompt = generate_system_prompt(&cli.task);
+    let reports = reports.into_inner().expect("batch reports poisoned");
+    print_batch_report(&reports, start.elapsed());
+    match reports.iter().flatten().filter(|report| report.error.is_some()).count() {
+        0 => Ok(()), 
+        n_failed => Err(format!("{n_failed} of {} tasks failed", tasks.len()).into()), 
+    }
+}
 
-    let api_key = env::var("API_KEY")
-        .map_err(|_| "Please set the environment variable API_KEY")?;
+/// Runs one task of a batch in a fresh session, saving its log
+fn run_task(cli: &Cli, task: &str, primary_api: AnyApi, fallback_apis: Vec<AnyApi>, current_pause: &Mutex<Arc<AtomicBool>>) -> TaskReport {
+    let start = time::Instant::now();
+    match build_session(cli, task, primary_api, fallback_apis) {
+        Ok(mut session) => {
+            *current_pause.lock().expect("pause handle poisoned") = session.pause_handle();
+            let result = run_session(cli, &mut session);
+            let metadata = session.metadata();
+            TaskReport {
+                task: task.to_string(), 
+                error: result.err().map(|e| e.to_string()), 
+                n_steps: metadata.n_steps, 
+                cost: metadata.total_cost, 
+                duration: start.elapsed(), 
+                log: save_log(&mut session), 
+            }
+        }, 
+        Err(e) => {
+            eprintln!("Failed to start task: {e}");
+            TaskReport {
+                task: task.to_string(), 
+                error: Some(e.to_string()), 
+                n_steps: 0, 
+                cost: 0.0, 
+                duration: start.elapsed(), 
+                log: None, 
+            }
+        }, 
+    }
+}
 
-    // Build the appropriate LLMKind variant
-    let mut llm_kind = match cli.api {
+/// Builds the primary backend and its fallbacks, configured with the request settings
+fn build_apis(cli: &Cli, transport: &Transport) -> Result<(AnyApi, Vec<AnyApi>), Box<dyn std::error::Error>> {
+    let (section, config_key) = match cli.api {
+        ApiChoice::Anthropic => ("anthropic", cli.config_file.anthropic.api_key.as_deref()), 
+        ApiChoice::OpenAI => ("openai", cli.config_file.openai.api_key.as_deref()), 
+        ApiChoice::Gemini => ("gemini", cli.config_file.gemini.api_key.as_deref()), 
+        ApiChoice::Ollama => ("ollama", None), 
+    };
+    let api_key = || match (env_api_key("API_KEY"), &cli.replay_http) {
+        // Replayed responses need no key
+        (_, Some(_)) => Ok(String::new()), 
+        (Ok(Some(api_key)), None) => Ok(api_key), 
+        (Ok(None), None) => config_api_key(section, config_key)?
+            .ok_or_else(|| "Please set the environment variable API_KEY or the api_key of the provider in the config file".to_string()), 
+        (Err(e), None) => Err(e), 
+    };
+
+    // Build the primary backend
+    let primary_api = match cli.api {
         ApiChoice::Anthropic => {
             // Convert model choice to Anthropic model
+            let model_choice = cli.model_choice()?;
             let chosen_model = model_choice.to_anthropic_model()
                 .ok_or_else(|| format!("Invalid Anthropic model: {:?}", model_choice))?;
 
-            let anthropic_api = AnthropicApi::new(api_key, chosen_model);
-            LLMKind::AnthropicLLM(LLM::new(anthropic_api, system_prompt))
+            let anthropic_api = AnthropicApi::new(api_key()?, chosen_model);
+            AnyApi::Anthropic(match &cli.base_url {
+                Some(url) => anthropic_api.with_base_url(url), 
+                None => anthropic_api, 
+            })
         }
         ApiChoice::OpenAI => {
             // Convert model choice to OpenAI model
+            let model_choice = cli.model_choice()?;
             let chosen_model = model_choice.to_openai_model()
                 .ok_or_else(|| format!("Invalid OpenAI model: {:?}", model_choice))?;
 
-            let oai_api = OAIApi::new(api_key, chosen_model);
-            LLMKind::OpenAILLM(LLM::new(oai_api, system_prompt))
+            let oai_api = OAIApi::new(api_key()?, chosen_model)
+                .with_image_detail(cli.image_detail.to_openai_detail())
+                .with_candidates(cli.candidates);
+            AnyApi::OpenAI(match &cli.base_url {
+                Some(url) => oai_api.with_base_url(url), 
+                None => oai_api, 
+            })
+        }
+        ApiChoice::Gemini | ApiChoice::Ollama if cli.base_url.is_some() => {
+            return Err("--base-url only applies to --api anthropic and --api openai, use --ollama-host for Ollama".into());
         }
+        ApiChoice::Gemini => {
+            let model_choice = cli.model_choice()?;
+            let chosen_model = model_choice.to_gemini_model()
+                .ok_or_else(|| format!("Invalid Gemini model: {:?}", model_choice))?;
+
+            AnyApi::Gemini(GeminiApi::new(api_key()?, chosen_model))
+        }
+        // A local server needs no key
+        ApiChoice::Ollama => AnyApi::Ollama(OllamaApi::new(cli.ollama_model()?).with_host(&cli.ollama_host)), 
+    };
+    let fallback_apis = cli.fallback_models
+        .iter()
+        .map(|model| model.to_fallback_api(&cli.config_file, cli.image_detail.to_openai_detail(), cli.candidates))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let model_params = match &cli.model_params {
+        Some(json) => parse_model_params(json)?, 
+        None => serde_json::Map::new(), 
+    };
+    // Request settings apply to whichever backend ends up answering
+    let configure = |api: AnyApi| {
+        let api = match cli.max_output_tokens {
+            Some(n) => api.with_max_output_tokens(n), 
+            None => api, 
+        };
+        let api = match (api, cli.temperature, cli.top_p) {
+            (AnyApi::OpenAI(api), Some(temperature), _) => AnyApi::OpenAI(api.with_sampling(openai::Sampling::Temperature { temperature })), 
+            (AnyApi::OpenAI(api), _, Some(top_p)) => AnyApi::OpenAI(api.with_sampling(openai::Sampling::TopP { top_p })), 
+            (api, _, _) => api, 
+        };
+        let api = match (api, cli.reasoning_effort) {
+            (AnyApi::OpenAI(api), Some(effort)) => AnyApi::OpenAI(api.with_reasoning_effort(effort.to_openai_effort())), 
+            (api, _) => api, 
+        };
+        let api = match api {
+            AnyApi::Anthropic(api) if cli.cache => AnyApi::Anthropic(api.with_prompt_cache()), 
+            api => api, 
+        };
+        let api = match api {
+            AnyApi::Anthropic(api) if cli.stream_responses => AnyApi::Anthropic(api.with_streaming(StreamCallback::new(|text| match text {
+                Some(text) => eprint!("{text}"), 
+                None => eprintln!(), 
+            }))), 
+            api => api, 
+        };
+        api.with_params(model_params.clone())
+            .with_transport(transport.clone())
+    };
+    let primary_api = configure(primary_api);
+    let fallback_apis: Vec<AnyApi> = fallback_apis.into_iter().map(configure).collect();
+    Ok( (primary_api, fallback_apis) )
+}
+
+/// Sets up a fresh terminal and session for a task
+fn build_session(cli: &Cli, task: &str, primary_api: AnyApi, fallback_apis: Vec<AnyApi>) -> Result<SessionKind, Box<dyn std::error::Error>> {
+    // Prepare the system prompt
+    let system_prompt = cli.system_prompt(task)?;
+
+    let container = match &cli.docker {
+        Some(image) => Some(
+            Container {
+                runtime: cli.container_runtime.clone(), 
+                image: image.clone(), 
+                mount: match &cli.mount {
+                    Some(dir) => Some(dir.canonicalize().map_err(|e| format!("Cannot mount {}: {e}", dir.display()))?), 
+                    None => None, 
+                }, 
+                startup_timeout: time::Duration::from_secs(cli.container_startup_timeout), 
+            }
+        ), 
+        None => None, 
     };
 
     // Set up the local pseudo-terminal
-    let terminal = Terminal::new()?;
-    let command_timeout = time::Duration::from_secs(2);
+    let terminal = Terminal::with_config(
+        TerminalConfig {
+            shell: cli.shell.clone(), 
+            startup_timeout: time::Duration::from_secs(cli.startup_timeout), 
+            interrupt_timeout: time::Duration::from_secs(cli.interrupt_timeout), 
+            bash_opts: cli.bash_opts.clone(), 
+            locale: match cli.locale.as_str() {
+                "utf8" => Locale::Utf8, 
+                "inherit" => Locale::Inherit, 
+                name => Locale::Named(name.to_string()), 
+            }, 
+            container, 
+            keep_prompt_command: cli.keep_prompt_command, 
+            max_output_bytes: match cli.max_output {
+                0 => None, 
+                n => Some(n), 
+            }, 
+            max_line_chars: match cli.max_line_chars {
+                0 => None, 
+                n => Some(n), 
+            }, 
+            keep_ansi: cli.keep_ansi, 
+            env: cli.env.iter().cloned().collect(), 
+        }
+    )?;
+    let default_options = SessionOptions::default();
+    let options = SessionOptions {
+        command_timeout: cli.command_timeout.map_or(default_options.command_timeout, time::Duration::from_secs),
+        summarize_on_exit: cli.summarize_on_exit,
+        max_images: cli.max_images,
+        max_image_dimension: cli.max_image_dim,
+        pdf_pages: cli.pdf_pages.clone(),
+        pdf_renderer: cli.pdf_renderer.clone(),
+        stream_chunk_size: cli.stream_chunk_size,
+        stream_deadline: time::Duration::from_secs(cli.stream_deadline),
+        non_interactive: cli.non_interactive,
+        command_hook: match (cli.allow.is_empty() && cli.deny.is_empty(), cli.confirm) {
+            (true, false) => None, 
+            (false, false) => Some(pattern_hook(cli.allow.clone(), cli.deny.clone())), 
+            (true, true) => Some(confirm_hook(|line: &mut String| io::stdin().read_line(line), io::stderr())), 
+            (false, true) => Some(chain_hooks(
+                pattern_hook(cli.allow.clone(), cli.deny.clone()), 
+                confirm_hook(|line: &mut String| io::stdin().read_line(line), io::stderr()), 
+            )), 
+        },
+        transcript_format: cli.transcript_format.as_ref().map(TranscriptFormatChoice::to_transcript_format),
+        candidate_selection: match cli.pick_candidate {
+            true => CandidateSelection::Interactive, 
+            false => CandidateSelection::FirstValid, 
+        },
+        sudo_policy: cli.sudo.to_sudo_policy(),
+        // Several sessions printing at once would interleave
+        echo: !cli.tui && cli.parallel <= 1,
+        report_paused_commands: cli.report_paused_commands,
+        show_cost: cli.show_cost,
+        verbose_usage: cli.verbose_usage,
+        raw_responses: cli.debug_raw,
+        transfer_root: cli.transfer_root.clone(),
+        max_transfer_bytes: cli.max_transfer_size,
+        liveness_interval: cli.liveness_interval.map(time::Duration::from_secs),
+        filter_retries: cli.filter_retries,
+        max_message_bytes: Some(cli.max_message_bytes),
+        number_output: cli.number_output,
+        show_cwd: cli.show_cwd,
+        show_duration: cli.show_duration,
+        auto_mask_over: cli.auto_mask_over,
+        auto_mask_after_turns: cli.auto_mask_after,
+        auto_compact: cli.auto_compact,
+        max_iterations: cli.max_iterations,
+        dry_run: cli.dry_run,
+        log_dir: cli.log_dir.clone(),
+        redactor: redactor(cli),
+        cached_commands: match (cli.cache_output, cli.cache_commands.is_empty()) {
+            (false, _) => None, 
+            (true, true) => Some(OutputCache::default_read_only()), 
+            (true, false) => Some(cli.cache_commands.clone()), 
+        },
+        ..default_options
+    };
 
-    // Run the conversation loop
-    if let Err(e) = run_session_loop(&mut llm_kind, terminal, command_timeout) {
-        eprintln!("Session loop terminated with error: {}", e);
-        // If an error occurs, still save the session log
-        if let Err(e2) = llm_kind.apply(
-            |anthropic_llm| save_session_log(anthropic_llm),
-            |openai_llm| save_session_log(openai_llm),
-        ) {
-            eprintln!("Failed to save session log: {}", e2);
+    // Build the appropriate SessionKind variant
+    let mut session = match (primary_api, fallback_apis.is_empty()) {
+        (primary_api, false) => {
+            let fallback_api = FallbackApi::new(primary_api, fallback_apis);
+            SessionKind::Fallback(Session::new(LLM::new(fallback_api, system_prompt), terminal, options))
         }
-        return Err(Box::new(e));
-    }
+        (AnyApi::Anthropic(anthropic_api), true) => {
+            SessionKind::Anthropic(Session::new(LLM::new(anthropic_api, system_prompt), terminal, options))
+        }
+        (AnyApi::OpenAI(oai_api), true) => {
+            SessionKind::OpenAI(Session::new(LLM::new(oai_api, system_prompt), terminal, options))
+        }
+        (AnyApi::Gemini(gemini_api), true) => {
+            SessionKind::Gemini(Session::new(LLM::new(gemini_api, system_prompt), terminal, options))
+        }
+        (AnyApi::Ollama(ollama_api), true) => {
+            SessionKind::Ollama(Session::new(LLM::new(ollama_api, system_prompt), terminal, options))
+        }
+    };
 
-    // On successful exit, also save session log
-    if let Err(e2) = llm_kind.apply(
-        |anthropic_llm| save_session_log(anthropic_llm),
-        |openai_llm| save_session_log(openai_llm),
-    ) {
-        eprintln!("Failed to save session log: {}", e2);
+    for path in &cli.reference_files {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read reference file {}: {e}", path.display()))?;
+        session.add_reference(&path.display().to_string(), &text);
     }
+    Ok(session)
+}
 
-    Ok(())
+/// Runs the conversation loop of a session until the model exits or it fails
+fn run_session(cli: &Cli, session: &mut SessionKind) -> Result<(), Box<dyn std::error::Error>> {
+    let result = match cli.tui {
+        true => session.run_tui(), 
+        false => session.run().map_err(|e| e.into()), 
+    };
+    if cli.context_window_report {
+        session.print_context_report();
+    }
+    if let Err(e) = &result {
+        eprintln!("Session loop terminated with error: {}", e);
+    }
+    result
 }
 
-/// Runs a loop that prompts the LLM and feeds it terminal output
-fn run_session_loop(llm_kind: &mut LLMKind, terminal: Terminal, command_timeout: time::Duration) -> Result<(), LLMApiError> {
-    match llm_kind {
-        LLMKind::AnthropicLLM(llm) => run_session_loop_generic(llm, terminal, command_timeout), 
-        LLMKind::OpenAILLM(llm) => run_session_loop_generic(llm, terminal, command_timeout), 
+/// Saves the session log whether or not the session succeeded, returning its path
+fn save_log(session: &mut SessionKind) -> Option<PathBuf> {
+    match session.save_log() {
+        Ok(path) => Some(path), 
+        Err(e) => {
+            eprintln!("Failed to save session log: {}", e);
+            None
+        }, 
     }
 }
 
-fn run_session_loop_generic<Api: LLMApi>(
-    llm: &mut LLM<Api>,
-    mut terminal: Terminal, 
-    command_timeout: time::Duration, 
-) -> Result<(), LLMApiError> {
-    let timeout = time::Duration::from_secs(10);
+/// How one task of a batch went
+struct TaskReport {
+    task: String,
+    /// Why the task failed, `None` if the model exited on its own
+    error: Option<String>,
+    n_steps: usize,
+    cost: f64,
+    duration: time::Duration,
+    log: Option<PathBuf>,
+}
 
-    let mut n_msgs_printed = 0;
+/// Reads the single task from --task-file, from stdin when the task is `-`, or from the argument itself
+fn load_task(cli: &Cli) -> Result<String, Box<dyn std::error::Error>> {
+    let task = match (&cli.task_file, cli.task.as_deref()) {
+        (Some(path), _) => fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read task file {}: {e}", path.display()))?, 
+        (None, Some("-")) if cli.confirm => {
+            return Err("--confirm reads its answers from stdin, so the task cannot be read from it too, use --task-file".into());
+        }, 
+        (None, Some("-")) => io::read_to_string(io::stdin())
+            .map_err(|e| format!("Failed to read the task from stdin: {e}"))?, 
+        (None, Some(task)) => task.to_string(), 
+        (None, None) => unreachable!("a task is required unless replaying or running a batch"), 
+    };
+    let task = task.trim();
+    if task.is_empty() {
+        return Err("The task is empty".into());
+    }
+    Ok(task.to_string())
+}
 
-    let mut user_control = false;
+/// Reads the tasks of a batch, either a JSON array of strings or one task per line.
+/// Blank lines and lines starting with # are skipped
+fn load_tasks(path: &PathBuf) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read tasks file {}: {e}", path.display()))?;
 
-    llm.add_msg(
-        Message {
-            role: Role::User,
-            content: "".into(),
-        }
-    );
+    let tasks: Vec<String> = match text.trim_start().starts_with('[') {
+        true => serde_json::from_str(&text)
+            .map_err(|e| format!("Tasks file {} is not a JSON array of strings: {e}", path.display()))?, 
+        false => text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect(), 
+    };
+    if tasks.is_empty() {
+        return Err(format!("Tasks file {} has no tasks", path.display()).into());
+    }
+    Ok(tasks)
+}
 
-    loop {
-        let n_msgs = llm.num_msgs();
-        for id in n_msgs_printed..n_msgs {
-            let msg = llm
-                        .get_msg(id)
-                        .unwrap()
-                        .to_message_with_id_no_mask();
-
-            match msg.role {
-                Role::Assistant => {
-                    println!("LLM: {}", msg.content.to_string());
-                }, 
-                Role::User => {
-                    println!("Terminal: {}", msg.content.to_string());
-                }, 
-            }
-        }
-        n_msgs_printed = n_msgs;
-
-        let (llm_resp, usage) = match user_control {
-            true => {
-                print!("LLM: {n_msgs}>>");
-                io::stdout().flush().expect("Failed to flush stdout");
-                n_msgs_printed += 1;
-
-                let mut input = String::new();
-                io::stdin().read_line(&mut input).expect("Failed to read line");
-                let llm_resp = serde_json::from_str(&input);
-                llm.add_msg(
-                    Message {
-                        role: Role::Assistant, 
-                        content: input.trim().into(), 
-                    }
-                );
-                (llm_resp, None)
-            }, 
-            false => {
-                match llm.prompt(timeout) {
-                    Ok((llm_resp, usage)) => (llm_resp, Some(usage)),
-                    Err(e) => {
-                        eprintln!("Error communicating with LLM: {}", e);
-                        return Err(e);
-                    }
-                }
-            }, 
+/// Prints the outcome, steps, cost, duration and log of every task that ran and the batch's totals.
+/// Tasks that never ran, because the batch stopped early, are `None`
+fn print_batch_report(reports: &[Option<TaskReport>], elapsed: time::Duration) {
+    println!();
+    println!("Batch report:");
+    for (i, report) in reports.iter().enumerate() {
+        let Some(report) = report else {
+            println!("{:>3}. not run", i + 1);
+            continue;
         };
-
-        // Send the last content output to the LLM
-        /*let (llm_resp, usage) = match llm.prompt(timeout) {
-            Ok(resp) => resp,
-            Err(e) => {
-                eprintln!("Error communicating with LLM: {}", e);
-                return Err(e);
-            }
-        };*/
-
-        /*let response_id = llm.last_msg_id().unwrap();
-        let prompt_id = response_id - 1;
-        let last_response_msg = llm.get_msg(response_id).unwrap();
-        let last_prompt_msg = llm.get_msg(prompt_id).unwrap();
-
-        println!("Terminal: {}", last_prompt_msg.to_message_with_id_no_mask().content.to_string());
-        println!("LLM: {}", last_response_msg.to_message_with_id_no_mask().content.to_string());*/
-
-        
-
-        match llm_resp {
-            Ok(llm_resp) => {
-                match llm_resp {
-                    LLMResponse::Command(command) => {
-                        // Execute in the hidden terminal
-                        match terminal.run_command(&command, command_timeout) {
-                            Ok(output) => {
-                                let output = match output {
-                                    CommandOutput::Complete(out) => out, 
-                                    CommandOutput::Partial(out) => format!("Partial output, command timed out: {out}"), 
-                                };
-
-                                llm.add_msg(
-                                    Message {
-                                        role: Role::User, 
-                                        content: output.into(), 
-                                    }
-                                );
-                            }
-                            Err(e) => {
-                                eprintln!("Terminal error: {e}");
-                                return Ok(());
-                            }
-                        }
-                    }, 
-                    LLMResponse::LLMSee(img_path) => {
-                        let content = match Image::from_file(&img_path) {
-                            Ok(img) => img.into(), 
-                            Err(e) => e.to_string().into(), 
-                        };
-        
-                        llm.add_msg(
-                            Message {
-                                role: Role::User, 
-                                content: content, 
-                            }
-                        );
-                    }, 
-                    LLMResponse::MaskContent(id) => {
-                        llm.mask_message(id);
-                        llm.add_msg(
-                            Message {
-                                role: Role::User, 
-                                content: format!("message {id} is masked").into(), 
-                            }
-                        );
-                    }, 
-                    LLMResponse::UserControl => {
-                        user_control = true;
-                        llm.add_msg(
-                            Message {
-                                role: Role::User, 
-                                content: "Switched to external control.".into(), 
-                            }
-                        );
-                    }, 
-                    LLMResponse::AgentControl => {
-                        user_control = false;
-                        llm.add_msg(
-                            Message {
-                                role: Role::User, 
-                                content: "Switched back to agent control.".into(), 
-                            }
-                        );
-                    }, 
-                    LLMResponse::Exit => {
-                        println!("Terminal session terminated.");
-                        return Ok(());
-                    }, 
-                }
-            }, 
-            Err(e) => {
-                llm.add_msg(
-                    Message {
-                        role: Role::User, 
-                        content: "Invalid output. Omit id, must be json parsable.".into(), 
-                    }
-                );
-            }, 
+        let outcome = match &report.error {
+            None => "finished".to_string(), 
+            Some(e) => format!("failed: {e}"), 
         };
-
-        if let Some(usage) = usage {
-            if usage.n_input_tokens + usage.n_output_tokens > llm.max_context_tokens() * 9 / 10 {
-                llm.add_msg(
-                    Message {
-                        role: Role::User, 
-                        content: "Warning: over 90% of token context is used.".into(), 
-                    }
-                );
-            }
-        }
-
-        // Add a small delay between iterations
-        thread::sleep(time::Duration::from_millis(200));
+        let log = match &report.log {
+            Some(path) => path.display().to_string(), 
+            None => "no log".to_string(), 
+        };
+        println!("{:>3}. {} ({} steps, ${:.4}, {:.0}s, {log})", i + 1, report.task, report.n_steps, report.cost, report.duration.as_secs_f64());
+        println!("     {outcome}");
     }
-}
-
 
+    let ran: Vec<&TaskReport> = reports.iter().flatten().collect();
+    let n_finished = ran.iter().filter(|report| report.error.is_none()).count();
+    let total_cost: f64 = ran.iter().map(|report| report.cost).sum();
+    let total_steps: usize = ran.iter().map(|report| report.n_steps).sum();
+    let task_time: f64 = ran.iter().map(|report| report.duration.as_secs_f64()).sum();
+    println!(
+        "{n_finished} of {} tasks finished, {} failed, {} not run, {total_steps} steps, ${total_cost:.4} in total",
+        reports.len(), ran.len() - n_finished, reports.len() - ran.len(),
+    );
+    println!("Took {:.0}s, the tasks ran for {task_time:.0}s combined", elapsed.as_secs_f64());
+}
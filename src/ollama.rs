@@ -0,0 +1,294 @@
+use serde::{Serialize, Deserialize};
+use crate::llm::{self, LLMApi, ModelInfo, ApiResponse, LLMApiError, Message, Role};
+use crate::http::Transport;
+
+/// Where an Ollama server listens unless told otherwise
+pub const DEFAULT_HOST: &str = "http://localhost:11434";
+
+/// A model served by a local Ollama server, no key is needed and nothing is charged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OllamaApi {
+    host: String,
+    model: Model,
+    #[serde(default)]
+    max_output_tokens: Option<usize>,
+    /// Extra fields added to every request body
+    #[serde(default)]
+    params: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip)]
+    transport: Transport,
+}
+
+impl OllamaApi {
+    pub fn new(model: Model) -> Self {
+        Self {
+            host: DEFAULT_HOST.to_string(),
+            model,
+            max_output_tokens: None,
+            params: serde_json::Map::new(),
+            transport: Transport::default(),
+        }
+    }
+
+    /// Talks to the server at `host`, e.g. `http://gpu-box:11434`, instead of the local one
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = host.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Requests at most `n` output tokens instead of the model's maximum
+    pub fn with_max_output_tokens(mut self, n: usize) -> Self {
+        self.max_output_tokens = Some(llm::clamp_output_tokens(&self.model, n));
+        self
+    }
+
+    /// Adds fields the crate does not model, like `keep_alive`, to every request
+    pub fn with_params(mut self, params: serde_json::Map<String, serde_json::Value>) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Records or replays requests instead of only sending them
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+}
+
+/// Any model the server has pulled. Its name and context size are up to the user,
+/// since they are not known until the server is asked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Model {
+    /// Name as the server knows it, e.g. `llama3.1:8b`
+    pub name: String,
+    /// Tokens the server is asked to keep in context, sent as `num_ctx` since its own default is small
+    pub max_context_tokens: usize,
+}
+
+impl Model {
+    pub fn new(name: &str, max_context_tokens: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            max_context_tokens,
+        }
+    }
+}
+
+impl ModelInfo for Model {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn max_context_tokens(&self) -> usize {
+        self.max_context_tokens
+    }
+
+    fn max_output_tokens(&self) -> usize {
+        // The server has no limit of its own besides the context
+        4096.min(self.max_context_tokens / 2)
+    }
+
+    fn max_image_dimension(&self) -> u32 {
+        // Vision models like llava scale images down to a few hundred pixels anyway
+        2048
+    }
+
+    fn max_image_bytes(&self) -> usize {
+        20 * 1024 * 1024
+    }
+
+    fn max_images(&self) -> usize {
+        // Models without vision ignore them, the server does not reject them
+        10
+    }
+
+    fn input_price(&self) -> f64 {
+        0.0
+    }
+
+    fn output_price(&self) -> f64 {
+        0.0
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct OllamaRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: Options,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Options {
+    num_ctx: usize,
+    num_predict: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OllamaMessage {
+    pub role: OllamaRole,
+    pub content: String,
+    /// Base64 encoded images, only vision models look at them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+impl From<llm::Message> for OllamaMessage {
+    fn from(msg: llm::Message) -> Self {
+        let items = match msg.content {
+            llm::Content::Single(c) => vec![c],
+            llm::Content::Multiple(cs) => cs,
+        };
+        let mut content = String::new();
+        let mut images = Vec::new();
+        for item in items {
+            match item {
+                llm::ContentItem::Text(text) => content.push_str(&text),
+                llm::ContentItem::Image(image) => images.push(image.data),
+            }
+        }
+        Self {
+            role: msg.role.into(),
+            content,
+            images,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OllamaRole {
+    System,
+    Assistant,
+    User,
+    /// Results of tool calls, which this crate does not make
+    Tool,
+}
+
+impl From<Role> for OllamaRole {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::System => OllamaRole::System,
+            Role::Assistant => OllamaRole::Assistant,
+            Role::User => OllamaRole::User,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OllamaResult {
+    Success(OllamaResponse),
+    Error(ErrorResponse),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OllamaResponse {
+    pub model: String,
+    pub message: OllamaMessage,
+    #[serde(default)]
+    pub done_reason: Option<DoneReason>,
+    /// Input tokens evaluated, missing when the whole prompt was cached from the last request
+    #[serde(default)]
+    pub prompt_eval_count: Option<u32>,
+    #[serde(default)]
+    pub eval_count: Option<u32>,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoneReason {
+    Stop,
+    Length,
+    #[serde(other)]
+    Other,
+}
+
+impl TryInto<llm::StopReason> for DoneReason {
+    type Error = &'static str;
+
+    fn try_into(self) -> Result<llm::StopReason, Self::Error> {
+        match self {
+            DoneReason::Stop => Ok(llm::StopReason::EndTurn),
+            DoneReason::Length => Ok(llm::StopReason::MaxTokens),
+            DoneReason::Other => Err("Other has no equivalent in StopReason"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+impl LLMApi for OllamaApi {
+    fn model_info(&self) -> &dyn ModelInfo {
+        &self.model
+    }
+
+    fn prompt(&self, system_msg: &str, msgs: impl IntoIterator<Item = Message>) -> Result<ApiResponse, LLMApiError> {
+        let msgs: Vec<Message> = msgs.into_iter().collect();
+        let max_tokens = llm::output_token_budget(&self.model, self.max_output_tokens, system_msg, &msgs);
+
+        let system_msg = OllamaMessage {
+            role: OllamaRole::System,
+            content: system_msg.to_string(),
+            images: Vec::new(),
+        };
+        let mut messages = vec![system_msg];
+        messages.extend(msgs.into_iter().map(|msg| msg.into()));
+
+        let request_body = OllamaRequest {
+            model: self.model.name.clone(),
+            messages,
+            stream: false,
+            options: Options {
+                num_ctx: self.model.max_context_tokens,
+                num_predict: max_tokens,
+            },
+        };
+        let mut request_body = serde_json::to_value(&request_body)?;
+        llm::merge_params(&mut request_body, &self.params);
+
+        let headers = [("Content-Type", "application/json")];
+        let (status, body) = self.transport.post(&format!("{}/api/chat", self.host), &headers, "", &request_body)?;
+        let result: OllamaResult = match serde_json::from_str(&body) {
+            Ok(result) => result,
+            Err(_) if !status.is_success() => return Err(status.into()),
+            Err(e) => return Err(e.into()),
+        };
+
+        match result {
+            OllamaResult::Success(response) => {
+                let stop_reason = response.done_reason
+                    .map_or(Ok(llm::StopReason::EndTurn), |reason| reason.try_into())
+                    .map_err(|_| LLMApiError::Other)?;
+
+                // Counts are best effort, a prompt served from the server's cache has none
+                let usage = llm::Usage {
+                    n_input_tokens: response.prompt_eval_count.unwrap_or(0) as usize,
+                    n_output_tokens: response.eval_count.unwrap_or(0) as usize,
+                    ..llm::Usage::default()
+                };
+
+                Ok(
+                    ApiResponse {
+                        resp: response.message.content,
+                        stop_reason,
+                        usage,
+                        alternatives: Vec::new(),
+                    }
+                )
+            },
+            OllamaResult::Error(err) => {
+                eprintln!("Ollama error: {}", err.error);
+                // The server answers 404 for models that were never pulled
+                match status.as_u16() {
+                    404 => Err(LLMApiError::NotFoundError),
+                    _ => Err(status.into()),
+                }
+            },
+        }
+    }
+}
@@ -1,11 +1,35 @@
 use serde::{Serialize, Serializer, ser::SerializeMap, Deserialize};
-use crate::llm::{self, LLMApi, ApiResponse, StopReason, LLMApiError, Message, Role};
+use crate::llm::{self, LLMApi, ModelInfo, ApiResponse, StopReason, LLMApiError, Message};
+use crate::http::Transport;
+
+/// Where the chat completions API lives unless overridden with `OAIApi::with_base_url`
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OAIApi {
     #[serde(skip_serializing)]
     secret_key: Option<String>,
     model: Model,
+    #[serde(default)]
+    image_detail: ImageDetail,
+    #[serde(default)]
+    n_candidates: Option<u32>,
+    #[serde(default)]
+    max_output_tokens: Option<usize>,
+    /// Extra fields added to every request body
+    #[serde(default)]
+    params: serde_json::Map<String, serde_json::Value>,
+    /// Root of an OpenAI compatible API, `DEFAULT_BASE_URL` when not set
+    #[serde(default)]
+    base_url: Option<String>,
+    /// Temperature or nucleus sampling, ignored by o1 models which only sample their own way
+    #[serde(default)]
+    sampling: Option<Sampling>,
+    /// How long o1 models think before answering, the API's default when not set
+    #[serde(default)]
+    reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip)]
+    transport: Transport,
 }
 
 impl OAIApi {
@@ -13,8 +37,64 @@ impl OAIApi {
         Self {
             secret_key: Some(key),
             model,
+            image_detail: ImageDetail::default(),
+            n_candidates: None,
+            max_output_tokens: None,
+            params: serde_json::Map::new(),
+            base_url: None,
+            sampling: None,
+            reasoning_effort: None,
+            transport: Transport::default(),
         }
     }
+
+    pub fn with_image_detail(mut self, image_detail: ImageDetail) -> Self {
+        self.image_detail = image_detail;
+        self
+    }
+
+    /// Samples `n` responses per request, multiplying the output cost
+    pub fn with_candidates(mut self, n: u32) -> Self {
+        self.n_candidates = (n > 1).then_some(n);
+        self
+    }
+
+    /// Requests at most `n` output tokens instead of leaving the limit to the API
+    pub fn with_max_output_tokens(mut self, n: usize) -> Self {
+        self.max_output_tokens = Some(llm::clamp_output_tokens(&self.model, n));
+        self
+    }
+
+    /// Adds fields the crate does not model, like `frequency_penalty`, to every request
+    pub fn with_params(mut self, params: serde_json::Map<String, serde_json::Value>) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Samples with a temperature or top_p instead of the API's default, o1 models ignore it
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = Some(sampling);
+        self
+    }
+
+    /// Has o1 models spend more or less reasoning tokens, other models ignore it
+    pub fn with_reasoning_effort(mut self, reasoning_effort: ReasoningEffort) -> Self {
+        self.reasoning_effort = Some(reasoning_effort);
+        self
+    }
+
+    /// Sends requests to another OpenAI compatible API, e.g. `https://api.groq.com/openai/v1`,
+    /// which gets `/chat/completions` appended
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = Some(base_url.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Records or replays requests instead of only sending them
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -31,8 +111,18 @@ pub enum Model {
     O1Preview,
 }
 
-impl Model {
-    pub fn max_context_tokens(self) -> usize {
+impl ModelInfo for Model {
+    fn name(&self) -> &'static str {
+        match self {
+            Model::GPT4O => "gpt-4o", 
+            Model::GPT4OMini => "gpt-4o-mini", 
+            Model::O1 => "o1", 
+            Model::O1Mini => "o1-mini", 
+            Model::O1Preview => "o1-preview", 
+        }
+    }
+
+    fn max_context_tokens(&self) -> usize {
         match self {
             Model::GPT4O => 128_000, 
             Model::GPT4OMini => 128_000, 
@@ -42,7 +132,7 @@ impl Model {
         }
     }
 
-    pub fn max_output_tokens(self) -> usize {
+    fn max_output_tokens(&self) -> usize {
         match self {
             Model::GPT4O => 4096, 
             Model::GPT4OMini => 4096, 
@@ -51,9 +141,53 @@ impl Model {
             Model::O1Preview => 4096, 
         }
     }
+
+    fn max_image_dimension(&self) -> u32 {
+        // Larger images are scaled down by the API anyway
+        2048
+    }
+
+    fn max_image_bytes(&self) -> usize {
+        20 * 1024 * 1024
+    }
+
+    fn max_images(&self) -> usize {
+        match self {
+            Model::O1Mini | Model::O1Preview => 0, 
+            _ => 500, 
+        }
+    }
+
+    fn input_price(&self) -> f64 {
+        match self {
+            Model::GPT4O => 2.50, 
+            Model::GPT4OMini => 0.15, 
+            Model::O1 => 15.0, 
+            Model::O1Mini => 3.0, 
+            Model::O1Preview => 15.0, 
+        }
+    }
+
+    fn output_price(&self) -> f64 {
+        match self {
+            Model::GPT4O => 10.0, 
+            Model::GPT4OMini => 0.60, 
+            Model::O1 => 60.0, 
+            Model::O1Mini => 12.0, 
+            Model::O1Preview => 60.0, 
+        }
+    }
+
+    fn estimate_tokens(&self, text: &str) -> usize {
+        // Every supported model uses the o200k tokenizer
+        tiktoken_rs::o200k_base_singleton()
+            .lock()
+            .encode_ordinary(text)
+            .len()
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Sampling {
     Temperature { temperature: f32 },
@@ -70,6 +204,8 @@ struct OAIRequest {
     sampling: Option<Sampling>,
     #[serde(skip_serializing_if = "Option::is_none")]
     reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -94,6 +230,18 @@ pub enum Content {
     Mixed(Vec<ContentElem>), 
 }
 
+impl Content {
+    fn set_image_detail(&mut self, detail: ImageDetail) {
+        if let Content::Mixed(elems) = self {
+            for elem in elems {
+                if let ContentElem::Image(img) = elem {
+                    img.detail = detail;
+                }
+            }
+        }
+    }
+}
+
 impl From<llm::Content> for Content {
     fn from(content: llm::Content) -> Self {
         match content {
@@ -156,6 +304,7 @@ impl Serialize for ContentElem {
 pub struct Image {
     media_type: MediaType, 
     data: String, 
+    detail: ImageDetail, 
 }
 
 impl From<llm::Image> for Image {
@@ -163,6 +312,7 @@ impl From<llm::Image> for Image {
         Self {
             media_type: image.image_type.into(),
             data: image.data,
+            detail: ImageDetail::default(),
         }
     }
 }
@@ -173,12 +323,23 @@ impl Serialize for Image {
         S: Serializer,
     {
         let data_url = format!("data:{};base64,{}", self.media_type, self.data);
-        let mut map = serializer.serialize_map(Some(1))?;
+        let mut map = serializer.serialize_map(Some(2))?;
         map.serialize_entry("url", &data_url)?;
+        map.serialize_entry("detail", &self.detail)?;
         map.end()
     }
 }
 
+/// Resolution at which the model views an image, low is much cheaper in tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetail {
+    Low,
+    High,
+    #[default]
+    Auto,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MediaType {
     #[serde(rename = "image/jpeg")]
@@ -226,11 +387,12 @@ impl From<llm::Role> for OAIRole {
         match role {
             llm::Role::Assistant => OAIRole::Assistant,
             llm::Role::User => OAIRole::User,
+            llm::Role::System => OAIRole::Developer,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ReasoningEffort {
     Low,
@@ -244,7 +406,9 @@ pub struct OAIResponse {
     pub object: String,
     pub created: u64,
     pub model: String,
-    pub system_fingerprint: String,
+    /// Left out by most OpenAI compatible APIs
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
     pub choices: Vec<Choice>,
     pub usage: UsageInfo,
 }
@@ -257,9 +421,13 @@ pub struct Choice {
 }
 
 #[derive(Debug, Clone, Deserialize)]
-struct OAIMessageResp {
-    role: OAIRole,
-    content: String,
+pub struct OAIMessageResp {
+    pub role: OAIRole,
+    /// Null when the model returned nothing, e.g. because of a content filter
+    pub content: Option<String>,
+    /// Set instead of `content` when the model refuses
+    #[serde(default)]
+    pub refusal: Option<String>,
 }
 
 #[derive(Debug, Copy, Clone, Deserialize)]
@@ -279,7 +447,8 @@ impl TryInto<StopReason> for FinishReason {
             FinishReason::Stop => Ok(StopReason::EndTurn),
             FinishReason::Length => Ok(StopReason::MaxTokens),
             FinishReason::ContentFilter => Err("ContentFilter has no equivalent in StopReason"),
-            FinishReason::ToolCalls => Err("ToolCalls has no equivalent in StopReason"),
+            // No tools are offered, so whatever the model wrote before calling one is all there is
+            FinishReason::ToolCalls => Ok(StopReason::EndTurn),
         }
     }
 }
@@ -289,18 +458,32 @@ pub struct UsageInfo {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
-    pub completion_tokens_details: TokenDetails,
+    /// Left out by most OpenAI compatible APIs
+    #[serde(default)]
+    pub completion_tokens_details: Option<TokenDetails>,
+    #[serde(default)]
+    pub prompt_tokens_details: Option<PromptTokenDetails>,
 }
 
-impl Into<llm::Usage> for UsageInfo {
-    fn into(self) -> llm::Usage {
+impl From<UsageInfo> for llm::Usage {
+    fn from(usage: UsageInfo) -> Self {
         llm::Usage {
-            n_input_tokens: self.prompt_tokens as usize, 
-            n_output_tokens: self.completion_tokens as usize, 
+            n_input_tokens: usage.prompt_tokens as usize, 
+            n_output_tokens: usage.completion_tokens as usize, 
+            reasoning_tokens: usage.completion_tokens_details.map(|details| details.reasoning_tokens as usize), 
+            cache_read_tokens: usage.prompt_tokens_details.map(|details| details.cached_tokens as usize), 
+            // Caching is automatic and writing to the cache is not reported
+            cache_creation_tokens: None, 
         }
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct PromptTokenDetails {
+    #[serde(default)]
+    pub cached_tokens: u32,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct TokenDetails {
     pub reasoning_tokens: u32,
@@ -324,16 +507,24 @@ impl From<reqwest::StatusCode> for LLMApiError {
 
 
 impl LLMApi for OAIApi {
-    fn max_context_tokens(&self) -> usize {
-        self.model.max_context_tokens()
+    fn model_info(&self) -> &dyn ModelInfo {
+        &self.model
     }
 
     fn prompt(&self, system_msg: &str, msgs: impl IntoIterator<Item = Message>) -> Result<ApiResponse, LLMApiError> {
         let secret_key = self.secret_key.as_ref().ok_or(LLMApiError::AuthenticationError)?;
 
+        // The limit is only sent when overridden or when the input leaves less room than the model's maximum
+        let msgs: Vec<Message> = msgs.into_iter().collect();
+        let max_tokens = llm::output_token_budget(&self.model, self.max_output_tokens, system_msg, &msgs);
+        let max_completion_tokens = (self.max_output_tokens.is_some() || max_tokens < self.model.max_output_tokens())
+            .then_some(max_tokens as u32);
+
         // As of January 2025 
         // O1 models do not support developer messages
         // change when support is added
+        let is_o1 = matches!(self.model, Model::O1 | Model::O1Mini | Model::O1Preview);
+        let supports_developer = !is_o1;
         let system_msg = match self.model {
             Model::O1 | Model::O1Mini | Model::O1Preview => OAIMessage {
                 role: OAIRole::User,
@@ -347,40 +538,63 @@ impl LLMApi for OAIApi {
 
         let mut messages = vec![system_msg];
 
-        messages.extend(msgs.into_iter().map(|msg| msg.into()));
+        messages.extend(msgs.into_iter().map(|msg| {
+            let mut msg: OAIMessage = msg.into();
+            msg.content.set_image_detail(self.image_detail);
+            if !supports_developer && matches!(msg.role, OAIRole::Developer) {
+                msg.role = OAIRole::User;
+            }
+            msg
+        }));
 
         let request_body = OAIRequest {
             model: self.model,
             messages,
-            max_completion_tokens: None,
-            sampling: None,
-            reasoning_effort: None,
+            max_completion_tokens,
+            // O1 models reject any sampling parameters
+            sampling: self.sampling.clone().filter(|_| !is_o1),
+            // Only o1 models accept a reasoning effort, the others reject the request
+            reasoning_effort: self.reasoning_effort.filter(|_| is_o1),
+            n: self.n_candidates,
         };
-
-        let client = reqwest::blocking::Client::new();
-        let response = client
-            .post("https://api.openai.com/v1/chat/completions")
-            .header("Authorization", format!("Bearer {}", secret_key))
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()?;
-
-        if !response.status().is_success() {
-            return Err(response.status().into());
+        let mut request_body = serde_json::to_value(&request_body)?;
+        llm::merge_params(&mut request_body, &self.params);
+
+        let authorization = format!("Bearer {}", secret_key);
+        let headers = [
+            ("Authorization", authorization.as_str()),
+            ("Content-Type", "application/json"),
+        ];
+        let url = format!("{}/chat/completions", self.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL));
+        let (status, body) = self.transport.post(&url, &headers, secret_key, &request_body)?;
+
+        if !status.is_success() {
+            return Err(status.into());
         }
 
-        let body = response.text()?;
         let result: OAIResponse = serde_json::from_str(&body)?;
 
         let choice = result.choices
             .first()
-            .ok_or(LLMApiError::Other)?;
+            .ok_or(LLMApiError::EmptyResponse)?;
+
+        if matches!(choice.finish_reason, FinishReason::ContentFilter) || choice.message.refusal.is_some() {
+            return Err(LLMApiError::ContentFiltered);
+        }
 
-        let resp = choice.message.content.clone();
+        let resp = choice.message.content.clone().unwrap_or_default();
         let stop_reason = choice.finish_reason
             .try_into()
             .map_err(|_| LLMApiError::Other)?;
 
+        // Truncated candidates cannot be continued, so only complete ones are offered
+        let alternatives = result.choices
+            .iter()
+            .skip(1)
+            .filter(|choice| matches!(choice.finish_reason, FinishReason::Stop))
+            .filter_map(|choice| choice.message.content.clone())
+            .collect();
+
         let usage = result.usage.into();
 
         Ok(
@@ -388,6 +602,7 @@ impl LLMApi for OAIApi {
                 resp, 
                 stop_reason, 
                 usage, 
+                alternatives, 
             }
         )
     }
@@ -0,0 +1,163 @@
+use std::io::{self, Write};
+
+/// What to do with a command the model wants to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandDecision {
+    /// Run the given command, which may differ from the one the model issued
+    Run(String),
+    /// Do not run the command and tell the model why
+    Block(String),
+    /// Do not run the command, the model is only told it was not run
+    Skip,
+    /// Do not run the command and send the given text to the model as the user's own message
+    Reject(String),
+}
+
+/// Called with every command before it reaches the terminal, including streamed ones.
+/// The hook sees the command exactly as the model issued it and is called once per command,
+/// so it may keep state between calls.
+pub type CommandHook = Box<dyn FnMut(&str) -> CommandDecision>;
+
+/// Builds a hook that blocks commands containing any of `deny`, and when `allow` is not empty,
+/// commands that do not start with one of `allow`
+pub fn pattern_hook(allow: Vec<String>, deny: Vec<String>) -> CommandHook {
+    Box::new(move |command| {
+        let command = command.trim();
+
+        if let Some(pattern) = deny.iter().find(|pattern| command.contains(pattern.as_str())) {
+            return CommandDecision::Block(format!("it contains the denied pattern {pattern:?}"));
+        }
+
+        if !allow.is_empty() && !allow.iter().any(|prefix| command.starts_with(prefix.as_str())) {
+            return CommandDecision::Block("it does not start with an allowed command".to_string());
+        }
+
+        CommandDecision::Run(command.to_string())
+    })
+}
+
+/// Builds a hook that runs `first` and passes every command it lets through on to `then`
+pub fn chain_hooks(mut first: CommandHook, mut then: CommandHook) -> CommandHook {
+    Box::new(move |command| match first(command) {
+        CommandDecision::Run(command) => then(&command), 
+        decision => decision, 
+    })
+}
+
+/// Builds a hook that shows each command on `output` and reads lines with `read_line`, like
+/// `Stdin::read_line`, to decide whether to run it, reject it with an optional reason for the model,
+/// or run an edited command instead. Running out of input rejects the command
+pub fn confirm_hook(
+    mut read_line: impl FnMut(&mut String) -> io::Result<usize> + 'static, 
+    mut output: impl Write + 'static, 
+) -> CommandHook {
+    Box::new(move |command| {
+        let mut ask = |prompt: &str| -> Option<String> {
+            write!(output, "{prompt}").ok()?;
+            output.flush().ok()?;
+            let mut line = String::new();
+            match read_line(&mut line) {
+                Ok(0) | Err(_) => None, 
+                Ok(_) => Some(line.trim().to_string()), 
+            }
+        };
+
+        loop {
+            let Some(answer) = ask(&format!("Run `{command}`? [y]es/[n]o/[e]dit: ")) else {
+                return CommandDecision::Reject("The user rejected the command.".to_string());
+            };
+            match answer.to_lowercase().as_str() {
+                "y" | "yes" => return CommandDecision::Run(command.to_string()), 
+                "n" | "no" => {
+                    let reason = ask("Reason for the model (optional): ").unwrap_or_default();
+                    return CommandDecision::Reject(match reason.is_empty() {
+                        true => "The user rejected the command.".to_string(), 
+                        false => format!("The user rejected the command: {reason}"), 
+                    });
+                }, 
+                "e" | "edit" => match ask("Command to run instead: ") {
+                    Some(edited) if !edited.is_empty() => return CommandDecision::Run(edited), 
+                    Some(_) => continue, 
+                    None => return CommandDecision::Reject("The user rejected the command.".to_string()), 
+                }, 
+                _ => continue, 
+            }
+        }
+    })
+}
+
+/// How commands that could stop at a sudo password prompt are handled, the prompt would
+/// otherwise hang the terminal until the command times out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SudoPolicy {
+    /// Add `-n` so sudo fails immediately when a password would be needed
+    #[default]
+    Rewrite, 
+    /// Do not run the command and tell the model interactive sudo is not allowed
+    Reject, 
+}
+
+impl SudoPolicy {
+    pub fn apply(&self, command: &str) -> CommandDecision {
+        let lines: Vec<(&str, Vec<usize>)> = command
+            .lines()
+            .map(|line| (line, interactive_sudo_ends(line)))
+            .collect();
+
+        if lines.iter().all(|(_, ends)| ends.is_empty()) {
+            return CommandDecision::Run(command.to_string());
+        }
+
+        match self {
+            SudoPolicy::Rewrite => CommandDecision::Run(
+                lines
+                    .into_iter()
+                    .map(|(line, ends)| {
+                        let mut line = line.to_string();
+                        for end in ends.into_iter().rev() {
+                            line.insert_str(end, " -n");
+                        }
+                        line
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ), 
+            SudoPolicy::Reject => CommandDecision::Block("interactive sudo is not allowed, use sudo -n".to_string()), 
+        }
+    }
+}
+
+/// Byte offsets right after every `sudo` in command position that has neither `-n` nor `-S`
+fn interactive_sudo_ends(line: &str) -> Vec<usize> {
+    let tokens: Vec<(usize, &str)> = line
+        .split_whitespace()
+        .map(|token| (token.as_ptr() as usize - line.as_ptr() as usize, token))
+        .collect();
+
+    let mut ends = Vec::new();
+    for (i, &(offset, token)) in tokens.iter().enumerate() {
+        let in_command_position = match i {
+            0 => true, 
+            _ => {
+                let prev = tokens[i - 1].1;
+                ["&&", "||", "|", ";"].contains(&prev) || prev.ends_with(';')
+            }, 
+        };
+        if token != "sudo" || !in_command_position {
+            continue;
+        }
+
+        let non_interactive = tokens[i + 1..]
+            .iter()
+            .map(|&(_, opt)| opt)
+            .take_while(|opt| opt.starts_with('-'))
+            .any(|opt| match opt.strip_prefix("--") {
+                Some(long) => long == "non-interactive" || long == "stdin", 
+                None => opt.contains('n') || opt.contains('S'), 
+            });
+        if !non_interactive {
+            ends.push(offset + token.len());
+        }
+    }
+    ends
+}
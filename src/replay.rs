@@ -0,0 +1,93 @@
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use crate::llm::{LLMResponse, MaskableMessage, Role};
+use crate::log::{msg_heading, SavedSession};
+use crate::terminal::{CommandOutput, Terminal};
+
+/// Prints a saved session turn by turn. When `step` is set it waits before showing what each
+/// of the model's responses led to, and lets the user re-run its command in a fresh shell.
+/// Commands are re-run with the session's recorded timeout and working directory,
+/// `command_timeout` is used for logs that have no recorded environment
+pub fn replay(log: &SavedSession, step: bool, command_timeout: Duration) -> Result<(), Box<dyn Error>> {
+    if let Some(model) = &log.metadata.model {
+        println!("Model: {model}, {} steps", log.metadata.n_steps);
+    }
+
+    // Only started once a command is re-run
+    let mut terminal: Option<Terminal> = None;
+    let environment = log.metadata.environment.as_ref();
+    let command_timeout = environment.map_or(command_timeout, |environment| Duration::from_millis(environment.command_timeout_ms));
+
+    for msg in &log.messages {
+        println!("{}: {}", msg_heading(msg), msg.get_message().content);
+
+        if !step || !matches!(msg.get_message().role, Role::Assistant) {
+            continue;
+        }
+
+        let command = recorded_command(msg);
+        loop {
+            match command {
+                Some(_) => print!("[Enter] show the recorded result, [r] re-run the command, [q] quit: "), 
+                None => print!("[Enter] show the recorded result, [q] quit: "), 
+            }
+            io::stdout().flush()?;
+
+            let mut input = String::new();
+            if io::stdin().read_line(&mut input)? == 0 {
+                return Ok(());
+            }
+
+            match (input.trim(), &command) {
+                ("", _) => break, 
+                ("q", _) => return Ok(()), 
+                ("r", Some(command)) => {
+                    let terminal = match &mut terminal {
+                        Some(terminal) => terminal, 
+                        None => {
+                            let mut new_terminal = Terminal::new()?;
+                            if let Some(dir) = environment.and_then(|environment| environment.working_dir.as_deref()) {
+                                new_terminal.run_command(&cd_command(dir), command_timeout)?;
+                                if new_terminal.last_exit_code() != Some(0) {
+                                    eprintln!("Could not change to the session's working directory {}, commands run in the current one", dir.display());
+                                }
+                            }
+                            terminal.insert(new_terminal)
+                        }, 
+                    };
+                    match terminal.run_command(command, command_timeout)? {
+                        CommandOutput::Complete(out) => println!("Re-run: {out}"), 
+                        CommandOutput::Partial(out) => println!("Re-run, timed out: {out}"), 
+                    }
+                }, 
+                _ => {}, 
+            }
+        }
+    }
+
+    if let Some(summary) = &log.metadata.summary {
+        println!("Summary: {summary}");
+    }
+    Ok(())
+}
+
+/// A command changing the shell's working directory to `dir`, quoted for the shell
+fn cd_command(dir: &Path) -> String {
+    let dir = dir.display().to_string();
+    // PowerShell escapes a quote in a single quoted string by doubling it
+    match cfg!(windows) {
+        true => format!("cd '{}'", dir.replace('\'', "''")), 
+        false => format!("cd -- '{}'", dir.replace('\'', "'\\''")), 
+    }
+}
+
+/// The shell command an assistant message ran, if it was one
+fn recorded_command(msg: &MaskableMessage) -> Option<String> {
+    let text = String::try_from(&msg.get_message().content).ok()?;
+    match serde_json::from_str(&text).ok()? {
+        LLMResponse::Command(command) | LLMResponse::Stream(command) => Some(command), 
+        _ => None, 
+    }
+}
@@ -0,0 +1,1314 @@
+use std::{fmt, thread, time};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::llm::*;
+use crate::anthropic::AnthropicApi;
+use crate::openai::OAIApi;
+use crate::gemini::GeminiApi;
+use crate::ollama::OllamaApi;
+use crate::fallback::FallbackApi;
+use crate::terminal::*;
+use crate::log::{save_session_log, save_transcript, Environment, Redactor, SessionMetadata, TranscriptFormat};
+use crate::policy::{CommandDecision, CommandHook, SudoPolicy};
+use crate::cache::OutputCache;
+use crate::transfer::FileTransfer;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+
+/// Settings that control the behavior of the session loop
+pub struct SessionOptions {
+    /// How long a command may go without printing anything before it is interrupted
+    pub command_timeout: time::Duration,
+    /// How long to keep retrying the model while it is rate limited or overloaded
+    pub prompt_timeout: time::Duration,
+    pub summarize_on_exit: bool,
+    pub max_images: Option<usize>,
+    /// Longest side images the model views are scaled down to, below the model's own limit
+    pub max_image_dimension: Option<u32>,
+    /// Largest image that is downloaded when the model views a URL, before it is scaled down
+    pub max_image_download_bytes: usize,
+    /// How long downloading an image the model views may take
+    pub image_download_timeout: time::Duration,
+    /// Pages of a PDF the model views, numbered from 1
+    pub pdf_pages: RangeInclusive<u32>,
+    /// Program rendering those pages, taking pdftoppm's arguments
+    pub pdf_renderer: PathBuf,
+    pub stream_chunk_size: usize,
+    pub stream_deadline: time::Duration,
+    pub non_interactive: bool,
+    pub command_hook: Option<CommandHook>,
+    /// Also export a readable transcript next to the json log when the log is saved
+    pub transcript_format: Option<TranscriptFormat>,
+    /// How to pick a reply when the backend samples several candidates
+    pub candidate_selection: CandidateSelection,
+    pub sudo_policy: SudoPolicy,
+    /// Print the conversation to stdout as it happens, off when something else displays it
+    pub echo: bool,
+    /// Tell the model which commands the user ran while the session was paused,
+    /// otherwise they are only kept as a note in the log
+    pub report_paused_commands: bool,
+    /// Commands whose output is cached and repeated instead of running them again, off when `None`
+    pub cached_commands: Option<Vec<String>>,
+    /// Print the cost of each turn and the running total next to the model's messages
+    pub show_cost: bool,
+    /// Print the tokens of each turn below the model's messages, including cached and reasoning tokens
+    pub verbose_usage: bool,
+    /// Print the model's responses as the raw json instead of a readable rendering
+    pub raw_responses: bool,
+    /// Directory the model can put and get files in, relative paths are resolved against it
+    pub transfer_root: PathBuf,
+    /// Largest file in bytes that can be put or retrieved
+    pub max_transfer_bytes: usize,
+    /// How often to check that the shell still responds, restarting it if it does not. Off when `None`
+    pub liveness_interval: Option<time::Duration>,
+    /// How many filtered responses in a row are answered with a request to rephrase before giving up
+    pub filter_retries: usize,
+    /// Text of any single message beyond this many bytes is cut off, off when `None`
+    pub max_message_bytes: Option<usize>,
+    /// Number the lines of command output the model sees, streamed output is left alone
+    pub number_output: bool,
+    /// Follow the output of each command with the shell's working directory
+    pub show_cwd: bool,
+    /// Follow the output of each finished command with how long it ran, e.g. `(took 4.2s)`
+    pub show_duration: bool,
+    /// Messages estimated at over this many tokens are masked once `auto_mask_after_turns` turns
+    /// have passed unless the model retains them, off when `None`
+    pub auto_mask_over: Option<usize>,
+    pub auto_mask_after_turns: usize,
+    /// Fraction of the context window, e.g. 0.8, beyond which old messages are masked before
+    /// prompting so the request still fits, off when `None`
+    pub auto_compact: Option<f32>,
+    /// Turns the model may take before the session is stopped, unlimited when `None`
+    pub max_iterations: Option<usize>,
+    /// Tell the model its commands were not run instead of running them, and write no files
+    pub dry_run: bool,
+    /// Where the log is saved instead of the default log directory
+    pub log_dir: Option<PathBuf>,
+    /// Scrubs secrets from the log and transcript before they are saved
+    pub redactor: Option<Redactor>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            command_timeout: time::Duration::from_secs(2),
+            prompt_timeout: time::Duration::from_secs(10),
+            summarize_on_exit: false,
+            max_images: None,
+            max_image_dimension: None,
+            max_image_download_bytes: 20 * 1024 * 1024,
+            image_download_timeout: time::Duration::from_secs(30),
+            pdf_pages: 1..=5,
+            pdf_renderer: PathBuf::from(PDF_RENDERER),
+            stream_chunk_size: 4000,
+            stream_deadline: time::Duration::from_secs(300),
+            non_interactive: false,
+            command_hook: None,
+            transcript_format: None,
+            candidate_selection: CandidateSelection::default(),
+            sudo_policy: SudoPolicy::default(),
+            echo: true,
+            report_paused_commands: false,
+            cached_commands: None,
+            show_cost: false,
+            verbose_usage: false,
+            raw_responses: false,
+            transfer_root: PathBuf::from("."),
+            max_transfer_bytes: 1024 * 1024,
+            liveness_interval: None,
+            filter_retries: 0,
+            max_message_bytes: Some(256 * 1024),
+            number_output: false,
+            show_cwd: false,
+            show_duration: false,
+            auto_mask_over: None,
+            auto_mask_after_turns: 1,
+            auto_compact: None,
+            max_iterations: None,
+            dry_run: false,
+            log_dir: None,
+            redactor: None,
+        }
+    }
+}
+
+/// How to choose between several sampled responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CandidateSelection {
+    /// The first candidate that is a valid response
+    #[default]
+    FirstValid,
+    /// Show the candidates and let the user pick, falls back to `FirstValid` when non-interactive
+    Interactive,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Api(LLMApiError),
+    /// A human was needed but the session runs non-interactively
+    InteractionRequired(&'static str),
+    /// The model took as many turns as `SessionOptions::max_iterations` allows
+    IterationLimit(usize),
+}
+
+impl From<LLMApiError> for SessionError {
+    fn from(error: LLMApiError) -> Self {
+        Self::Api(error)
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Api(err) => write!(f, "{}", err), 
+            SessionError::InteractionRequired(reason) => write!(f, "User interaction required in non-interactive mode: {}", reason), 
+            SessionError::IterationLimit(n) => write!(f, "Stopped after the model took its limit of {} turns", n), 
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::Api(err) => Some(err), 
+            _ => None, 
+        }
+    }
+}
+
+/// Whether the session can take another step
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StepOutcome {
+    #[default]
+    Continue,
+    Finished,
+}
+
+/// What happened during one step, for callers that build on top of the session loop
+#[derive(Debug, Clone, Default)]
+pub struct StepResult {
+    pub outcome: StepOutcome,
+    /// The parsed response, the last one run when the model output several.
+    /// `None` when it was not valid or the input was a note
+    pub response: Option<LLMResponse>,
+    /// The command that ran, after the command hook and sudo policy rewrote it
+    pub command: Option<String>,
+    /// Combined stdout and stderr, the pty does not keep them apart
+    pub output: Option<String>,
+    /// `None` when the command did not finish, or its output came from the cache
+    pub exit_code: Option<i32>,
+    pub duration: time::Duration,
+    /// The model's reasoning when the command was a `:` thought
+    pub thought: Option<String>,
+    /// Tokens used by the model for this step, `None` in user control
+    pub usage: Option<Usage>,
+    /// Contents of a file the model retrieved for the caller, base64 encoded
+    pub file: Option<String>,
+}
+
+/// An LLM driving a terminal, one response at a time
+pub struct Session<Api: LLMApi, T: TerminalLike = Terminal> {
+    llm: LLM<Api>,
+    terminal: T,
+    options: SessionOptions,
+    metadata: SessionMetadata,
+    last_usage: Option<Usage>,
+    pause_requested: Arc<AtomicBool>,
+    output_cache: Option<OutputCache>,
+    file_transfer: FileTransfer,
+    last_liveness_check: time::Instant,
+    /// Filtered responses in a row
+    n_filtered: usize,
+    /// Turns the model took, those of the user in control are not counted
+    n_model_turns: usize,
+    /// Cost of the turn that produced a message and the total after it, by message id
+    turn_costs: HashMap<usize, (f64, f64)>,
+    /// Usage of the turn that produced a message, by message id, kept for `verbose_usage`
+    turn_usages: HashMap<usize, Usage>,
+    /// Filled in while the current step runs
+    step_result: StepResult,
+    n_msgs_printed: usize,
+}
+
+impl<Api: LLMApi, T: TerminalLike> Session<Api, T> {
+    pub fn new(llm: LLM<Api>, terminal: T, options: SessionOptions) -> Self {
+        Self::with_metadata(llm, terminal, options, SessionMetadata::default())
+    }
+
+    /// Creates a session whose loop state, like the control mode, is taken from `metadata`
+    pub fn with_metadata(mut llm: LLM<Api>, mut terminal: T, options: SessionOptions, mut metadata: SessionMetadata) -> Self {
+        metadata.model = Some(llm.model_info().name().to_string());
+        llm.set_max_message_bytes(options.max_message_bytes);
+        llm.set_auto_mask(options.auto_mask_over.map(|over_tokens| (over_tokens, options.auto_mask_after_turns)));
+        llm.set_auto_compact(options.auto_compact);
+        metadata.environment.get_or_insert_with(|| Environment::capture(&mut terminal, options.command_timeout));
+
+        if llm.num_msgs() == 0 {
+            llm.add_pinned_msg(
+                Message {
+                    role: Role::User, 
+                    content: "".into(), 
+                }
+            );
+        }
+
+        let output_cache = options.cached_commands.clone().map(OutputCache::new);
+        let file_transfer = FileTransfer::new(options.transfer_root.clone(), options.max_transfer_bytes);
+
+        Self {
+            llm,
+            terminal,
+            options,
+            metadata,
+            last_usage: None,
+            pause_requested: Arc::new(AtomicBool::new(false)),
+            output_cache,
+            file_transfer,
+            last_liveness_check: time::Instant::now(),
+            n_filtered: 0,
+            n_model_turns: 0,
+            turn_costs: HashMap::new(),
+            turn_usages: HashMap::new(),
+            step_result: StepResult::default(),
+            n_msgs_printed: 0,
+        }
+    }
+
+    /// Adds reference material, like documentation the task relies on, as a pinned message
+    pub fn add_reference(&mut self, name: &str, text: &str) {
+        self.llm.add_pinned_msg(
+            Message {
+                role: Role::User, 
+                content: format!("Reference material from {name}, pinned and cannot be masked:\n{text}").into(), 
+            }
+        );
+    }
+
+    /// Adds a note to the log that the model never sees
+    pub fn add_note(&mut self, text: &str) {
+        self.llm.add_local_msg(
+            Message {
+                role: Role::User, 
+                content: text.into(), 
+            }
+        );
+    }
+
+    pub fn set_command_hook(&mut self, hook: CommandHook) {
+        self.options.command_hook = Some(hook);
+    }
+
+    pub fn llm(&self) -> &LLM<Api> {
+        &self.llm
+    }
+
+    /// Moves files in and out of the session's working directory, e.g. to provide input files
+    pub fn file_transfer(&self) -> &FileTransfer {
+        &self.file_transfer
+    }
+
+    pub fn options(&self) -> &SessionOptions {
+        &self.options
+    }
+
+    pub fn metadata(&self) -> &SessionMetadata {
+        &self.metadata
+    }
+
+    /// Flag that pauses the session before its next step in `run` when set, e.g. from a signal handler
+    pub fn pause_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.pause_requested)
+    }
+
+    /// Token usage of the most recent model response
+    pub fn last_usage(&self) -> Option<&Usage> {
+        self.last_usage.as_ref()
+    }
+
+    /// Prints where the context's tokens went and how full it got at its peak
+    pub fn print_context_report(&self) {
+        let model = self.llm.model_info();
+        let peak = self.metadata.peak_input_tokens;
+        println!("Context window report for {}:", model.name());
+        println!("{}", self.llm.context_report());
+        println!("Peak context usage: {peak} of {} tokens ({:.0}%)", model.max_context_tokens(), peak as f64 * 100.0 / model.max_context_tokens() as f64);
+    }
+
+    /// Saves the log and the transcript if one is asked for, returning the log's path
+    pub fn save_log(&self) -> io::Result<PathBuf> {
+        let redactor = self.options.redactor.as_ref();
+        let log_path = save_session_log(&self.llm, &self.metadata, self.options.log_dir.as_deref(), redactor)?;
+        if let Some(format) = self.options.transcript_format {
+            save_transcript(&self.llm, format, &log_path.with_extension(format.extension()), redactor)?;
+        }
+        Ok(log_path)
+    }
+
+    /// Runs steps until the model exits or the terminal fails, pausing between steps when requested
+    pub fn run(&mut self) -> Result<(), SessionError> {
+        loop {
+            if self.pause_requested.load(Ordering::SeqCst) {
+                self.pause()?;
+            }
+
+            if self.step()?.outcome == StepOutcome::Finished {
+                return Ok(());
+            }
+
+            // Add a small delay between iterations
+            thread::sleep(time::Duration::from_millis(200));
+        }
+    }
+
+    /// Lets the user run their own commands in the session's shell until they resume,
+    /// without the model taking turns
+    fn pause(&mut self) -> Result<(), SessionError> {
+        if self.options.non_interactive {
+            return Err(SessionError::InteractionRequired("the session was paused"));
+        }
+        self.print_new_msgs();
+        println!("Session paused. Commands you enter run in the session's shell, enter resume to continue.");
+
+        let mut commands = Vec::new();
+        loop {
+            print!("paused$ ");
+            io::stdout().flush().expect("Failed to flush stdout");
+
+            let mut input = String::new();
+            if io::stdin().read_line(&mut input).expect("Failed to read line") == 0 {
+                return Err(SessionError::InteractionRequired("stdin was closed while the session was paused"));
+            }
+
+            let command = input.trim();
+            match command {
+                "resume" => break, 
+                "" => continue, 
+                "exit" => {
+                    println!("The session's shell cannot be exited, enter resume to continue.");
+                    continue;
+                }, 
+                _ => {}, 
+            }
+
+            // Commands run by hand may change what cached commands would print
+            self.clear_output_cache();
+            match self.terminal.run_command(command, self.options.command_timeout) {
+                Ok(CommandOutput::Complete(out)) => println!("{out}"), 
+                Ok(CommandOutput::Partial(out)) => println!("{out}\nCommand timed out and was interrupted."), 
+                Err(e) => eprintln!("Terminal error: {e}"), 
+            }
+            if !self.terminal.is_alive() {
+                match self.terminal.restart() {
+                    Ok(()) => println!("The shell exited, a new one was started."), 
+                    Err(e) => eprintln!("Restarting the shell failed: {e}"), 
+                }
+            }
+            commands.push(command.to_string());
+        }
+
+        self.pause_requested.store(false, Ordering::SeqCst);
+        println!("Session resumed.");
+
+        if !commands.is_empty() {
+            let text = format!("While the session was paused the user ran:\n{}", commands.join("\n"));
+            match self.options.report_paused_commands {
+                true => self.add_user_msg(text), 
+                false => self.add_note(&text), 
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets one response from the model, or the user when in user control, and acts on it
+    pub fn step(&mut self) -> Result<StepResult, SessionError> {
+        self.check_liveness();
+        self.print_new_msgs();
+
+        if self.metadata.user_control {
+            if self.options.non_interactive {
+                return Err(SessionError::InteractionRequired("the session is in user control"));
+            }
+            if self.options.echo {
+                print!("LLM: {}>>", self.llm.num_msgs());
+                io::stdout().flush().expect("Failed to flush stdout");
+                self.n_msgs_printed += 1;
+            }
+
+            let mut input = String::new();
+            // Stdin may have been read to its end already, e.g. for the task
+            if io::stdin().read_line(&mut input).expect("Failed to read line") == 0 {
+                return Err(SessionError::InteractionRequired("stdin was closed in user control"));
+            }
+            return self.step_with_input(&input);
+        }
+
+        let compacted_ids = self.llm.compact();
+        if !compacted_ids.is_empty() {
+            let ids = compacted_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            self.add_notice(format!("The context was nearly full, automatically masked messages {ids}. Recall them if you need them again"));
+        }
+
+        match self.prompt_model()? {
+            Some(llm_resp) => self.finish_step(llm_resp, true), 
+            None => Ok(StepResult::default()), 
+        }
+    }
+
+    /// Prompts the model for a turn, counted against `max_iterations`. `None` when the reply was empty
+    /// or filtered and the model was told so in a notice, it gets another try when prompted again
+    fn prompt_model(&mut self) -> Result<Option<Result<Vec<LLMResponse>, serde_json::Error>>, SessionError> {
+        self.check_turn_limit()?;
+
+        let selection = match self.options.non_interactive {
+            true => CandidateSelection::FirstValid, 
+            false => self.options.candidate_selection, 
+        };
+        let (llm_resp, usage) = match self.llm.prompt_with(self.options.prompt_timeout, |candidates| select_candidate(selection, candidates)) {
+            Ok(resp) => resp, 
+            Err(LLMApiError::EmptyResponse) => {
+                // Empty replies are paid for, so a backend that keeps sending them still runs out of turns
+                self.n_model_turns += 1;
+                self.add_notice("The model returned no content. Respond with exactly one json response.");
+                return Ok(None);
+            }, 
+            Err(LLMApiError::ContentFiltered) if self.n_filtered < self.options.filter_retries => {
+                self.n_filtered += 1;
+                self.add_notice("Your response was filtered. Rephrase it or try a different approach.");
+                return Ok(None);
+            }, 
+            Err(e) => {
+                eprintln!("Error communicating with LLM: {}", e);
+                return Err(e.into());
+            }
+        };
+        self.n_filtered = 0;
+        self.n_model_turns += 1;
+        self.add_cost(&usage);
+        self.last_usage = Some(usage);
+        // The backend may have fallen back to another model
+        self.metadata.model = Some(self.llm.model_info().name().to_string());
+        Ok(Some(llm_resp))
+    }
+
+    /// Stops the session once the model has had `max_iterations` turns, summarizing it first if the options ask for it
+    fn check_turn_limit(&mut self) -> Result<(), SessionError> {
+        if self.options.max_iterations.is_some_and(|max| self.n_model_turns >= max) {
+            self.add_notice("You have used up your turns, the session is stopped.");
+            if self.options.summarize_on_exit {
+                self.metadata.summary = self.summarize();
+            }
+            self.print_new_msgs();
+            return Err(SessionError::IterationLimit(self.n_model_turns));
+        }
+        Ok(())
+    }
+
+    /// Takes a step in user control with a response typed by the user instead of read from stdin.
+    /// Input starting with # is kept as a note instead
+    pub fn step_with_input(&mut self, input: &str) -> Result<StepResult, SessionError> {
+        if let Some(note) = input.trim().strip_prefix('#') {
+            self.add_note(note.trim());
+            return Ok(StepResult::default());
+        }
+
+        let llm_resp = parse_responses(input.trim());
+        self.llm.add_msg(
+            Message {
+                role: Role::Assistant, 
+                content: input.trim().into(), 
+            }
+        );
+        self.finish_step(llm_resp, false)
+    }
+
+    fn finish_step(&mut self, llm_resp: Result<Vec<LLMResponse>, serde_json::Error>, from_model: bool) -> Result<StepResult, SessionError> {
+        self.metadata.n_steps += 1;
+        self.step_result = StepResult {
+            usage: self.last_usage.clone().filter(|_| from_model), 
+            ..StepResult::default()
+        };
+
+        let llm_resp = llm_resp.or_else(|e| self.repair_last_response().ok_or(e));
+        let outcome = match llm_resp {
+            Ok(llm_resps) => self.handle_responses(llm_resps)?, 
+            Err(_) => {
+                self.add_notice("Invalid output. Omit id, must be json parsable.");
+                StepOutcome::Continue
+            }, 
+        };
+
+        if from_model {
+            let masked_ids = self.llm.end_turn();
+            if !masked_ids.is_empty() {
+                let ids = masked_ids
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                self.add_notice(format!("Automatically masked messages {ids}, they were large and not retained"));
+            }
+        }
+
+        if outcome == StepOutcome::Continue && from_model {
+            // The estimate includes what this step added, which the last usage cannot know about
+            let reported = self.last_usage.as_ref().map_or(0, |usage| usage.n_input_tokens + usage.n_output_tokens);
+            let used = reported.max(self.llm.estimated_prompt_tokens());
+            if used > self.llm.model_info().max_context_tokens() * 9 / 10 {
+                self.add_notice("Warning: over 90% of token context is used.");
+            }
+        }
+
+        self.step_result.outcome = outcome;
+        Ok(std::mem::take(&mut self.step_result))
+    }
+
+    /// Parses the last response again after repairing its json, noting the repair in the log
+    fn repair_last_response(&mut self) -> Option<Vec<LLMResponse>> {
+        let text = self.llm.last_msg_id()
+            .and_then(|id| self.llm.get_msg(id))
+            .and_then(|msg| String::try_from(&msg.get_message().content).ok())?;
+        let repaired = repair_json(&text)?;
+        let llm_resps = parse_responses(&repaired).ok()?;
+        self.add_note(&format!("Repaired invalid json in the previous response to: {repaired}"));
+        Some(llm_resps)
+    }
+
+    /// Acts on the responses in order, stopping early if one ends the session
+    fn handle_responses(&mut self, llm_resps: Vec<LLMResponse>) -> Result<StepOutcome, SessionError> {
+        let n_resps = llm_resps.len();
+        for llm_resp in llm_resps {
+            self.step_result.response = Some(llm_resp.clone());
+            if self.handle_response(llm_resp)? == StepOutcome::Finished {
+                return Ok(StepOutcome::Finished);
+            }
+        }
+
+        if n_resps > 1 {
+            self.add_notice(format!("Note: your message held {n_resps} json objects, they were run in order. Please output a single json per message."));
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    /// Prefixes each line of a command's output with its number if the options ask for it
+    fn number_lines(&self, output: &str) -> String {
+        if !self.options.number_output {
+            return output.to_string();
+        }
+        let width = output.lines().count().to_string().len();
+        output
+            .lines()
+            .enumerate()
+            .map(|(i, line)| format!("{:>width$}\t{line}", i + 1))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Adds the exit code of the command that just finished to its output if it failed,
+    /// so the model can tell a failure from a command that succeeded without output
+    fn with_exit_code(&self, output: String) -> String {
+        match self.terminal.last_exit_code() {
+            Some(exit_code) if exit_code != 0 && output.is_empty() => format!("(exit code {exit_code})"), 
+            Some(exit_code) if exit_code != 0 => format!("{output}\n(exit code {exit_code})"), 
+            _ => output, 
+        }
+    }
+
+    /// Follows a finished command's output with how long it ran if the options ask for it
+    fn with_duration(&self, output: String) -> String {
+        match self.terminal.last_duration() {
+            Some(duration) if self.options.show_duration && output.is_empty() => format!("(took {:.1}s)", duration.as_secs_f64()), 
+            Some(duration) if self.options.show_duration => format!("{output}\n(took {:.1}s)", duration.as_secs_f64()), 
+            _ => output, 
+        }
+    }
+
+    /// Follows a command's output with the shell's working directory if the options ask for it
+    fn with_cwd(&mut self, output: String) -> String {
+        if !self.options.show_cwd {
+            return output;
+        }
+        match self.terminal.cwd() {
+            Some(cwd) if output.is_empty() => format!("[cwd: {cwd}]"), 
+            Some(cwd) => format!("{output}\n[cwd: {cwd}]"), 
+            None => output, 
+        }
+    }
+
+    /// Runs a command through the hook and the output cache, adding its output to the context
+    fn run_command(&mut self, command: String) -> Result<StepOutcome, SessionError> {
+        match self.apply_command_hook(&command) {
+            Ok(command) => {
+                if is_thought_command(&command) {
+                    self.step_result.thought = Some(command.trim_start()[1..].trim().to_string());
+                }
+                self.step_result.command = Some(command.clone());
+
+                if self.options.dry_run {
+                    self.add_user_msg(format!("[dry-run] command not executed: {command}"));
+                    return Ok(StepOutcome::Continue);
+                }
+
+                if let Some(output) = self.output_cache.as_ref().and_then(|cache| cache.get(&command)) {
+                    self.step_result.output = Some(output.to_string());
+                    // Only commands that succeeded are cached
+                    self.step_result.exit_code = Some(0);
+                    let output = format!("{}\n(cached, the command was not run again. Any command that is not read-only clears the cache)", self.number_lines(output));
+                    self.add_user_msg(output);
+                    return Ok(StepOutcome::Continue);
+                }
+
+                // Execute in the hidden terminal
+                let start_time = time::Instant::now();
+                let output = self.terminal.run_command(&command, self.options.command_timeout);
+                self.step_result.duration = start_time.elapsed();
+                match output {
+                    Ok(output) => {
+                        self.step_result.exit_code = self.terminal.last_exit_code();
+                        let output = match output {
+                            CommandOutput::Complete(out) => {
+                                self.step_result.output = Some(out.clone());
+                                if let Some(cache) = &mut self.output_cache {
+                                    cache.record(&command, Some(&out), self.step_result.exit_code);
+                                }
+                                let output = self.with_exit_code(self.number_lines(&out));
+                                let output = self.with_duration(output);
+                                self.with_cwd(output)
+                            }, 
+                            CommandOutput::Partial(out) => {
+                                self.step_result.output = Some(out.clone());
+                                if let Some(cache) = &mut self.output_cache {
+                                    cache.record(&command, None, None);
+                                }
+                                let output = format!(
+                                    "Partial output, the command printed nothing for {:.1}s and was interrupted after running for {:.1}s. \
+                                    If it was still working, run it with stream to keep reading its output, or in the background with its output redirected to a file. \
+                                    If it was waiting for input, run it with stream and answer it with Input: {}", 
+                                    self.options.command_timeout.as_secs_f64(), 
+                                    self.step_result.duration.as_secs_f64(), 
+                                    self.number_lines(&out), 
+                                );
+                                self.with_cwd(output)
+                            }, 
+                        };
+                        self.add_user_msg(output);
+                    }
+                    Err(e) if matches!(e.downcast_ref(), Some(TerminalError::ExitRequested)) => return Ok(self.exit()), 
+                    Err(e) => return Ok(self.handle_terminal_error(e)), 
+                }
+            }, 
+            Err(msg) => self.llm.add_msg(msg), 
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    fn handle_response(&mut self, llm_resp: LLMResponse) -> Result<StepOutcome, SessionError> {
+        match llm_resp {
+            LLMResponse::Command(command) => return self.run_command(command), 
+            LLMResponse::Conditional { command, on_success, on_failure } => {
+                self.step_result.exit_code = None;
+                if self.run_command(command)? == StepOutcome::Finished {
+                    return Ok(StepOutcome::Finished);
+                }
+                let Some(exit_code) = self.step_result.exit_code else {
+                    self.add_notice("The command did not finish with an exit code, so neither branch was run.");
+                    return Ok(StepOutcome::Continue);
+                };
+                let next = match exit_code {
+                    0 => on_success, 
+                    _ => on_failure, 
+                };
+                if let Some(next) = next {
+                    self.add_notice(format!("Exit code {exit_code}, running: {next}"));
+                    return self.run_command(next);
+                }
+            }, 
+            LLMResponse::Stream(command) => match self.apply_command_hook(&command) {
+                Ok(command) if self.options.dry_run => {
+                    self.step_result.command = Some(command.clone());
+                    self.add_user_msg(format!("[dry-run] command not executed: {command}"));
+                }, 
+                Ok(command) => {
+                    self.step_result.command = Some(command.clone());
+                    let start_time = time::Instant::now();
+                    let outcome = self.run_stream(&command);
+                    self.step_result.duration = start_time.elapsed();
+                    return outcome;
+                }, 
+                Err(msg) => self.llm.add_msg(msg), 
+            }, 
+            LLMResponse::Continue | LLMResponse::Stop | LLMResponse::Input(_) => {
+                self.add_notice("No command is streaming.");
+            }, 
+            LLMResponse::LLMSee(img_path) => {
+                let model_info = self.llm.model_info();
+                if model_info.max_images() == 0 {
+                    self.add_notice(format!("{} cannot view images.", model_info.name()));
+                    return Ok(StepOutcome::Continue);
+                }
+
+                // The provider's own limit applies even when no limit was set
+                let max_images = self.options.max_images
+                    .unwrap_or(usize::MAX)
+                    .min(model_info.max_images());
+
+                if img_path.starts_with("http://") || img_path.starts_with("https://") {
+                    let image = Image::from_url(&img_path, self.options.max_image_download_bytes, self.options.image_download_timeout);
+                    self.add_image(image);
+                } else {
+                    // Relative paths are the shell's, which may have changed directory
+                    let path = match self.terminal.working_dir() {
+                        Some(dir) => Path::new(dir).join(&img_path), 
+                        None => PathBuf::from(&img_path), 
+                    };
+                    match path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pdf")) {
+                        true => self.add_pdf_pages(&img_path, Image::from_pdf_with(&self.options.pdf_renderer, &path, self.options.pdf_pages.clone()), max_images), 
+                        false => self.add_image(Image::from_file(&path)), 
+                    }
+                }
+
+                let masked_ids = self.llm.mask_excess_images(max_images);
+                if !masked_ids.is_empty() {
+                    let ids = masked_ids
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    self.add_notice(format!("Only {max_images} image(s) may be in context, automatically masked messages {ids}"));
+                }
+            }, 
+            LLMResponse::MaskContent(id) => {
+                if self.llm.get_msg(id).is_none() {
+                    self.add_notice(format!("There is no message {id}."));
+                } else {
+                    match self.llm.mask_message(id) {
+                        true => self.add_notice(format!("message {id} is masked")), 
+                        false => self.add_notice(format!("Cannot mask message {id}, it is a protected message.")), 
+                    }
+                }
+            }, 
+            LLMResponse::Recall(id) => {
+                match self.llm.get_msg(id).filter(|msg| !msg.is_local()) {
+                    Some(msg) if msg.is_masked() => {
+                        let mut content: Content = format!("Content of masked message {id}, which stays masked:\n").into();
+                        content.extend(msg.get_message().content.clone());
+                        self.add_user_msg(content);
+                    }, 
+                    Some(_) => self.add_notice(format!("Message {id} is not masked, it is already in context.")), 
+                    None => self.add_notice(format!("There is no message {id}.")), 
+                }
+            }, 
+            LLMResponse::Retain(id) => {
+                match self.llm.retain_message(id) {
+                    true => self.add_notice(format!("Message {id} will not be masked automatically.")), 
+                    false => self.add_notice(format!("Message {id} was not going to be masked automatically.")), 
+                }
+            }, 
+            LLMResponse::PutFile { path, .. } if self.options.dry_run => {
+                self.add_notice(format!("[dry-run] {path} was not written"));
+            }, 
+            LLMResponse::PutFile { path, base64 } => {
+                let cwd = self.terminal.cwd().map(PathBuf::from);
+                let note = match BASE64.decode(base64.trim()) {
+                    Ok(bytes) => match self.file_transfer.put(cwd.as_deref(), &path, &bytes) {
+                        Ok(written) => {
+                            // The file may be one a cached command reads
+                            self.clear_output_cache();
+                            format!("Wrote {} bytes to {}", bytes.len(), written.display())
+                        }, 
+                        Err(e) => format!("Could not write {path}: {e}"), 
+                    }, 
+                    Err(e) => format!("Could not write {path}, invalid base64: {e}"), 
+                };
+                self.add_notice(note);
+            }, 
+            LLMResponse::GetFile(path) => {
+                let cwd = self.terminal.cwd().map(PathBuf::from);
+                match self.file_transfer.get(cwd.as_deref(), &path) {
+                    Ok((bytes, read)) => {
+                        self.step_result.file = Some(BASE64.encode(&bytes));
+                        self.add_notice(format!("Retrieved {}, {} bytes", read.display(), bytes.len()));
+                    }, 
+                    Err(e) => self.add_notice(format!("Could not retrieve {path}: {e}")), 
+                }
+            }, 
+            LLMResponse::UserControl => {
+                if self.options.non_interactive {
+                    return Err(SessionError::InteractionRequired("the model requested user control"));
+                }
+                self.metadata.user_control = true;
+                self.add_protected_msg("Switched to external control.");
+            }, 
+            LLMResponse::AgentControl => {
+                self.metadata.user_control = false;
+                self.add_protected_msg("Switched back to agent control.");
+            }, 
+            LLMResponse::Exit => return Ok(self.exit()), 
+        }
+
+        Ok(StepOutcome::Continue)
+    }
+
+    /// Ends the session, summarizing it first if the options ask for it
+    fn exit(&mut self) -> StepOutcome {
+        if self.options.echo {
+            println!("Terminal session terminated.");
+        }
+        if self.options.summarize_on_exit {
+            self.metadata.summary = self.summarize();
+        }
+        StepOutcome::Finished
+    }
+
+    fn add_user_msg(&mut self, content: impl Into<Content>) {
+        self.llm.add_msg(
+            Message {
+                role: Role::User, 
+                content: content.into(), 
+            }
+        );
+    }
+
+    /// Scales an image down to the session's and the model's limits
+    fn fit_image(&self, image: Image) -> Result<(Image, Option<String>), ImageLoadError> {
+        let image = match self.options.max_image_dimension {
+            Some(max_dimension) => image.scaled(max_dimension)?, 
+            None => image, 
+        };
+        image.fit_to_limits(self.llm.model_info())
+    }
+
+    /// Adds an image the model asked to view, or why it could not be loaded
+    fn add_image(&mut self, image: Result<Image, ImageLoadError>) {
+        let (content, note): (Content, _) = match image.and_then(|img| self.fit_image(img)) {
+            Ok((img, note)) => (img.into(), note), 
+            Err(e) => (e.to_string().into(), None), 
+        };
+        self.add_user_msg(content);
+        if let Some(note) = note {
+            self.add_notice(note);
+        }
+    }
+
+    /// Adds the rendered pages of a PDF as a single message, at most `max_images` of them
+    fn add_pdf_pages(&mut self, path: &str, pages: Result<Vec<Image>, ImageLoadError>, max_images: usize) {
+        let pages = pages.and_then(|pages| {
+            pages.into_iter()
+                .take(max_images)
+                .map(|page| self.fit_image(page).map(|(page, _)| page))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        let pages = match pages {
+            Ok(pages) if !pages.is_empty() => pages, 
+            Ok(_) => {
+                self.add_user_msg(format!("{path} has no pages in the range {:?}", self.options.pdf_pages));
+                return;
+            }, 
+            Err(e) => {
+                self.add_user_msg(e.to_string());
+                return;
+            }, 
+        };
+
+        let first = *self.options.pdf_pages.start();
+        let last = first + pages.len() as u32 - 1;
+        let mut content: Content = format!("Pages {first} to {last} of {path}").into();
+        content.extend(Content::Multiple(pages.into_iter().map(ContentItem::Image).collect()));
+        let id = self.llm.num_msgs();
+        self.add_user_msg(content);
+        self.add_notice(format!("PDF pages take up a lot of context, mask message {id} with maskcontent once you have read them."));
+    }
+
+    /// Adds a message from the session itself, like a warning, as opposed to terminal output
+    fn add_notice(&mut self, content: impl Into<Content>) {
+        self.llm.add_msg(
+            Message {
+                role: Role::System, 
+                content: content.into(), 
+            }
+        );
+    }
+
+    /// Adds a notice the model cannot mask, for notices it should not lose track of
+    fn add_protected_msg(&mut self, content: impl Into<Content>) {
+        self.llm.add_pinned_msg(
+            Message {
+                role: Role::System, 
+                content: content.into(), 
+            }
+        );
+    }
+
+    fn print_new_msgs(&mut self) {
+        let n_msgs = self.llm.num_msgs();
+        if !self.options.echo {
+            self.n_msgs_printed = n_msgs;
+            return;
+        }
+
+        for id in self.n_msgs_printed..n_msgs {
+            let maskable_msg = self.llm.get_msg(id).unwrap();
+            let kind = maskable_msg.kind();
+            let msg = maskable_msg.to_message_with_id_no_mask();
+
+            let response = maskable_msg.response().filter(|_| !self.options.raw_responses);
+
+            match (msg.role, response) {
+                _ if maskable_msg.is_local() => {
+                    println!("Note: {}", msg.content);
+                }, 
+                (Role::Assistant, Some(response)) => {
+                    println!("LLM {}{}", response.describe(), self.cost_label(id));
+                }, 
+                (Role::Assistant, None) if kind == MessageKind::Thought => {
+                    println!("LLM thinks{}: {}", self.cost_label(id), msg.content);
+                }, 
+                (Role::Assistant, None) => {
+                    println!("LLM{}: {}", self.cost_label(id), msg.content);
+                }, 
+                (Role::User, _) => {
+                    println!("Terminal: {}", msg.content);
+                }, 
+                (Role::System, _) => {
+                    // The id prefix already marks it as a system message
+                    println!("{}", msg.content);
+                }, 
+            }
+            if let Some(usage) = self.turn_usages.get(&id) {
+                println!("    Usage: {usage}");
+            }
+        }
+        self.n_msgs_printed = n_msgs;
+    }
+
+    /// Passes a command through the session's command hook and sudo policy, returning the command
+    /// to run or the message to send back to the model instead
+    fn apply_command_hook(&mut self, command: &str) -> Result<String, Message> {
+        let decision = match self.options.command_hook.as_mut() {
+            Some(hook) => hook(command), 
+            None => CommandDecision::Run(command.to_string()), 
+        };
+        let decision = match decision {
+            CommandDecision::Run(command) => self.options.sudo_policy.apply(&command), 
+            decision => decision, 
+        };
+
+        let notice = |text: String| Message { role: Role::System, content: text.into() };
+        match decision {
+            CommandDecision::Run(command) => Ok(command), 
+            CommandDecision::Block(reason) => Err(notice(format!("Command blocked: {reason}"))), 
+            CommandDecision::Skip => Err(notice("Command was not run.".to_string())), 
+            CommandDecision::Reject(text) => Err(Message { role: Role::User, content: text.into() }), 
+        }
+    }
+
+    /// Restarts the shell if it exited, as it does on a failing command under `set -e`,
+    /// otherwise the error is fatal
+    fn handle_terminal_error(&mut self, error: impl fmt::Display) -> StepOutcome {
+        if self.terminal.is_alive() {
+            eprintln!("Terminal error: {error}");
+            return StepOutcome::Finished;
+        }
+
+        self.clear_output_cache();
+        match self.terminal.restart() {
+            Ok(()) => {
+                self.add_notice("The shell exited, likely because a command failed while errexit is set. \
+A new shell was started in the same working directory, variables and functions were reset.");
+                StepOutcome::Continue
+            }, 
+            Err(e) => {
+                eprintln!("Terminal error: {error}, restarting the shell failed: {e}");
+                StepOutcome::Finished
+            }, 
+        }
+    }
+
+    /// Restarts the shell if it no longer answers, checked at most every `liveness_interval`
+    fn check_liveness(&mut self) {
+        const LIVENESS_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+        let Some(interval) = self.options.liveness_interval else {
+            return;
+        };
+        if self.last_liveness_check.elapsed() < interval {
+            return;
+        }
+        self.last_liveness_check = time::Instant::now();
+        if self.terminal.is_responsive(LIVENESS_TIMEOUT) {
+            return;
+        }
+
+        self.clear_output_cache();
+        match self.terminal.restart() {
+            Ok(()) => self.add_notice("The shell stopped responding and was restarted in the same working directory, \
+variables and functions were reset."), 
+            Err(e) => eprintln!("The shell stopped responding, restarting it failed: {e}"), 
+        }
+    }
+
+    /// Adds the cost of a model response to the total, attributing it to the latest message
+    fn add_cost(&mut self, usage: &Usage) {
+        let cost = self.llm.model_info().cost(usage);
+        self.metadata.total_cost += cost;
+        self.metadata.peak_input_tokens = self.metadata.peak_input_tokens.max(usage.n_input_tokens);
+        self.metadata.total_input_tokens += usage.n_input_tokens;
+        self.metadata.total_output_tokens += usage.n_output_tokens;
+        if let Some(id) = self.llm.last_msg_id() {
+            self.turn_costs.insert(id, (cost, self.metadata.total_cost));
+            if self.options.verbose_usage {
+                self.turn_usages.insert(id, usage.clone());
+            }
+        }
+    }
+
+    fn cost_label(&self, id: usize) -> String {
+        match (self.options.show_cost, self.turn_costs.get(&id)) {
+            (true, Some((cost, total))) => format!(" [${cost:.3}, total ${total:.2}]"), 
+            _ => String::new(), 
+        }
+    }
+
+    fn clear_output_cache(&mut self) {
+        if let Some(cache) = &mut self.output_cache {
+            cache.clear();
+        }
+    }
+
+    /// Asks the model for a plaintext recap of the session, outside of the json command format
+    fn summarize(&mut self) -> Option<String> {
+        self.add_notice("The session is over, no more commands will be run. \
+Reply in plain text, not json, with a concise summary of what you accomplished and any remaining issues.");
+
+        match self.llm.prompt_text(self.options.prompt_timeout) {
+            Ok((summary, usage)) => {
+                self.add_cost(&usage);
+                if self.options.echo {
+                    println!("Summary: {summary}");
+                }
+                Some(summary)
+            }, 
+            Err(LLMApiError::RequestTooLarge) => {
+                eprintln!("Could not summarize session: context window is full");
+                None
+            }, 
+            Err(e) => {
+                eprintln!("Could not summarize session: {e}");
+                None
+            }, 
+        }
+    }
+
+    /// Runs a command in stream mode, showing the model its output chunk by chunk until the command
+    /// finishes, the model stops it, or the stream deadline passes
+    fn run_stream(&mut self, command: &str) -> Result<StepOutcome, SessionError> {
+        if command.trim().lines().count() > 1 {
+            self.add_notice("Only single line commands can be streamed.");
+            return Ok(StepOutcome::Continue);
+        }
+
+        // Streamed commands are usually long running and rarely read-only
+        self.clear_output_cache();
+        if let Err(e) = self.terminal.start_command(command) {
+            return Ok(self.handle_terminal_error(e));
+        }
+
+        let start_time = time::Instant::now();
+        loop {
+            let output = match self.terminal.read_chunk(self.options.command_timeout, self.options.stream_chunk_size) {
+                Ok(StreamChunk::Done(out)) => {
+                    self.step_result.output.get_or_insert_with(String::new).push_str(&out);
+                    self.step_result.exit_code = self.terminal.last_exit_code();
+                    let output = self.with_exit_code(out);
+                    let output = self.with_duration(output);
+                    let output = self.with_cwd(output);
+                    self.add_user_msg(output);
+                    return Ok(StepOutcome::Continue);
+                }, 
+                Ok(StreamChunk::Running(out)) => out, 
+                Err(e) => return Ok(self.handle_terminal_error(e)), 
+            };
+            self.step_result.output.get_or_insert_with(String::new).push_str(&output);
+
+            if start_time.elapsed() >= self.options.stream_deadline {
+                let interruption = match self.terminal.interrupt() {
+                    Ok(interruption) => interruption, 
+                    Err(e) => return Ok(self.handle_terminal_error(e)), 
+                };
+                self.add_user_msg(format!("{output}\nStream deadline reached, command interrupted."));
+                if let Some(note) = interruption.note() {
+                    self.add_notice(note);
+                }
+                return Ok(StepOutcome::Continue);
+            }
+
+            self.add_user_msg(format!("{output}\nCommand still running, respond Continue to read more, Input to type a line into it, or Stop to interrupt it."));
+
+            // Every prompt while streaming is a turn of its own
+            let llm_resp = loop {
+                if let Some(llm_resp) = self.prompt_model()? {
+                    break llm_resp;
+                }
+            };
+            let note = match llm_resp.as_deref() {
+                Ok([LLMResponse::Continue]) => continue, 
+                Ok([LLMResponse::Input(line)]) => match self.terminal.send_input(line) {
+                    Ok(()) => continue, 
+                    Err(e) => return Ok(self.handle_terminal_error(e)), 
+                }, 
+                Ok([LLMResponse::Stop]) => "Command interrupted.", 
+                _ => "Command interrupted, only Continue, Input or Stop are accepted while a command is streaming.", 
+            };
+
+            match self.terminal.interrupt() {
+                Ok(interruption) => match interruption.note() {
+                    Some(how) => self.add_notice(format!("{note} {how}")), 
+                    None => self.add_notice(note), 
+                }, 
+                Err(e) => return Ok(self.handle_terminal_error(e)), 
+            }
+            return Ok(StepOutcome::Continue);
+        }
+    }
+}
+
+fn select_candidate(selection: CandidateSelection, candidates: &[String]) -> usize {
+    if candidates.len() <= 1 {
+        return 0;
+    }
+
+    match selection {
+        CandidateSelection::FirstValid => candidates
+            .iter()
+            .position(|candidate| parse_responses(trim_id_prefix(candidate)).is_ok())
+            .unwrap_or(0), 
+        CandidateSelection::Interactive => {
+            for (i, candidate) in candidates.iter().enumerate() {
+                println!("Candidate {i}: {candidate}");
+            }
+            loop {
+                print!("Pick a candidate [0-{}]: ", candidates.len() - 1);
+                io::stdout().flush().expect("Failed to flush stdout");
+
+                let mut input = String::new();
+                io::stdin().read_line(&mut input).expect("Failed to read line");
+                match input.trim().parse::<usize>() {
+                    Ok(i) if i < candidates.len() => return i, 
+                    _ => println!("Invalid choice."), 
+                }
+            }
+        }, 
+    }
+}
+
+/// A session with any of the supported backends
+pub enum SessionKind {
+    Anthropic(Session<AnthropicApi>),
+    OpenAI(Session<OAIApi>),
+    Gemini(Session<GeminiApi>),
+    Ollama(Session<OllamaApi>),
+    /// A session whose backend can change when requests keep failing
+    Fallback(Session<FallbackApi>),
+}
+
+impl SessionKind {
+    pub fn apply<FA, FO, FG, FL, FF, R>(
+        &mut self,
+        f_anthropic: FA,
+        f_openai: FO,
+        f_gemini: FG,
+        f_ollama: FL,
+        f_fallback: FF
+    ) -> R
+    where
+        FA: FnOnce(&mut Session<AnthropicApi>) -> R,
+        FO: FnOnce(&mut Session<OAIApi>) -> R,
+        FG: FnOnce(&mut Session<GeminiApi>) -> R,
+        FL: FnOnce(&mut Session<OllamaApi>) -> R,
+        FF: FnOnce(&mut Session<FallbackApi>) -> R,
+    {
+        match self {
+            SessionKind::Anthropic(session) => f_anthropic(session), 
+            SessionKind::OpenAI(session) => f_openai(session), 
+            SessionKind::Gemini(session) => f_gemini(session), 
+            SessionKind::Ollama(session) => f_ollama(session), 
+            SessionKind::Fallback(session) => f_fallback(session), 
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), SessionError> {
+        self.apply(
+            |session| session.run(),
+            |session| session.run(),
+            |session| session.run(),
+            |session| session.run(),
+            |session| session.run(),
+        )
+    }
+
+    pub fn pause_handle(&mut self) -> Arc<AtomicBool> {
+        self.apply(
+            |session| session.pause_handle(),
+            |session| session.pause_handle(),
+            |session| session.pause_handle(),
+            |session| session.pause_handle(),
+            |session| session.pause_handle(),
+        )
+    }
+
+    pub fn run_tui(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.apply(
+            crate::tui::run,
+            crate::tui::run,
+            crate::tui::run,
+            crate::tui::run,
+            crate::tui::run,
+        )
+    }
+
+    pub fn add_reference(&mut self, name: &str, text: &str) {
+        self.apply(
+            |session| session.add_reference(name, text),
+            |session| session.add_reference(name, text),
+            |session| session.add_reference(name, text),
+            |session| session.add_reference(name, text),
+            |session| session.add_reference(name, text),
+        )
+    }
+
+    pub fn print_context_report(&mut self) {
+        self.apply(
+            |session| session.print_context_report(),
+            |session| session.print_context_report(),
+            |session| session.print_context_report(),
+            |session| session.print_context_report(),
+            |session| session.print_context_report(),
+        )
+    }
+
+    pub fn metadata(&mut self) -> SessionMetadata {
+        self.apply(
+            |session| session.metadata().clone(),
+            |session| session.metadata().clone(),
+            |session| session.metadata().clone(),
+            |session| session.metadata().clone(),
+            |session| session.metadata().clone(),
+        )
+    }
+
+    pub fn save_log(&mut self) -> io::Result<PathBuf> {
+        self.apply(
+            |session| session.save_log(),
+            |session| session.save_log(),
+            |session| session.save_log(),
+            |session| session.save_log(),
+            |session| session.save_log(),
+        )
+    }
+}
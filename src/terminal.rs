@@ -1,5 +1,40 @@
-use rexpect::{spawn, session::PtySession, error::Error};
-use std::time::{Duration, Instant};
+#[cfg(unix)]
+use rexpect::session::{spawn_command, PtySession};
+#[cfg(unix)]
+use rexpect::process::wait::WaitStatus;
+#[cfg(unix)]
+use rexpect::process::signal::Signal;
+#[cfg(unix)]
+use nix::unistd::tcgetpgrp;
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+#[cfg(unix)]
+use std::time::Instant;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(unix)]
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::process::Command;
+#[cfg(unix)]
+use uuid::Uuid;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use windows::Terminal;
+
+/// What the shell's commands fail with
+#[cfg(unix)]
+pub use rexpect::error::Error;
+#[cfg(windows)]
+pub use std::io::Error;
+
+/// The shell used when none is configured
+pub const DEFAULT_SHELL: &str = if cfg!(windows) { "powershell.exe" } else { "/bin/bash" };
 
 #[derive(Debug)]
 pub enum CommandOutput {
@@ -7,87 +42,830 @@ pub enum CommandOutput {
     Partial(String), 
 }
 
+/// A piece of output from a command started with `Terminal::start_command`
+#[derive(Debug)]
+pub enum StreamChunk {
+    /// The command is still running and may print more
+    Running(String), 
+    /// The command finished, this is the rest of its output
+    Done(String), 
+}
+
+/// How `Terminal::interrupt` stopped the running command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interruption {
+    /// Ctrl+C was enough
+    CtrlC, 
+    /// It ignored Ctrl+C and was sent SIGTERM
+    Terminated, 
+    /// It ignored Ctrl+C and SIGTERM and was sent SIGKILL. On Windows, where a piped shell cannot
+    /// be sent Ctrl+C, the command's processes are always killed right away
+    Killed, 
+    /// The shell did not come back even after SIGKILL, e.g. the command is stuck in uninterruptible sleep,
+    /// so a new one was started as with `Terminal::restart`
+    Restarted, 
+}
+
+impl Interruption {
+    /// What to tell the model about how the command was stopped, nothing when Ctrl+C was enough
+    pub fn note(&self) -> Option<&'static str> {
+        match self {
+            Self::CtrlC => None, 
+            Self::Terminated => Some("The command ignored Ctrl+C and was stopped with SIGTERM."), 
+            Self::Killed if cfg!(windows) => Some("The command was force-killed."), 
+            Self::Killed => Some("The command ignored Ctrl+C and SIGTERM and was force-killed with SIGKILL."), 
+            Self::Restarted if cfg!(windows) => Some("The command could not be stopped. \
+A new shell was started in the same working directory, variables and functions were reset."), 
+            Self::Restarted => Some("The command could not be stopped, not even with SIGKILL. \
+A new shell was started in the same working directory, variables and functions were reset."), 
+        }
+    }
+}
+
+enum ReadStop {
+    /// The command's end marker was read
+    Prompt, 
+    Idle, 
+    Full, 
+}
+
+/// How long a shell gets to exit cleanly when the terminal is dropped before it is killed
+const EXIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Start of the line printed after every command, followed by a per-command id, an underscore and the exit code
+const MARKER_PREFIX: &str = "CMD_END_";
+/// Shell function printing the marker given as its argument and the exit code on their own line,
+/// it returns the exit code again so `$?` is not changed
+#[cfg(unix)]
+const MARKER_COMMAND: &str = "__agentic_marker";
+
+#[derive(Debug)]
+pub enum TerminalError {
+    /// The shell could not be spawned or never showed its prompt
+    Startup(Error), 
+    /// A command line was `exit`, which would end the shell instead of running a command.
+    /// Callers end the session instead
+    ExitRequested, 
+    /// The shell is not one whose prompt can be set up, see `ShellKind`
+    UnsupportedShell(String), 
+    /// A name in `TerminalConfig::env` that the shell cannot hold a variable under
+    InvalidEnvName(String), 
+}
+
+impl fmt::Display for TerminalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Startup(e) => write!(f, "Failed to start shell: {}", e),
+            Self::ExitRequested => write!(f, "The command asked the shell to exit"),
+            Self::UnsupportedShell(shell) => write!(f, "Unsupported shell {}, only bash, zsh and sh are supported, or PowerShell on Windows", shell),
+            Self::InvalidEnvName(name) => write!(f, "Invalid environment variable name {:?}, it must be letters, digits and underscores not starting with a digit", name),
+        }
+    }
+}
+
+impl std::error::Error for TerminalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Startup(e) => Some(e),
+            Self::ExitRequested | Self::UnsupportedShell(_) | Self::InvalidEnvName(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TerminalConfig {
+    /// Path of the shell to run, in the container when there is one. It must be one of the kinds in `ShellKind`
+    pub shell: String, 
+    /// How long to wait for the shell to show a prompt, also bounds any later wait for the prompt
+    pub startup_timeout: Duration, 
+    /// Options passed to `set` once the shell is up, e.g. `-euo pipefail`.
+    /// With `-e` the shell exits on the first failing command, see `Terminal::restart`
+    pub bash_opts: Option<String>, 
+    /// Locale exported as `LANG` and `LC_ALL` so command output is decoded consistently
+    pub locale: Locale, 
+    /// Run the shell in a container instead of on the host
+    pub container: Option<Container>, 
+    /// Keep `PROMPT_COMMAND`, which is otherwise unset since whatever it prints ends up in command output
+    pub keep_prompt_command: bool, 
+    /// How long each step of stopping a command, Ctrl+C, then SIGTERM, then SIGKILL, waits for the shell
+    /// before moving on to the next
+    pub interrupt_timeout: Duration, 
+    /// Output of a command beyond this many bytes has its middle dropped, keeping the start and the end.
+    /// Streamed output is read in chunks instead. Off when `None`
+    pub max_output_bytes: Option<usize>, 
+    /// Lines of output longer than this many characters are cut, e.g. a minified file. Off when `None`
+    pub max_line_chars: Option<usize>, 
+    /// Keep ANSI escape sequences, like colors and cursor movement, in command output instead of stripping them
+    pub keep_ansi: bool, 
+    /// Variables exported once the shell is up, e.g. `GIT_AUTHOR_NAME`. They are set after the locale,
+    /// so `LANG` here overrides it
+    pub env: HashMap<String, String>, 
+}
+
+impl Default for TerminalConfig {
+    fn default() -> Self {
+        Self {
+            shell: DEFAULT_SHELL.to_string(), 
+            startup_timeout: Duration::from_secs(30), 
+            bash_opts: None, 
+            locale: Locale::default(), 
+            container: None, 
+            keep_prompt_command: false, 
+            interrupt_timeout: Duration::from_secs(5), 
+            max_output_bytes: Some(16 * 1024), 
+            max_line_chars: Some(10_000), 
+            keep_ansi: false, 
+            env: HashMap::new(), 
+        }
+    }
+}
+
+impl TerminalConfig {
+    /// Fails on the first name in `env` that is not a valid variable name
+    fn check_env(&self) -> Result<(), TerminalError> {
+        let is_valid = |name: &str| {
+            name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        match self.env.keys().find(|name| !is_valid(name)) {
+            Some(name) => Err(TerminalError::InvalidEnvName(name.clone())), 
+            None => Ok(()), 
+        }
+    }
+
+    fn strip_escapes<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.keep_ansi {
+            true => Cow::Borrowed(text), 
+            false => strip_ansi(text), 
+        }
+    }
+
+    fn cut_line<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let Some(max_chars) = self.max_line_chars else {
+            return Cow::Borrowed(line);
+        };
+        match line.char_indices().nth(max_chars) {
+            Some((end, _)) => {
+                let n_cut = line[end..].chars().count();
+                Cow::Owned(format!("{}... [{n_cut} more characters on this line were cut]", &line[..end]))
+            }, 
+            None => Cow::Borrowed(line), 
+        }
+    }
+}
+
+/// A throwaway container the shell runs in, so commands only see the image's filesystem
+/// and whatever directory is mounted into it
+#[derive(Debug, Clone)]
+pub struct Container {
+    /// `docker` or anything with the same command line, like `podman`
+    pub runtime: String, 
+    pub image: String, 
+    /// Host directory mounted read-write at the same path and used as the working directory.
+    /// Files the container creates in it belong to the container's user, often root
+    pub mount: Option<PathBuf>, 
+    /// How long to wait for the container's shell, which may include pulling the image
+    pub startup_timeout: Duration, 
+}
+
+impl Container {
+    #[cfg(unix)]
+    fn command(&self, name: &str, shell: &str) -> Command {
+        let mut command = Command::new(&self.runtime);
+        command.args(["run", "--interactive", "--tty", "--rm", "--name", name]);
+        if let Some(mount) = &self.mount {
+            let mount = mount.display();
+            command.arg("--volume").arg(format!("{mount}:{mount}"));
+            command.arg("--workdir").arg(mount.to_string());
+        }
+        command.args([self.image.as_str(), shell]);
+        command
+    }
+}
+
+/// The shells whose prompt and line editor can be set up, each needs a little of its own setup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellKind {
+    Bash, 
+    Zsh, 
+    /// A POSIX shell like dash or ash
+    Sh, 
+    /// Windows PowerShell or PowerShell 7, only on Windows
+    PowerShell, 
+}
+
+impl ShellKind {
+    /// The kind of the shell at `path`, taken from its file name. `None` if it is not supported
+    pub fn of(path: &str) -> Option<Self> {
+        match Path::new(path).file_name()?.to_str()? {
+            "bash" => Some(Self::Bash), 
+            "zsh" => Some(Self::Zsh), 
+            "sh" | "dash" | "ash" => Some(Self::Sh), 
+            "powershell" | "powershell.exe" | "pwsh" | "pwsh.exe" => Some(Self::PowerShell), 
+            _ => None, 
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bash => "bash", 
+            Self::Zsh => "zsh", 
+            Self::Sh => "sh", 
+            Self::PowerShell => "powershell", 
+        }
+    }
+}
+
+/// Which locale the shell runs commands in
+#[derive(Debug, Clone, Default)]
+pub enum Locale {
+    /// `C.UTF-8`, or `en_US.UTF-8` where it is not installed. If neither is, the locale is left alone
+    #[default]
+    Utf8, 
+    /// Whatever locale the shell inherits
+    Inherit, 
+    /// A specific locale, e.g. `de_DE.UTF-8`
+    Named(String), 
+}
+
+impl Locale {
+    /// The locale to export, if any
+    #[cfg(unix)]
+    fn resolve(&self, container: Option<&Container>) -> Option<String> {
+        match self {
+            // The host's locales say nothing about the image's, C.UTF-8 is the one most images have
+            Locale::Utf8 if container.is_some() => Some("C.UTF-8".to_string()), 
+            Locale::Utf8 => {
+                let output = Command::new("locale").arg("-a").output().ok()?;
+                // `locale -a` lists normalized names, e.g. C.utf8 for C.UTF-8
+                let normalize = |name: &str| name.to_lowercase().replace('-', "");
+                let installed: Vec<String> = String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .map(normalize)
+                    .collect();
+                ["C.UTF-8", "en_US.UTF-8"]
+                    .into_iter()
+                    .find(|locale| installed.contains(&normalize(locale)))
+                    .map(str::to_string)
+            }, 
+            Locale::Inherit => None, 
+            Locale::Named(name) => Some(name.clone()), 
+        }
+    }
+}
+
+#[cfg(unix)]
 pub struct Terminal {
     session: PtySession,
+    /// End marker of the running command, up to the exit code
+    marker: String,
+    /// The last characters read, enough to hold the marker and an exit code
+    prompt_buffer: String,
+    held_back: String,
+    last_exit_code: Option<i32>,
+    config: TerminalConfig,
+    /// Name of the container the shell runs in, if any
+    container_name: Option<String>,
+    /// The shell's working directory as of the last command that could change it, kept across restarts
+    working_dir: Option<String>,
+    /// Whether the running command may change the working directory
+    changes_dir: bool,
+    /// Whether a command was started and has neither finished nor been interrupted
+    running: bool,
+    /// When the running command was sent
+    started: Instant,
+    /// How long the last command ran until it finished or was interrupted
+    last_duration: Option<Duration>,
 }
 
+#[cfg(unix)]
 impl Terminal {
-    pub fn new() -> Result<Self, Error> {
-        let mut session = spawn("/bin/bash", None/*Some(30000)*/)?; // 30 second timeout?
+    pub fn new() -> Result<Self, TerminalError> {
+        Self::with_config(TerminalConfig::default())
+    }
+
+    /// A terminal running `shell` instead of bash, e.g. `/bin/zsh`
+    pub fn with_shell(shell: &str) -> Result<Self, TerminalError> {
+        Self::with_config(
+            TerminalConfig {
+                shell: shell.to_string(), 
+                ..TerminalConfig::default()
+            }
+        )
+    }
+
+    /// A terminal exporting `vars` into its shell, on top of the environment it inherits
+    pub fn with_env(vars: HashMap<String, String>) -> Result<Self, TerminalError> {
+        Self::with_config(
+            TerminalConfig {
+                env: vars, 
+                ..TerminalConfig::default()
+            }
+        )
+    }
+
+    pub fn with_config(config: TerminalConfig) -> Result<Self, TerminalError> {
+        let shell_kind = ShellKind::of(&config.shell)
+            .filter(|kind| *kind != ShellKind::PowerShell)
+            .ok_or_else(|| TerminalError::UnsupportedShell(config.shell.clone()))?;
+        config.check_env()?;
+        let timeout_ms = config.startup_timeout.as_millis() as u64;
+        let container_name = config.container
+            .as_ref()
+            .map(|_| format!("agentic_terminal_{}", Uuid::now_v7().simple()));
+        let command = match (&config.container, &container_name) {
+            (Some(container), Some(name)) => container.command(name, &config.shell), 
+            _ => Command::new(&config.shell), 
+        };
+        let mut session = spawn_command(command, Some(timeout_ms))
+            .map_err(TerminalError::Startup)?;
         
-        // Wait for initial prompt and clear it
-        session.exp_regex(r"[\$\#] $")?;
+        // Wait for initial prompt and clear it, a container may take much longer to come up than the shell itself
+        let startup_start = Instant::now();
+        let startup_timeout = config.container
+            .as_ref()
+            .map_or(config.startup_timeout, |container| container.startup_timeout);
+        loop {
+            // The default prompts end in $ or #, and % for zsh
+            match session.exp_regex(r"[\$\#%] $") {
+                Ok(_) => break, 
+                Err(Error::Timeout { .. }) if startup_start.elapsed() < startup_timeout => continue, 
+                Err(e) => return Err(TerminalError::Startup(e)), 
+            }
+        }
         
+        let mut terminal = Terminal {
+            session, 
+            marker: String::new(), 
+            prompt_buffer: String::new(), 
+            held_back: String::new(), 
+            last_exit_code: None, 
+            config, 
+            container_name, 
+            working_dir: None, 
+            changes_dir: false, 
+            running: false, 
+            started: Instant::now(), 
+            last_duration: None, 
+        };
+
         // Set up clean environment
-        session.send_line("export PS1='CMD_END> '")?;
-        session.exp_string("CMD_END> ")?;
+        // Commands are followed by their own end marker, so the prompts are left empty
+        terminal.run_setup(&format!("{MARKER_COMMAND}() {{ local status=$?; printf '\\n%s%s\\n' \"$1\" \"$status\"; return $status; }}"))?;
+        terminal.run_setup("export PS1='' PS2=''")?;
+        match shell_kind {
+            // Bracketed paste wraps every command's output in escape codes
+            ShellKind::Bash => terminal.run_setup("bind 'set enable-bracketed-paste off'")?, 
+            // Zsh also pads the line before each prompt with spaces and may print a prompt on the right
+            ShellKind::Zsh => terminal.run_setup("unset zle_bracketed_paste RPROMPT RPS1; unsetopt PROMPT_SP PROMPT_CR")?, 
+            ShellKind::Sh | ShellKind::PowerShell => {}, 
+        }
+
+        if !terminal.config.keep_prompt_command {
+            terminal.run_setup("unset PROMPT_COMMAND")?;
+        }
+
+        if let Some(locale) = terminal.config.locale.resolve(terminal.config.container.as_ref()) {
+            terminal.run_setup(&format!("export LANG='{locale}' LC_ALL='{locale}'"))?;
+        }
+
+        // Single quoted, so nothing in a value is expanded
+        for (name, value) in terminal.config.env.clone() {
+            terminal.run_setup(&format!("export {name}='{}'", value.replace('\'', "'\\''")))?;
+        }
+
+        if let Some(bash_opts) = terminal.config.bash_opts.clone() {
+            terminal.run_setup(&format!("set {bash_opts}"))?;
+        }
         
-        Ok(Terminal { session })
+        Ok(terminal)
+    }
+
+    /// Runs a setup command and waits for its end marker
+    fn run_setup(&mut self, line: &str) -> Result<(), TerminalError> {
+        self.start_command(line)
+            .and_then(|_| self.expect_marker())
+            .map_err(TerminalError::Startup)
+    }
+
+    /// Waits for the end marker of the running command and discards everything before it
+    fn expect_marker(&mut self) -> Result<(), Error> {
+        self.session.exp_regex(&format!("{}[0-9]+\\r?\\n", self.marker))?;
+        self.running = false;
+        self.prompt_buffer.clear();
+        self.held_back.clear();
+        Ok(())
+    }
+
+    /// Stops a running command with SIGTERM and then SIGKILL, then asks the shell to exit and kills it
+    /// if it has not within `EXIT_TIMEOUT`, reaping it either way so no zombie is left behind
+    fn shut_down(&mut self) -> Result<(), Error> {
+        if !self.is_alive() {
+            return Ok(());
+        }
+        // Killing only the shell would leave the command running, e.g. one that ignores the hangup
+        for signal in ["TERM", "KILL"] {
+            if self.foreground_group().is_none() {
+                break;
+            }
+            self.signal_foreground(signal);
+            let start = Instant::now();
+            while start.elapsed() < EXIT_TIMEOUT && self.foreground_group().is_some() {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+
+        // A shell stuck in a command never reads the exit, it is killed below
+        let _ = self.session.send_line("exit");
+
+        let start = Instant::now();
+        while start.elapsed() < EXIT_TIMEOUT {
+            if !self.is_alive() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        self.session.process.signal(Signal::SIGKILL)?;
+        self.session.process.wait()?;
+        Ok(())
+    }
+
+    /// Whether the shell is still running, it exits on a failing command when `set -e` is on
+    pub fn is_alive(&self) -> bool {
+        matches!(self.session.process.status(), Some(WaitStatus::StillAlive))
+    }
+
+    /// Replaces the shell with a fresh one using the same config, started in the old shell's working directory.
+    /// Anything else the old shell held, like variables and functions, is lost
+    pub fn restart(&mut self) -> Result<(), TerminalError> {
+        let working_dir = self.working_dir.take();
+        *self = Self::with_config(self.config.clone())?;
+        if let Some(dir) = working_dir {
+            self.run_setup(&format!("cd -- '{}'", dir.replace('\'', "'\\''")))?;
+            self.working_dir = Some(dir);
+        }
+        Ok(())
+    }
+
+    /// Path of the shell that runs the commands
+    pub fn shell(&self) -> &str {
+        &self.config.shell
+    }
+
+    /// The shell's working directory, `None` until a command changed it or it was asked for with `cwd`
+    pub fn working_dir(&self) -> Option<&str> {
+        self.working_dir.as_deref()
+    }
+
+    /// The shell's working directory. The shell is only asked the first time, after that it is kept
+    /// up to date by the commands that may change it
+    pub fn cwd(&mut self) -> Option<&str> {
+        if self.working_dir.is_none() {
+            self.changes_dir = true;
+            self.update_working_dir();
+        }
+        self.working_dir.as_deref()
+    }
+
+    /// Whether a line runs a command that changes the shell's working directory
+    fn changes_dir(line: &str) -> bool {
+        line.split(|c: char| c.is_whitespace() || ";&|()`".contains(c))
+            .any(|word| matches!(word, "cd" | "pushd" | "popd"))
+    }
+
+    /// Asks the shell for its working directory after a command that may have changed it.
+    /// The exit code and duration of that command are kept, the exit code also in the shell's `$?`
+    fn update_working_dir(&mut self) {
+        if !std::mem::take(&mut self.changes_dir) {
+            return;
+        }
+        let (last_exit_code, last_duration) = (self.last_exit_code, self.last_duration);
+        let query = "__status=$?; printf '%s\\n' \"$PWD\"; eval \"unset __status; (exit $__status)\"";
+        if self.start_command(query).is_ok() {
+            if let Ok((output, ReadStop::Prompt)) = self.read_output(self.config.startup_timeout, None) {
+                let output = output.rsplit_once(self.marker.as_str()).map_or(output.as_str(), |(output, _)| output);
+                // Any echo of the query comes before the directory
+                self.working_dir = self.clean_output(output).lines().last().map(|dir| dir.trim().to_string());
+            }
+        }
+        self.last_exit_code = last_exit_code;
+        self.last_duration = last_duration;
+    }
+
+    /// Whether the shell answers a no-op within `timeout`, a wedged pty can keep running without ever
+    /// printing another prompt. The previous exit code and duration are kept, the exit code also in the shell's `$?`
+    pub fn is_responsive(&mut self, timeout: Duration) -> bool {
+        let (last_exit_code, last_duration) = (self.last_exit_code, self.last_duration);
+        let probe = "__status=$?; echo __alive__; eval \"unset __status; (exit $__status)\"";
+        if self.start_command(probe).is_err() {
+            return false;
+        }
+
+        let responsive = match self.read_output(timeout, None) {
+            Ok((output, ReadStop::Prompt)) => output.lines().any(|line| line.trim() == "__alive__"), 
+            _ => false, 
+        };
+        self.last_exit_code = last_exit_code;
+        self.last_duration = last_duration;
+        responsive
+    }
+
+    /// Exit code of the last command that finished, `None` if it was interrupted
+    pub fn last_exit_code(&self) -> Option<i32> {
+        self.last_exit_code
+    }
+
+    /// How long the last command ran, from being sent until it finished or was interrupted
+    pub fn last_duration(&self) -> Option<Duration> {
+        self.last_duration
+    }
+
+    /// Trims the output, dropping the line sending the end marker if a command read it as input,
+    /// stripping escape sequences and cutting lines that are too long
+    fn clean_output(&self, raw_output: &str) -> String {
+        let marker_command = format!("{MARKER_COMMAND} {MARKER_PREFIX}");
+        self.config.strip_escapes(raw_output)
+            .trim()
+            .lines()
+            .filter(|line| !line.trim_start().starts_with(&marker_command))
+            .map(|line| self.config.cut_line(line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Cleans the output of a finished command, taking the exit code off the end marker
+    fn finish_output(&mut self, raw_output: &str) -> String {
+        let (output, status) = raw_output
+            .rsplit_once(self.marker.as_str())
+            .unwrap_or((raw_output, ""));
+        self.last_exit_code = status.trim().parse().ok();
+        self.last_duration = Some(self.started.elapsed());
+        let output = self.clean_output(output);
+        self.update_working_dir();
+        output
     }
 
-    fn clean_output(&self, raw_output: &str, suffix: &str) -> String {
-        let trimmed = raw_output.trim();
-        trimmed
-            .strip_suffix(suffix)
-            .unwrap_or(trimmed)
-            .to_string()
+    /// Runs a single line to completion, or until it prints nothing for `timeout`.
+    /// A line running `exit` is not sent, it fails with `TerminalError::ExitRequested`
+    pub fn run_line(&mut self, line: &str, timeout: Duration) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        let line = line.trim();
+        if line.split([' ', ';', '&', '|']).next() == Some("exit") {
+            return Err(TerminalError::ExitRequested.into());
+        }
+
+        self.start_command(line)?;
+
+        let (output, stop) = self.read_output(timeout, None)?;
+        match stop {
+            ReadStop::Prompt => Ok(
+                CommandOutput::Complete(
+                    self.finish_output(&output)
+                )
+            ), 
+            ReadStop::Idle | ReadStop::Full => {
+                // Timeout occurred
+                let mut output = self.clean_output(&output);
+                if let Some(note) = self.interrupt()?.note() {
+                    output = format!("{output}\n[{note}]");
+                }
+
+                Ok(
+                    CommandOutput::Partial(output)
+                )
+            }, 
+        }
     }
 
-    pub fn run_line(&mut self, line: &str, timeout: Duration) -> Result<CommandOutput, Error> {
+    /// Sends a line to the shell without waiting for it to finish, read its output with `read_chunk`.
+    /// The line is followed by a command printing an end marker unique to it, so neither the command's
+    /// output nor its echo can be mistaken for the end of the command.
+    /// Both are sent as one group that the shell reads in full before running it, otherwise a command
+    /// reading input, like `read`, would read the marker's line instead of what `send_input` types
+    pub fn start_command(&mut self, line: &str) -> Result<(), Error> {
+        self.prompt_buffer.clear();
+        self.held_back.clear();
+        self.last_exit_code = None;
+        self.last_duration = None;
+        self.started = Instant::now();
+        self.changes_dir = Self::changes_dir(line);
+        self.running = true;
+        // A group without a command is a syntax error, and there is nothing to read input
         let line = line.trim();
-        if line.trim() == "exit" {
-            //return Err("Exit requested".into());
-            panic!();
+        if line.is_empty() || line.starts_with('#') {
+            return self.send_marker();
         }
+        self.new_marker();
+        // The line ends before the closing brace so a trailing comment or `&` stays valid
+        self.session.send_line(&format!("{{ {line}\n}}; {MARKER_COMMAND} {}", self.marker))?;
+        Ok(())
+    }
 
+    /// Types a line into the running command, e.g. the answer to a prompt it printed.
+    /// Fails when no command is running, since the shell would run the line as a command
+    pub fn send_input(&mut self, line: &str) -> Result<(), Error> {
+        if !self.running {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::NotConnected, "no command is running to take the input")));
+        }
         self.session.send_line(line)?;
+        Ok(())
+    }
+
+    /// Whether a command was started and has neither finished nor been interrupted
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn new_marker(&mut self) {
+        self.marker = format!("{MARKER_PREFIX}{}_", Uuid::now_v7().simple());
+    }
+
+    /// Has the shell print a new end marker once it is done with what it was sent before,
+    /// the echo of this command is not a marker since the id is not followed by digits
+    fn send_marker(&mut self) -> Result<(), Error> {
+        self.new_marker();
+        self.session.send_line(&format!("{MARKER_COMMAND} {}", self.marker))?;
+        Ok(())
+    }
+
+    /// Reads the running command's output until it finishes, `max_chars` have been read,
+    /// or nothing has been printed for `idle_timeout`
+    pub fn read_chunk(&mut self, idle_timeout: Duration, max_chars: usize) -> Result<StreamChunk, Error> {
+        let (output, stop) = self.read_output(idle_timeout, Some(max_chars))?;
+        let mut output = std::mem::take(&mut self.held_back) + &output;
+        match stop {
+            ReadStop::Prompt => Ok(StreamChunk::Done(self.finish_output(&output))), 
+            ReadStop::Idle | ReadStop::Full => {
+                // Hold back what may be the start of the end marker or of an escape sequence
+                // so it is not split across chunks
+                let line_start = output.rfind('\n').map_or(0, |i| i + 1);
+                let n_held = match self.is_partial_marker(&output[line_start..]) {
+                    true => output.len() - line_start, 
+                    false => incomplete_escape(&output).map_or(0, |start| output.len() - start), 
+                };
+                self.held_back = output.split_off(output.len() - n_held);
+                Ok(StreamChunk::Running(self.config.strip_escapes(&output).into_owned()))
+            }, 
+        }
+    }
+
+    /// Whether the end of the output may be an end marker that is not complete yet
+    fn is_partial_marker(&self, tail: &str) -> bool {
+        let tail = tail.trim_end_matches('\r');
+        match tail.strip_prefix(self.marker.as_str()) {
+            Some(status) => status.chars().all(|c| c.is_ascii_digit()), 
+            None => !tail.is_empty() && self.marker.starts_with(tail), 
+        }
+    }
+
+    /// Interrupts the running command with Ctrl+C and waits for the shell to be ready again.
+    /// A command that ignores it is sent SIGTERM and then SIGKILL, and if the shell still does not
+    /// come back it is restarted. The interrupt discards the pending end marker, so a new one is sent
+    pub fn interrupt(&mut self) -> Result<Interruption, Error> {
+        self.session.send_control('c')?;
+
+        let mut interruption = Interruption::CtrlC;
+        let mut escalations = [("TERM", Interruption::Terminated), ("KILL", Interruption::Killed)].into_iter();
+        while !self.wait_for_marker(self.config.interrupt_timeout)? {
+            let Some((signal, next)) = escalations.next() else {
+                self.restart().map_err(|e| match e {
+                    TerminalError::Startup(e) => e, 
+                    e => Error::Io(io::Error::other(e.to_string())), 
+                })?;
+                return Ok(Interruption::Restarted);
+            };
+            self.signal_foreground(signal);
+            interruption = next;
+        }
+        self.running = false;
+        self.prompt_buffer.clear();
+        self.held_back.clear();
+        self.last_duration = Some(self.started.elapsed());
+        // The command may have changed directory before it was interrupted
+        self.update_working_dir();
+        Ok(interruption)
+    }
+
+    /// Sends an end marker and waits for it until `timeout` passes, returning whether it was printed.
+    /// Only one is sent, a command that ignored the interrupt and reads its input would read any more of them.
+    /// The read ends by the deadline, a command that keeps printing would otherwise never be idle
+    fn wait_for_marker(&mut self, timeout: Duration) -> Result<bool, Error> {
+        self.send_marker()?;
+        let (_, stop) = self.read_output_until(timeout, None, Some(Instant::now() + timeout))?;
+        Ok(matches!(stop, ReadStop::Prompt))
+    }
+
+    /// A command run next to the shell, in its container if there is one
+    fn command_beside_shell(&self, args: &[&str]) -> Command {
+        match (&self.config.container, &self.container_name) {
+            (Some(container), Some(name)) => {
+                let mut command = Command::new(&container.runtime);
+                command.args(["exec", name]).args(args);
+                command
+            }, 
+            _ => {
+                let mut command = Command::new(args[0]);
+                command.args(&args[1..]);
+                command
+            }, 
+        }
+    }
+
+    /// Process group in the foreground of the shell's terminal, `None` if it is the shell itself or unknown
+    fn foreground_group(&self) -> Option<String> {
+        let Some(_) = self.container_name else {
+            let pid = self.session.process.child_pid.as_raw();
+            let group = tcgetpgrp(self.session.process.pty.as_raw_fd()).ok()?.as_raw();
+            return (group != pid && group > 0).then(|| group.to_string());
+        };
+
+        // The container's shell has a terminal of its own inside the container, which is always
+        // Linux, and the shell is its first process
+        let output = self.command_beside_shell(&["cat", "/proc/1/stat"]).output().ok()?;
+        let stat = String::from_utf8_lossy(&output.stdout);
+        // The fields after the parenthesized command name are state, ppid, pgrp, session, tty_nr and tpgid
+        let (_, fields) = stat.rsplit_once(')')?;
+        let group = fields.split_whitespace().nth(5)?;
+        (group != "1" && group.parse::<i32>().is_ok_and(|group| group > 0)).then(|| group.to_string())
+    }
+
+    /// Sends a signal like `TERM` to the foreground process group, the shell itself is left alone
+    fn signal_foreground(&self, signal: &str) {
+        let Some(group) = self.foreground_group() else {
+            return;
+        };
+        if let Err(e) = self.command_beside_shell(&["kill", "-s", signal, "--", &format!("-{group}")]).output() {
+            eprintln!("Failed to send SIG{signal} to the running command: {e}");
+        }
+    }
+
+    /// Whether the last line read is a complete end marker
+    fn ends_with_marker(&self) -> bool {
+        let line = self.prompt_buffer.trim_end_matches(['\r', '\n']);
+        match line.rsplit_once(self.marker.as_str()) {
+            Some((_, status)) => !status.is_empty() && status.chars().all(|c| c.is_ascii_digit()), 
+            None => false, 
+        }
+    }
 
+    /// Reads until the end marker, until `max_chars` have been read or until nothing has been printed for
+    /// `idle_timeout`. Without `max_chars` the middle of the output is dropped once it is over `max_output_bytes`
+    fn read_output(&mut self, idle_timeout: Duration, max_chars: Option<usize>) -> Result<(String, ReadStop), Error> {
+        self.read_output_until(idle_timeout, max_chars, None)
+    }
+
+    /// Like `read_output`, but also stops as if idle once `deadline` has passed, however much is being printed
+    fn read_output_until(&mut self, idle_timeout: Duration, max_chars: Option<usize>, deadline: Option<Instant>) -> Result<(String, ReadStop), Error> {
         let mut last_char_time = Instant::now();
-        let mut output = String::new();
-        let mut prompt_buffer = String::new();
-        const PROMPT: &str = "CMD_END>";
-        
+        let mut output = CappedOutput::new(self.config.max_output_bytes.filter(|_| max_chars.is_none()));
+        let mut n_chars = 0;
+
         loop {
-            if last_char_time.elapsed() >= timeout {
-                // Timeout occurred
-                self.session.send_control('c')?;
-                // Wait for prompt to return after Ctrl+C
-                self.session.exp_string(PROMPT)?;
-
-                return Ok(
-                    CommandOutput::Partial(
-                        self.clean_output(&output, PROMPT)
-                    )
-                );
+            if last_char_time.elapsed() >= idle_timeout || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok( (output.into_string(), ReadStop::Idle) );
             }
 
-            if let Some(c) = self.session.try_read() {
-                last_char_time = Instant::now(); // Reset timer on character receipt
-                output.push(c);
-                prompt_buffer.push(c);
-                
-                // Keep prompt buffer at most as long as our prompt
-                if prompt_buffer.len() > PROMPT.len() {
-                    prompt_buffer.remove(0);
-                }
-                
-                // Check if we've reached the prompt
-                if prompt_buffer == PROMPT {
-                    return Ok(
-                        CommandOutput::Complete(
-                            self.clean_output(&output, PROMPT)
-                        )
-                    );
-                }
+            match self.session.try_read() {
+                Some(c) => {
+                    last_char_time = Instant::now(); // Reset timer on character receipt
+                    output.push(c);
+                    n_chars += 1;
+                    self.prompt_buffer.push(c);
+                    
+                    // Keep the buffer just long enough for the marker and an exit code
+                    let max_len = self.marker.len() + 16;
+                    if self.prompt_buffer.len() > max_len {
+                        let start = self.prompt_buffer.len() - max_len;
+                        let start = (start..self.prompt_buffer.len())
+                            .find(|&i| self.prompt_buffer.is_char_boundary(i))
+                            .unwrap_or(start);
+                        self.prompt_buffer.drain(..start);
+                    }
+                    
+                    // The marker's line ends after the exit code
+                    if c == '\n' && self.ends_with_marker() {
+                        self.running = false;
+                        self.prompt_buffer.clear();
+                        return Ok( (output.into_string(), ReadStop::Prompt) );
+                    }
+
+                    // A chunk never ends in the middle of the marker, it may be longer than a chunk
+                    let line_start = self.prompt_buffer.rfind('\n').map_or(0, |i| i + 1);
+                    if max_chars.is_some_and(|max_chars| n_chars >= max_chars) && !self.is_partial_marker(&self.prompt_buffer[line_start..]) {
+                        return Ok( (output.into_string(), ReadStop::Full) );
+                    }
+                }, 
+                None => {
+                    // Small sleep to prevent busy waiting
+                    std::thread::sleep(Duration::from_millis(10));
+                }, 
             }
-            
-            // Small sleep to prevent busy waiting
-            std::thread::sleep(Duration::from_millis(10));
         }
     }
 
     pub fn run_command(&mut self, command: &str, timeout: Duration) -> Result<CommandOutput, Box<dyn std::error::Error>> {
         let mut output = String::new();
+        let start = Instant::now();
 
         let command = command.trim();
 
@@ -98,6 +876,7 @@ impl Terminal {
                     output += &out;
                 }, 
                 CommandOutput::Partial(pout) => {
+                    self.last_duration = Some(start.elapsed());
                     return Ok(
                         CommandOutput::Partial(output + &pout)
                     );
@@ -105,16 +884,239 @@ impl Terminal {
             }
         }
 
+        self.last_duration = Some(start.elapsed());
         Ok(
             CommandOutput::Complete(output)
         )
     }
 }
 
+/// Output of a command that keeps only its start and its end once it grows too large
+struct CappedOutput {
+    head: String, 
+    tail: String, 
+    /// Bytes kept of the start and of the end, everything is kept when `None`
+    half: Option<usize>, 
+    n_omitted: usize, 
+}
+
+impl CappedOutput {
+    fn new(max_bytes: Option<usize>) -> Self {
+        Self {
+            head: String::new(), 
+            tail: String::new(), 
+            half: max_bytes.map(|max_bytes| max_bytes / 2), 
+            n_omitted: 0, 
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        match self.half {
+            Some(half) if self.head.len() >= half => {
+                self.tail.push(c);
+                // Dropped in batches so the tail is not shifted on every character
+                if self.tail.len() > half + 4096 {
+                    let mut cut = self.tail.len() - half;
+                    while !self.tail.is_char_boundary(cut) {
+                        cut += 1;
+                    }
+                    self.tail.drain(..cut);
+                    self.n_omitted += cut;
+                }
+            }, 
+            _ => self.head.push(c), 
+        }
+    }
+
+    /// The output with a note where the middle was dropped, cut at line ends close to the edges
+    fn into_string(self) -> String {
+        if self.n_omitted == 0 {
+            return self.head + &self.tail;
+        }
+        let head_end = self.head
+            .rfind('\n')
+            .filter(|&i| i >= self.head.len() * 3 / 4)
+            .map_or(self.head.len(), |i| i + 1);
+        let tail_start = self.tail
+            .find('\n')
+            .filter(|&i| i < self.tail.len() / 4)
+            .map_or(0, |i| i + 1);
+        let n_omitted = self.n_omitted + self.head.len() - head_end + tail_start;
+        format!("{}\n...[{n_omitted} bytes omitted]...\n{}", &self.head[..head_end], &self.tail[tail_start..])
+    }
+}
+
+/// Length in bytes of the ANSI escape sequence `seq` starts with, `None` if it is cut off.
+/// Handles CSI sequences like colors and cursor movement, OSC sequences like window titles,
+/// and the short ones made of the escape and one or two more characters
+fn escape_len(seq: &str) -> Option<usize> {
+    let mut chars = seq.char_indices().skip(1);
+    let end = |(i, c): (usize, char)| i + c.len_utf8();
+    match chars.next()? {
+        (_, '[') => chars.find(|(_, c)| ('@'..='~').contains(c)).map(end), 
+        // Ended by BEL or by ESC \
+        (_, ']') => {
+            let mut after_escape = false;
+            for (i, c) in chars {
+                if c == '\x07' || (after_escape && c == '\\') {
+                    return Some(end((i, c)));
+                }
+                after_escape = c == '\x1b';
+            }
+            None
+        }, 
+        // E.g. ESC ( B selecting a character set
+        (_, c) if (' '..='/').contains(&c) => chars.find(|(_, c)| ('0'..='~').contains(c)).map(end), 
+        next => Some(end(next)), 
+    }
+}
+
+/// Removes ANSI escape sequences from text, one cut off at the end is removed as well
+fn strip_ansi(text: &str) -> Cow<'_, str> {
+    if !text.contains('\x1b') {
+        return Cow::Borrowed(text);
+    }
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('\x1b') {
+        stripped.push_str(&rest[..start]);
+        match escape_len(&rest[start..]) {
+            Some(len) => rest = &rest[start + len..], 
+            None => return Cow::Owned(stripped), 
+        }
+    }
+    stripped.push_str(rest);
+    Cow::Owned(stripped)
+}
+
+/// Where the escape sequence cut off at the end of the text starts, if there is one
+fn incomplete_escape(text: &str) -> Option<usize> {
+    let start = text.rfind('\x1b')?;
+    escape_len(&text[start..]).is_none().then_some(start)
+}
+
+/// What the session loop needs from a shell, so it can be driven by something other than a real pty
+pub trait TerminalLike {
+    /// Runs a command to completion, or until it prints nothing for `timeout`
+    fn run_command(&mut self, command: &str, timeout: Duration) -> Result<CommandOutput, Box<dyn std::error::Error>>;
+    /// Starts a command whose output is then read with `read_chunk`
+    fn start_command(&mut self, line: &str) -> Result<(), Error>;
+    fn read_chunk(&mut self, idle_timeout: Duration, max_chars: usize) -> Result<StreamChunk, Error>;
+    /// Types a line into the running command
+    fn send_input(&mut self, line: &str) -> Result<(), Error>;
+    /// Interrupts the running command, escalating to signals it cannot ignore
+    fn interrupt(&mut self) -> Result<Interruption, Error>;
+    /// Exit code of the last command that finished, `None` if it was interrupted
+    fn last_exit_code(&self) -> Option<i32>;
+    /// How long the last command ran, if it is known
+    fn last_duration(&self) -> Option<Duration> {
+        None
+    }
+    fn is_alive(&self) -> bool;
+    /// Whether the shell answers within `timeout`
+    fn is_responsive(&mut self, timeout: Duration) -> bool;
+    /// Replaces the shell with a fresh one
+    fn restart(&mut self) -> Result<(), TerminalError>;
+    /// The shell's working directory, if it is known
+    fn working_dir(&self) -> Option<&str> {
+        None
+    }
+    /// The shell's working directory, asking the shell for it if it is not known
+    fn cwd(&mut self) -> Option<String> {
+        self.working_dir().map(str::to_string)
+    }
+    /// Path of the shell that runs the commands
+    fn shell(&self) -> &str {
+        "/bin/bash"
+    }
+    /// Trimmed stdout of a program run next to the shell, so in its container if there is one.
+    /// `None` if it could not be run or failed
+    fn program_output(&self, args: &[&str]) -> Option<String> {
+        let output = std::process::Command::new(args[0]).args(&args[1..]).output().ok()?;
+        output.status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+impl TerminalLike for Terminal {
+    fn run_command(&mut self, command: &str, timeout: Duration) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        Terminal::run_command(self, command, timeout)
+    }
+
+    fn start_command(&mut self, line: &str) -> Result<(), Error> {
+        Terminal::start_command(self, line)
+    }
+
+    fn read_chunk(&mut self, idle_timeout: Duration, max_chars: usize) -> Result<StreamChunk, Error> {
+        Terminal::read_chunk(self, idle_timeout, max_chars)
+    }
+
+    fn send_input(&mut self, line: &str) -> Result<(), Error> {
+        Terminal::send_input(self, line)
+    }
+
+    fn interrupt(&mut self) -> Result<Interruption, Error> {
+        Terminal::interrupt(self)
+    }
+
+    fn last_exit_code(&self) -> Option<i32> {
+        Terminal::last_exit_code(self)
+    }
+
+    fn last_duration(&self) -> Option<Duration> {
+        Terminal::last_duration(self)
+    }
+
+    fn is_alive(&self) -> bool {
+        Terminal::is_alive(self)
+    }
+
+    fn is_responsive(&mut self, timeout: Duration) -> bool {
+        Terminal::is_responsive(self, timeout)
+    }
+
+    fn restart(&mut self) -> Result<(), TerminalError> {
+        Terminal::restart(self)
+    }
+
+    fn working_dir(&self) -> Option<&str> {
+        Terminal::working_dir(self)
+    }
+
+    fn cwd(&mut self) -> Option<String> {
+        Terminal::cwd(self).map(str::to_string)
+    }
+
+    fn shell(&self) -> &str {
+        Terminal::shell(self)
+    }
+
+    #[cfg(unix)]
+    fn program_output(&self, args: &[&str]) -> Option<String> {
+        let output = self.command_beside_shell(args).output().ok()?;
+        output.status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+#[cfg(unix)]
 impl Drop for Terminal {
     fn drop(&mut self) {
-        let _ = self.session.send_line("exit"); 
-        // Give it a moment to clean up
-        let _ = std::thread::sleep(Duration::from_millis(100));
+        if let Err(e) = self.shut_down() {
+            eprintln!("Failed to shut down the shell: {e}");
+        }
+
+        // A wedged shell never exits, so the container is removed by name as well
+        if let (Some(container), Some(name)) = (&self.config.container, &self.container_name) {
+            match Command::new(&container.runtime).args(["rm", "--force", name]).output() {
+                Ok(output) if !output.status.success() => eprintln!(
+                    "Failed to remove container {name}: {}", String::from_utf8_lossy(&output.stderr).trim()
+                ), 
+                Ok(_) => {}, 
+                Err(e) => eprintln!("Failed to remove container {name}: {e}"), 
+            }
+        }
     }
 }
\ No newline at end of file
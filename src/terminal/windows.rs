@@ -0,0 +1,532 @@
+//! The terminal on Windows, where there is no pty to drive: PowerShell reads the commands from a pipe
+//! and its output and errors are read from another one
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use super::{
+    incomplete_escape, CappedOutput, CommandOutput, Error, Interruption, ReadStop, ShellKind, StreamChunk, 
+    TerminalConfig, TerminalError, EXIT_TIMEOUT, MARKER_PREFIX, 
+};
+
+pub struct Terminal {
+    /// The shell, locked only so `is_alive` can poll it through a shared reference
+    child: Mutex<Child>,
+    stdin: ChildStdin,
+    /// What the shell prints to stdout and stderr, a pipe cannot be read without blocking so a thread reads it
+    output: Receiver<char>,
+    /// End marker of the running command, up to the exit code
+    marker: String,
+    /// The last characters read, enough to hold the marker and an exit code
+    prompt_buffer: String,
+    held_back: String,
+    last_exit_code: Option<i32>,
+    config: TerminalConfig,
+    /// The shell's working directory as of the last command that could change it, kept across restarts
+    working_dir: Option<String>,
+    /// Whether the running command may change the working directory
+    changes_dir: bool,
+    /// Whether a command was started and has neither finished nor been interrupted
+    running: bool,
+    /// When the running command was sent
+    started: Instant,
+    /// How long the last command ran until it finished or was interrupted
+    last_duration: Option<Duration>,
+}
+
+impl Terminal {
+    pub fn new() -> Result<Self, TerminalError> {
+        Self::with_config(TerminalConfig::default())
+    }
+
+    /// A terminal running `shell` instead of Windows PowerShell, e.g. `pwsh.exe`
+    pub fn with_shell(shell: &str) -> Result<Self, TerminalError> {
+        Self::with_config(
+            TerminalConfig {
+                shell: shell.to_string(), 
+                ..TerminalConfig::default()
+            }
+        )
+    }
+
+    /// A terminal setting `vars` in its shell, on top of the environment it inherits
+    pub fn with_env(vars: HashMap<String, String>) -> Result<Self, TerminalError> {
+        Self::with_config(
+            TerminalConfig {
+                env: vars, 
+                ..TerminalConfig::default()
+            }
+        )
+    }
+
+    /// Starts PowerShell. `bash_opts` and `locale` do not apply to it and containers are not supported,
+    /// output is always UTF-8
+    pub fn with_config(config: TerminalConfig) -> Result<Self, TerminalError> {
+        if ShellKind::of(&config.shell) != Some(ShellKind::PowerShell) {
+            return Err(TerminalError::UnsupportedShell(config.shell.clone()));
+        }
+        config.check_env()?;
+        if config.container.is_some() {
+            return Err(TerminalError::Startup(io::Error::new(io::ErrorKind::Unsupported, "containers are not supported on Windows")));
+        }
+
+        let (reader, writer) = io::pipe().map_err(TerminalError::Startup)?;
+        let errors = writer.try_clone().map_err(TerminalError::Startup)?;
+        let mut child = Command::new(&config.shell)
+            .args(["-NoLogo", "-NoProfile", "-NonInteractive", "-Command", "-"])
+            .stdin(Stdio::piped())
+            .stdout(writer)
+            .stderr(errors)
+            .spawn()
+            .map_err(TerminalError::Startup)?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+
+        let (sender, output) = mpsc::channel();
+        thread::spawn(move || forward_output(reader, sender));
+
+        let mut terminal = Terminal {
+            child: Mutex::new(child), 
+            stdin, 
+            output, 
+            marker: String::new(), 
+            prompt_buffer: String::new(), 
+            held_back: String::new(), 
+            last_exit_code: None, 
+            config, 
+            working_dir: None, 
+            changes_dir: false, 
+            running: false, 
+            started: Instant::now(), 
+            last_duration: None, 
+        };
+
+        // No progress bars, they are drawn with cursor movement, and no prompt between commands
+        terminal.run_setup("function prompt { '' }; $ProgressPreference = 'SilentlyContinue'; \
+[Console]::OutputEncoding = [Text.Encoding]::UTF8; $OutputEncoding = [Text.Encoding]::UTF8")?;
+
+        // Single quoted, so nothing in a value is expanded
+        for (name, value) in terminal.config.env.clone() {
+            terminal.run_setup(&format!("$env:{name} = '{}'", value.replace('\'', "''")))?;
+        }
+
+        Ok(terminal)
+    }
+
+    /// Runs a setup command and waits for its end marker
+    fn run_setup(&mut self, line: &str) -> Result<(), TerminalError> {
+        self.start_command(line).map_err(TerminalError::Startup)?;
+        match self.read_output(self.config.startup_timeout, None).map_err(TerminalError::Startup)? {
+            (_, ReadStop::Prompt) => Ok(()), 
+            _ => Err(TerminalError::Startup(io::Error::new(io::ErrorKind::TimedOut, "the shell did not finish its setup"))), 
+        }
+    }
+
+    /// Asks the shell to exit and kills it with everything it started if it has not within `EXIT_TIMEOUT`
+    fn shut_down(&mut self) -> io::Result<()> {
+        let _ = writeln!(self.stdin, "exit");
+
+        let child = self.child.get_mut().unwrap_or_else(|e| e.into_inner());
+        let start = Instant::now();
+        while start.elapsed() < EXIT_TIMEOUT {
+            if child.try_wait()?.is_some() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Command::new("taskkill").args(["/F", "/T", "/PID", &child.id().to_string()]).output()?;
+        child.kill()?;
+        child.wait()?;
+        Ok(())
+    }
+
+    /// Whether the shell is still running
+    pub fn is_alive(&self) -> bool {
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        matches!(child.try_wait(), Ok(None))
+    }
+
+    /// Replaces the shell with a fresh one using the same config, started in the old shell's working directory.
+    /// Anything else the old shell held, like variables and functions, is lost
+    pub fn restart(&mut self) -> Result<(), TerminalError> {
+        let working_dir = self.working_dir.take();
+        *self = Self::with_config(self.config.clone())?;
+        if let Some(dir) = working_dir {
+            self.run_setup(&format!("Set-Location -LiteralPath '{}'", dir.replace('\'', "''")))?;
+            self.working_dir = Some(dir);
+        }
+        Ok(())
+    }
+
+    /// Path of the shell that runs the commands
+    pub fn shell(&self) -> &str {
+        &self.config.shell
+    }
+
+    /// The shell's working directory, `None` until a command changed it or it was asked for with `cwd`
+    pub fn working_dir(&self) -> Option<&str> {
+        self.working_dir.as_deref()
+    }
+
+    /// The shell's working directory. The shell is only asked the first time, after that it is kept
+    /// up to date by the commands that may change it
+    pub fn cwd(&mut self) -> Option<&str> {
+        if self.working_dir.is_none() {
+            self.changes_dir = true;
+            self.update_working_dir();
+        }
+        self.working_dir.as_deref()
+    }
+
+    /// Whether a line runs a command that changes the shell's working directory, PowerShell's commands
+    /// and aliases are not case sensitive
+    fn changes_dir(line: &str) -> bool {
+        line.split(|c: char| c.is_whitespace() || ";&|(){}".contains(c))
+            .map(str::to_lowercase)
+            .any(|word| matches!(
+                word.as_str(), 
+                "cd" | "chdir" | "sl" | "set-location" | "pushd" | "push-location" | "popd" | "pop-location"
+            ))
+    }
+
+    /// Asks the shell for its working directory after a command that may have changed it.
+    /// The exit code of that command is kept, both here and in the shell's `$LASTEXITCODE`
+    fn update_working_dir(&mut self) {
+        if !std::mem::take(&mut self.changes_dir) {
+            return;
+        }
+        let last_exit_code = self.last_exit_code;
+        if self.send_line("(Get-Location).ProviderPath").is_ok() {
+            if let Ok((output, ReadStop::Prompt)) = self.read_output(self.config.startup_timeout, None) {
+                let output = output.rsplit_once(self.marker.as_str()).map_or(output.as_str(), |(output, _)| output);
+                self.working_dir = self.clean_output(output).lines().last().map(|dir| dir.trim().to_string());
+            }
+        }
+        self.running = false;
+        self.last_exit_code = last_exit_code;
+    }
+
+    /// Whether the shell answers a no-op within `timeout`. The previous exit code is kept,
+    /// both here and in the shell's `$LASTEXITCODE`
+    pub fn is_responsive(&mut self, timeout: Duration) -> bool {
+        let last_exit_code = self.last_exit_code;
+        if self.send_line("'__alive__'").is_err() {
+            return false;
+        }
+
+        let responsive = match self.read_output(timeout, None) {
+            Ok((output, ReadStop::Prompt)) => output.lines().any(|line| line.trim() == "__alive__"), 
+            _ => false, 
+        };
+        self.running = false;
+        self.last_exit_code = last_exit_code;
+        responsive
+    }
+
+    /// Exit code of the last command that finished, `None` if it was interrupted
+    pub fn last_exit_code(&self) -> Option<i32> {
+        self.last_exit_code
+    }
+
+    /// How long the last command ran, from being sent until it finished or was interrupted
+    pub fn last_duration(&self) -> Option<Duration> {
+        self.last_duration
+    }
+
+    /// Trims the output, stripping escape sequences and cutting lines that are too long.
+    /// Nothing is echoed from a pipe, so unlike with a pty there is no command line to drop
+    fn clean_output(&self, raw_output: &str) -> String {
+        self.config.strip_escapes(raw_output)
+            .trim()
+            .lines()
+            .map(|line| self.config.cut_line(line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Cleans the output of a finished command, taking the exit code off the end marker
+    fn finish_output(&mut self, raw_output: &str) -> String {
+        let (output, status) = raw_output
+            .rsplit_once(self.marker.as_str())
+            .unwrap_or((raw_output, ""));
+        self.last_exit_code = status.trim().parse().ok();
+        self.last_duration = Some(self.started.elapsed());
+        let output = self.clean_output(output);
+        self.update_working_dir();
+        output
+    }
+
+    /// Runs a single line to completion, or until it prints nothing for `timeout`.
+    /// A line running `exit` is not sent, it fails with `TerminalError::ExitRequested`
+    pub fn run_line(&mut self, line: &str, timeout: Duration) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        let line = line.trim();
+        if line.split([' ', ';', '&', '|']).next().is_some_and(|word| word.eq_ignore_ascii_case("exit")) {
+            return Err(TerminalError::ExitRequested.into());
+        }
+
+        self.start_command(line)?;
+
+        let (output, stop) = self.read_output(timeout, None)?;
+        match stop {
+            ReadStop::Prompt => Ok(
+                CommandOutput::Complete(
+                    self.finish_output(&output)
+                )
+            ), 
+            ReadStop::Idle | ReadStop::Full => {
+                let mut output = self.clean_output(&output);
+                if let Some(note) = self.interrupt()?.note() {
+                    output = format!("{output}\n[{note}]");
+                }
+
+                Ok(
+                    CommandOutput::Partial(output)
+                )
+            }, 
+        }
+    }
+
+    /// Sends a line to the shell without waiting for it to finish, read its output with `read_chunk`.
+    /// `$LASTEXITCODE` is reset first so a failing cmdlet is not reported with an older program's exit code
+    pub fn start_command(&mut self, line: &str) -> Result<(), Error> {
+        self.last_exit_code = None;
+        self.last_duration = None;
+        self.started = Instant::now();
+        self.changes_dir = Self::changes_dir(line);
+        let line = line.trim();
+        match line.is_empty() || line.starts_with('#') {
+            true => self.send_line(""), 
+            false => self.send_line(&format!("$global:LASTEXITCODE = 0; {line}")), 
+        }
+    }
+
+    /// Sends a line followed by a statement printing an end marker unique to it and the line's exit code:
+    /// 0 if it succeeded, the exit code of the program that failed, or 1 for a failing cmdlet
+    fn send_line(&mut self, line: &str) -> Result<(), Error> {
+        self.prompt_buffer.clear();
+        self.held_back.clear();
+        self.running = true;
+        self.marker = format!("{MARKER_PREFIX}{}_", Uuid::now_v7().simple());
+        if !line.is_empty() {
+            writeln!(self.stdin, "{line}")?;
+        }
+        writeln!(
+            self.stdin, 
+            "$__status = $?; \"{}$(if ($__status) {{ 0 }} elseif ($LASTEXITCODE) {{ $LASTEXITCODE }} else {{ 1 }})\"", 
+            self.marker, 
+        )?;
+        self.stdin.flush()
+    }
+
+    /// Writes a line to the running command's stdin, e.g. the answer to a prompt it printed.
+    /// Fails when no command is running, since the shell would run the line as a command
+    pub fn send_input(&mut self, line: &str) -> Result<(), Error> {
+        if !self.running {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "no command is running to take the input"));
+        }
+        writeln!(self.stdin, "{line}")?;
+        self.stdin.flush()
+    }
+
+    /// Whether a command was started and has neither finished nor been interrupted
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Reads the running command's output until it finishes, `max_chars` have been read,
+    /// or nothing has been printed for `idle_timeout`
+    pub fn read_chunk(&mut self, idle_timeout: Duration, max_chars: usize) -> Result<StreamChunk, Error> {
+        let (output, stop) = self.read_output(idle_timeout, Some(max_chars))?;
+        let mut output = std::mem::take(&mut self.held_back) + &output;
+        match stop {
+            ReadStop::Prompt => Ok(StreamChunk::Done(self.finish_output(&output))), 
+            ReadStop::Idle | ReadStop::Full => {
+                // Hold back what may be the start of the end marker or of an escape sequence
+                // so it is not split across chunks
+                let line_start = output.rfind('\n').map_or(0, |i| i + 1);
+                let n_held = match self.is_partial_marker(&output[line_start..]) {
+                    true => output.len() - line_start, 
+                    false => incomplete_escape(&output).map_or(0, |start| output.len() - start), 
+                };
+                self.held_back = output.split_off(output.len() - n_held);
+                Ok(StreamChunk::Running(self.config.strip_escapes(&output).into_owned()))
+            }, 
+        }
+    }
+
+    /// Whether the end of the output may be an end marker that is not complete yet
+    fn is_partial_marker(&self, tail: &str) -> bool {
+        let tail = tail.trim_end_matches('\r');
+        match tail.strip_prefix(self.marker.as_str()) {
+            Some(status) => status.chars().all(|c| c.is_ascii_digit()), 
+            None => !tail.is_empty() && self.marker.starts_with(tail), 
+        }
+    }
+
+    /// Stops the running command. A shell reading from a pipe cannot be sent Ctrl+C, so the programs
+    /// the shell started are killed with everything they started, after which the shell prints the
+    /// pending end marker. If it does not, e.g. because the shell itself is busy in a cmdlet, it is restarted
+    pub fn interrupt(&mut self) -> Result<Interruption, Error> {
+        let pid = self.child.get_mut().unwrap_or_else(|e| e.into_inner()).id();
+        let kill_children = format!(
+            "Get-CimInstance Win32_Process -Filter 'ParentProcessId={pid}' | \
+ForEach-Object {{ taskkill /F /T /PID $_.ProcessId }}"
+        );
+        if let Err(e) = Command::new(&self.config.shell).args(["-NoProfile", "-Command", &kill_children]).output() {
+            eprintln!("Failed to kill the running command: {e}");
+        }
+
+        if let (_, ReadStop::Idle) = self.read_output(self.config.interrupt_timeout, None)? {
+            self.restart().map_err(|e| match e {
+                TerminalError::Startup(e) => e, 
+                e => io::Error::other(e.to_string()), 
+            })?;
+            return Ok(Interruption::Restarted);
+        }
+        self.running = false;
+        self.last_exit_code = None;
+        self.prompt_buffer.clear();
+        self.held_back.clear();
+        self.last_duration = Some(self.started.elapsed());
+        // The command may have changed directory before it was interrupted
+        self.update_working_dir();
+        Ok(Interruption::Killed)
+    }
+
+    /// Whether the last line read is a complete end marker
+    fn ends_with_marker(&self) -> bool {
+        let line = self.prompt_buffer.trim_end_matches(['\r', '\n']);
+        match line.rsplit_once(self.marker.as_str()) {
+            Some((_, status)) => !status.is_empty() && status.chars().all(|c| c.is_ascii_digit()), 
+            None => false, 
+        }
+    }
+
+    /// Reads until the end marker, until `max_chars` have been read or until nothing has been printed for
+    /// `idle_timeout`. Without `max_chars` the middle of the output is dropped once it is over `max_output_bytes`.
+    /// Fails once the shell closed its output
+    fn read_output(&mut self, idle_timeout: Duration, max_chars: Option<usize>) -> Result<(String, ReadStop), Error> {
+        let mut output = CappedOutput::new(self.config.max_output_bytes.filter(|_| max_chars.is_none()));
+        let mut n_chars = 0;
+
+        loop {
+            let c = match self.output.recv_timeout(idle_timeout) {
+                Ok(c) => c, 
+                Err(RecvTimeoutError::Timeout) => return Ok( (output.into_string(), ReadStop::Idle) ), 
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.running = false;
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the shell exited"));
+                }, 
+            };
+            output.push(c);
+            n_chars += 1;
+            self.prompt_buffer.push(c);
+
+            // Keep the buffer just long enough for the marker and an exit code
+            let max_len = self.marker.len() + 16;
+            if self.prompt_buffer.len() > max_len {
+                let start = self.prompt_buffer.len() - max_len;
+                let start = (start..self.prompt_buffer.len())
+                    .find(|&i| self.prompt_buffer.is_char_boundary(i))
+                    .unwrap_or(start);
+                self.prompt_buffer.drain(..start);
+            }
+
+            // The marker's line ends after the exit code
+            if c == '\n' && self.ends_with_marker() {
+                self.running = false;
+                self.prompt_buffer.clear();
+                return Ok( (output.into_string(), ReadStop::Prompt) );
+            }
+
+            // A chunk never ends in the middle of the marker, it may be longer than a chunk
+            let line_start = self.prompt_buffer.rfind('\n').map_or(0, |i| i + 1);
+            if max_chars.is_some_and(|max_chars| n_chars >= max_chars) && !self.is_partial_marker(&self.prompt_buffer[line_start..]) {
+                return Ok( (output.into_string(), ReadStop::Full) );
+            }
+        }
+    }
+
+    pub fn run_command(&mut self, command: &str, timeout: Duration) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        let mut output = String::new();
+        let start = Instant::now();
+
+        for line in command.trim().lines() {
+            match self.run_line(line, timeout)? {
+                CommandOutput::Complete(out) => {
+                    output += &out;
+                }, 
+                CommandOutput::Partial(pout) => {
+                    self.last_duration = Some(start.elapsed());
+                    return Ok(
+                        CommandOutput::Partial(output + &pout)
+                    );
+                }, 
+            }
+        }
+
+        self.last_duration = Some(start.elapsed());
+        Ok(
+            CommandOutput::Complete(output)
+        )
+    }
+}
+
+/// Decodes what the shell prints and sends it on character by character until the pipe is closed,
+/// which happens once the shell and everything it started have exited
+fn forward_output(mut reader: io::PipeReader, sender: Sender<char>) {
+    let mut buffer = [0; 4096];
+    let mut pending = Vec::new();
+    loop {
+        let n_read = match reader.read(&mut buffer) {
+            Ok(0) | Err(_) => return, 
+            Ok(n_read) => n_read, 
+        };
+        pending.extend_from_slice(&buffer[..n_read]);
+
+        // A character may be split across reads, its start is kept for the next one
+        let mut decoded = String::new();
+        let mut rest = pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    decoded.push_str(text);
+                    rest = &[];
+                    break;
+                }, 
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    decoded.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        Some(len) => {
+                            decoded.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }, 
+                        None => {
+                            rest = invalid;
+                            break;
+                        }, 
+                    }
+                }, 
+            }
+        }
+        pending = rest.to_vec();
+
+        if decoded.chars().any(|c| sender.send(c).is_err()) {
+            return;
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if let Err(e) = self.shut_down() {
+            eprintln!("Failed to shut down the shell: {e}");
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Moves whole files in and out of the session's working directory without going through the pty,
+/// which mangles anything that is not text
+#[derive(Debug, Clone)]
+pub struct FileTransfer {
+    root: PathBuf,
+    max_bytes: usize,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    /// The path resolves to somewhere outside the transfer root
+    OutsideRoot { path: PathBuf, root: PathBuf },
+    TooLarge { size: u64, max_bytes: usize },
+    Io(io::Error),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutsideRoot { path, root } => write!(f, "{} is outside {}, the directory files can be transferred in", path.display(), root.display()), 
+            Self::TooLarge { size, max_bytes } => write!(f, "File is {size} bytes, at most {max_bytes} can be transferred"), 
+            Self::Io(e) => write!(f, "{e}"), 
+        }
+    }
+}
+
+impl std::error::Error for TransferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e), 
+            _ => None, 
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl FileTransfer {
+    /// Files can only be transferred within `root`
+    pub fn new(root: PathBuf, max_bytes: usize) -> Self {
+        Self {
+            root,
+            max_bytes,
+        }
+    }
+
+    /// Writes `bytes` to `path`, replacing the file if it exists, and returns where it was written.
+    /// A relative path is resolved against `dir`, e.g. the shell's working directory, or against the
+    /// root without one. The directory must already exist
+    pub fn put(&self, dir: Option<&Path>, path: &str, bytes: &[u8]) -> Result<PathBuf, TransferError> {
+        self.check_size(bytes.len() as u64)?;
+
+        let path = self.resolve(dir, path);
+        let Some(file_name) = path.file_name() else {
+            return Err(self.outside_root(&path));
+        };
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let path = self.check_inside(&dir.canonicalize()?)?.join(file_name);
+
+        // A symlink could still point out of the root
+        if path.is_symlink() {
+            self.check_inside(&path.canonicalize()?)?;
+        }
+        fs::write(&path, bytes)?;
+        Ok(path)
+    }
+
+    /// Reads the file at `path`, resolved like in `put`, and returns its bytes and where it was read from
+    pub fn get(&self, dir: Option<&Path>, path: &str) -> Result<(Vec<u8>, PathBuf), TransferError> {
+        let path = self.check_inside(&self.resolve(dir, path).canonicalize()?)?;
+        self.check_size(fs::metadata(&path)?.len())?;
+        Ok( (fs::read(&path)?, path) )
+    }
+
+    fn resolve(&self, dir: Option<&Path>, path: &str) -> PathBuf {
+        dir.unwrap_or(&self.root).join(path)
+    }
+
+    fn check_inside(&self, path: &Path) -> Result<PathBuf, TransferError> {
+        match path.starts_with(self.root.canonicalize()?) {
+            true => Ok(path.to_path_buf()), 
+            false => Err(self.outside_root(path)), 
+        }
+    }
+
+    fn outside_root(&self, path: &Path) -> TransferError {
+        let root = self.root.canonicalize().unwrap_or_else(|_| self.root.clone());
+        TransferError::OutsideRoot { path: path.to_path_buf(), root }
+    }
+
+    fn check_size(&self, size: u64) -> Result<(), TransferError> {
+        match size > self.max_bytes as u64 {
+            true => Err(TransferError::TooLarge { size, max_bytes: self.max_bytes }), 
+            false => Ok(()), 
+        }
+    }
+}
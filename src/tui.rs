@@ -0,0 +1,256 @@
+use std::error::Error;
+use std::time::Duration;
+use ratatui::{DefaultTerminal, Frame};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use crate::llm::{LLMApi, MessageKind, Role};
+use crate::session::{Session, StepOutcome};
+
+/// What the session is doing, shown in the status line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Working,
+    WaitingForInput,
+    Finished,
+}
+
+enum Action {
+    None,
+    Quit,
+    Submit,
+}
+
+#[derive(Default)]
+struct Tui {
+    /// How many lines the view is scrolled up from the end of the conversation
+    scroll_from_bottom: usize,
+    max_scroll: usize,
+    input: String,
+}
+
+/// Runs the session in a full screen view with a scrollable conversation, a status line
+/// and an input box for user control turns. The session should be created with `echo` off
+pub fn run(session: &mut Session<impl LLMApi>) -> Result<(), Box<dyn Error>> {
+    let mut terminal = ratatui::init();
+    let result = Tui::default().run(&mut terminal, session);
+    ratatui::restore();
+    result
+}
+
+impl Tui {
+    fn run(&mut self, terminal: &mut DefaultTerminal, session: &mut Session<impl LLMApi>) -> Result<(), Box<dyn Error>> {
+        loop {
+            let outcome = match session.metadata().user_control {
+                true => match self.read_input(terminal, session, State::WaitingForInput)? {
+                    Some(input) => session.step_with_input(&input)?.outcome, 
+                    None => return Ok(()), 
+                }, 
+                false => {
+                    terminal.draw(|frame| self.draw(frame, session, State::Working))?;
+                    // Keys pressed while the model or a command was busy are handled between steps
+                    while event::poll(Duration::ZERO)? {
+                        if let Event::Key(key) = event::read()? {
+                            if let Action::Quit = self.handle_key(key, false) {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    session.step()?.outcome
+                }, 
+            };
+
+            if outcome == StepOutcome::Finished {
+                // Keep the conversation on screen until the user leaves
+                self.read_input(terminal, session, State::Finished)?;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Shows the session until the user submits a line, or returns `None` if they quit.
+    /// Nothing can be submitted unless the session is waiting for input
+    fn read_input(&mut self, terminal: &mut DefaultTerminal, session: &Session<impl LLMApi>, state: State) -> Result<Option<String>, Box<dyn Error>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame, session, state))?;
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                match self.handle_key(key, state == State::WaitingForInput) {
+                    Action::Quit => return Ok(None), 
+                    Action::Submit => return Ok(Some(std::mem::take(&mut self.input))), 
+                    Action::None => {}, 
+                }
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, accept_input: bool) -> Action {
+        if key.kind != KeyEventKind::Press {
+            return Action::None;
+        }
+
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Action::Quit, 
+            KeyCode::Esc => return Action::Quit, 
+            KeyCode::Char('q') if !accept_input => return Action::Quit, 
+            KeyCode::Up => self.scroll_up(1), 
+            KeyCode::Down => self.scroll_down(1), 
+            KeyCode::PageUp => self.scroll_up(10), 
+            KeyCode::PageDown => self.scroll_down(10), 
+            KeyCode::Home => self.scroll_from_bottom = self.max_scroll, 
+            KeyCode::End => self.scroll_from_bottom = 0, 
+            KeyCode::Char(c) if accept_input => self.input.push(c), 
+            KeyCode::Backspace if accept_input => {
+                self.input.pop();
+            }, 
+            KeyCode::Enter if accept_input && !self.input.trim().is_empty() => return Action::Submit, 
+            _ => {}, 
+        }
+        Action::None
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        self.scroll_from_bottom = (self.scroll_from_bottom + n).min(self.max_scroll);
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        self.scroll_from_bottom = self.scroll_from_bottom.saturating_sub(n);
+    }
+
+    fn draw(&mut self, frame: &mut Frame, session: &Session<impl LLMApi>, state: State) {
+        let [conversation_area, status_area, input_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Length(3),
+        ]).areas(frame.area());
+
+        // Estimate the wrapped height to keep the view pinned to the end of the conversation
+        let text = conversation_text(session);
+        let width = conversation_area.width.saturating_sub(2).max(1) as usize;
+        let height = conversation_area.height.saturating_sub(2) as usize;
+        let n_lines: usize = text.lines
+            .iter()
+            .map(|line| line.width().max(1).div_ceil(width))
+            .sum();
+        self.max_scroll = n_lines.saturating_sub(height);
+        self.scroll_from_bottom = self.scroll_from_bottom.min(self.max_scroll);
+        let scroll = self.max_scroll - self.scroll_from_bottom;
+
+        let conversation = Paragraph::new(text)
+            .block(Block::bordered().title(" Conversation "))
+            .wrap(Wrap { trim: false })
+            .scroll((scroll.min(u16::MAX as usize) as u16, 0));
+        frame.render_widget(conversation, conversation_area);
+
+        let status = Paragraph::new(status_line(session, state))
+            .style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_widget(status, status_area);
+
+        let title = match state {
+            State::WaitingForInput => " Your response, Enter to send, start with # for a note ", 
+            State::Working => " Input ", 
+            State::Finished => " Session finished, q to quit ", 
+        };
+        let input = Paragraph::new(self.input.as_str())
+            .block(Block::bordered().title(title));
+        frame.render_widget(input, input_area);
+
+        if state == State::WaitingForInput {
+            let cursor_x = input_area.x + 1 + Line::raw(self.input.as_str()).width() as u16;
+            frame.set_cursor_position(Position::new(cursor_x.min(input_area.right().saturating_sub(2)), input_area.y + 1));
+        }
+    }
+}
+
+fn status_line(session: &Session<impl LLMApi>, state: State) -> String {
+    let model_info = session.llm().model_info();
+    let n_tokens = session.last_usage()
+        .map(|usage| usage.n_input_tokens + usage.n_output_tokens)
+        .unwrap_or(0);
+    let state = match state {
+        State::Working => "working", 
+        State::WaitingForInput => "waiting for input", 
+        State::Finished => "finished", 
+    };
+    let control = match session.metadata().user_control {
+        true => "user control", 
+        false => "agent control", 
+    };
+
+    format!(
+        " {} | tokens {}/{} | ${:.2} | step {} | {} | {} ",
+        model_info.name(),
+        n_tokens,
+        model_info.max_context_tokens(),
+        session.metadata().total_cost,
+        session.metadata().n_steps,
+        control,
+        state,
+    )
+}
+
+fn conversation_text(session: &Session<impl LLMApi>) -> Text<'static> {
+    let llm = session.llm();
+    let mut lines = Vec::new();
+
+    for msg in (0..llm.num_msgs()).filter_map(|id| llm.get_msg(id)) {
+        let (speaker, style) = match (msg.get_message().role, msg.kind()) {
+            _ if msg.is_local() => ("Note", Style::default().fg(Color::Yellow)), 
+            (Role::Assistant, MessageKind::Thought) => ("LLM thinks", Style::default().fg(Color::Gray).add_modifier(Modifier::ITALIC)), 
+            (Role::Assistant, _) => ("LLM", Style::default().fg(Color::Cyan)), 
+            (Role::User, _) => ("Terminal", Style::default().fg(Color::Green)), 
+            (Role::System, _) => ("System", Style::default().fg(Color::Magenta)), 
+        };
+        // Responses are shown as e.g. "LLM runs: ls" rather than their json
+        let response = msg.response().filter(|_| !session.options().raw_responses);
+        let (speaker, separator, content) = match response {
+            Some(response) => ("LLM", " ", response.describe()), 
+            None => (speaker, ": ", msg.get_message().content.to_string()), 
+        };
+        let (label, body_style) = match msg.is_masked() {
+            true => (format!("{} {speaker} [masked]{separator}", msg.id()), Style::default().add_modifier(Modifier::DIM)), 
+            false => (format!("{} {speaker}{separator}", msg.id()), Style::default()), 
+        };
+
+        let content = strip_control(&content);
+        let mut content_lines = content.lines();
+        lines.push(Line::from(vec![
+            Span::styled(label, style.add_modifier(Modifier::BOLD)),
+            Span::styled(content_lines.next().unwrap_or("").to_string(), body_style),
+        ]));
+        lines.extend(content_lines.map(|line| Line::styled(line.to_string(), body_style)));
+    }
+
+    Text::from(lines)
+}
+
+/// Removes escape sequences and control characters from terminal output so they do not garble the view
+fn strip_control(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                // Skip a CSI sequence up to its final byte, or the single character after ESC
+                if chars.next_if_eq(&'[').is_some() {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                } else {
+                    chars.next();
+                }
+            }, 
+            '\t' => out.push_str("    "), 
+            '\n' => out.push('\n'), 
+            c if c.is_control() => {}, 
+            c => out.push(c), 
+        }
+    }
+    out
+}
@@ -0,0 +1,160 @@
+mod common;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use serde_json::json;
+use agentic_terminal::anthropic::{AnthropicApi, Model};
+use agentic_terminal::http::Transport;
+use agentic_terminal::llm::{Content, ContentItem, LLMApi, Message, ModelInfo, Role, StopReason, StreamCallback};
+
+fn text(role: Role, text: &str) -> Message {
+    Message { role, content: Content::Single(ContentItem::Text(text.to_string())) }
+}
+
+#[test]
+fn cached_turns_mark_breakpoints_and_report_cache_reads() {
+    let (url, server) = common::serve_once(json!({
+        "id": "msg_1",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-3-5-sonnet-20241022",
+        "content": [{"type": "text", "text": "{\"Command\":\"ls\"}"}],
+        "stop_reason": "end_turn",
+        "stop_sequence": null,
+        "usage": {"input_tokens": 20, "output_tokens": 8, "cache_creation_input_tokens": 30, "cache_read_input_tokens": 2000},
+    }));
+
+    let api = AnthropicApi::new("sk-ant-test".to_string(), Model::Sonnet3_5)
+        .with_base_url(&url)
+        .with_prompt_cache();
+    let msgs = [
+        text(Role::User, "0>>"),
+        text(Role::Assistant, r#"{"Command":"pwd"}"#),
+        text(Role::User, "/root\n1>>"),
+    ];
+    let result = api.prompt("Test system prompt", msgs).unwrap();
+    let (request_line, request) = server.join().unwrap();
+
+    let breakpoint = json!({"type": "ephemeral"});
+    assert_eq!(request_line, "POST /v1/messages HTTP/1.1");
+    assert_eq!(request["system"], json!([{"type": "text", "text": "Test system prompt", "cache_control": breakpoint}]));
+    assert_eq!(request["messages"][0]["content"], json!([{"type": "text", "text": "0>>", "cache_control": breakpoint}]));
+    assert_eq!(request["messages"][1]["content"], json!(r#"{"Command":"pwd"}"#));
+    assert_eq!(request["messages"][2]["content"], json!([{"type": "text", "text": "/root\n1>>", "cache_control": breakpoint}]));
+
+    assert_eq!(result.usage.n_input_tokens, 2050);
+    assert_eq!(result.usage.cache_read_tokens, Some(2000));
+    assert_eq!(result.usage.cache_creation_tokens, Some(30));
+    // 20 full price tokens, 2000 at a tenth and 30 at a quarter more
+    let expected = ((20.0 + 200.0 + 37.5) * 3.0 + 8.0 * 15.0) / 1_000_000.0;
+    assert!((Model::Sonnet3_5.cost(&result.usage) - expected).abs() < 1e-12);
+}
+
+#[test]
+fn uncached_requests_send_plain_text() {
+    let (url, server) = common::serve_once(json!({
+        "id": "msg_1",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-3-5-sonnet-20241022",
+        "content": [{"type": "text", "text": "{\"Command\":\"ls\"}"}],
+        "stop_reason": "end_turn",
+        "stop_sequence": null,
+        "usage": {"input_tokens": 120, "output_tokens": 8},
+    }));
+
+    let api = AnthropicApi::new("sk-ant-test".to_string(), Model::Sonnet3_5).with_base_url(&url);
+    let result = api.prompt("Test system prompt", [text(Role::User, "0>>")]).unwrap();
+    let (_, request) = server.join().unwrap();
+
+    assert_eq!(request["system"], json!("Test system prompt"));
+    assert_eq!(request["messages"][0]["content"], json!("0>>"));
+    assert_eq!(result.usage.cache_read_tokens, None);
+}
+
+#[test]
+fn text_blocks_are_joined_in_order() {
+    let (url, server) = common::serve_once(json!({
+        "id": "msg_1",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-3-5-sonnet-20241022",
+        "content": [
+            {"type": "text", "text": "{\"Command\":"},
+            {"type": "tool_use", "id": "toolu_1", "name": "bash", "input": {}},
+            {"type": "text", "text": "\"ls -la\"}"},
+        ],
+        "stop_reason": "end_turn",
+        "stop_sequence": null,
+        "usage": {"input_tokens": 120, "output_tokens": 8},
+    }));
+
+    let api = AnthropicApi::new("sk-ant-test".to_string(), Model::Sonnet3_5).with_base_url(&url);
+    let result = api.prompt("Test system prompt", [text(Role::User, "0>>")]).unwrap();
+    server.join().unwrap();
+
+    assert_eq!(result.resp, r#"{"Command":"ls -la"}"#);
+}
+
+#[test]
+fn streamed_responses_are_assembled_from_their_deltas() {
+    let events = [
+        json!({"type": "message_start", "message": {"id": "msg_1", "type": "message", "role": "assistant", "model": "claude-3-5-sonnet-20241022", "content": [], "stop_reason": null, "stop_sequence": null, "usage": {"input_tokens": 120, "output_tokens": 1}}}),
+        json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+        json!({"type": "ping"}),
+        json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "{\"Command\":"}}),
+        json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "\"ls\"}"}}),
+        json!({"type": "content_block_stop", "index": 0}),
+        json!({"type": "message_delta", "delta": {"stop_reason": "end_turn", "stop_sequence": null}, "usage": {"output_tokens": 8}}),
+        json!({"type": "message_stop"}),
+    ];
+    let body: String = events.iter()
+        .map(|event| format!("event: {}\ndata: {event}\n\n", event["type"].as_str().unwrap()))
+        .collect();
+    let dir = std::env::temp_dir().join(format!("agentic_terminal_anthropic_stream_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let exchange = json!({"url": "https://api.anthropic.com/v1/messages", "request": {}, "status": 200, "response": body});
+    fs::write(dir.join("0000.json"), exchange.to_string()).unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let on_stream = {
+        let received = received.clone();
+        StreamCallback::new(move |text| received.lock().unwrap().push(text.map(str::to_string)))
+    };
+    let api = AnthropicApi::new("sk-ant-test".to_string(), Model::Sonnet3_5)
+        .with_streaming(on_stream)
+        .with_transport(Transport::replay(dir.clone()));
+    let result = api.prompt("Test system prompt", [text(Role::User, "0>>")]);
+    fs::remove_dir_all(&dir).unwrap();
+    let result = result.unwrap();
+
+    assert_eq!(result.resp, r#"{"Command":"ls"}"#);
+    assert!(matches!(result.stop_reason, StopReason::EndTurn));
+    assert_eq!(result.usage.n_input_tokens, 120);
+    assert_eq!(result.usage.n_output_tokens, 8);
+    assert_eq!(*received.lock().unwrap(), [Some(r#"{"Command":"#.to_string()), Some(r#""ls"}"#.to_string()), None]);
+}
+
+#[test]
+fn tool_use_ends_the_turn_with_the_text_so_far() {
+    let (url, server) = common::serve_once(json!({
+        "id": "msg_1",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-3-5-sonnet-20241022",
+        "content": [
+            {"type": "text", "text": "{\"Command\":\"ls\"}"},
+            {"type": "tool_use", "id": "toolu_1", "name": "bash", "input": {"command": "ls"}},
+        ],
+        "stop_reason": "tool_use",
+        "stop_sequence": null,
+        "usage": {"input_tokens": 120, "output_tokens": 30},
+    }));
+
+    let api = AnthropicApi::new("sk-ant-test".to_string(), Model::Sonnet3_5).with_base_url(&url);
+    let result = api.prompt("Test system prompt", [text(Role::User, "0>>")]).unwrap();
+    server.join().unwrap();
+
+    assert_eq!(result.resp, r#"{"Command":"ls"}"#);
+    assert!(matches!(result.stop_reason, StopReason::EndTurn));
+}
@@ -0,0 +1,278 @@
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::rc::Rc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde::Serialize;
+use agentic_terminal::terminal::Error;
+use agentic_terminal::anthropic;
+use agentic_terminal::llm::{ApiResponse, LLMApi, LLMApiError, Message, ModelInfo, StopReason, Usage, LLM};
+use agentic_terminal::session::{Session, SessionOptions};
+use agentic_terminal::terminal::{CommandOutput, Interruption, StreamChunk, Terminal, TerminalError, TerminalLike};
+
+/// Requests a `MockApi` received, each the messages that were sent
+pub type Requests = Rc<RefCell<Vec<Vec<Message>>>>;
+
+/// A backend that answers with scripted replies in order and records every request.
+/// Once the script runs out it fails with `LLMApiError::Other`
+#[derive(Serialize, Default)]
+pub struct MockApi {
+    #[serde(skip)]
+    replies: RefCell<VecDeque<Result<(String, StopReason), LLMApiError>>>,
+    #[serde(skip)]
+    requests: Requests,
+}
+
+impl MockApi {
+    /// Replies that each finish the model's turn
+    pub fn new<'a>(replies: impl IntoIterator<Item = &'a str>) -> Self {
+        Self::with_stop_reasons(replies.into_iter().map(|reply| (reply, StopReason::EndTurn)))
+    }
+
+    pub fn with_stop_reasons<'a>(replies: impl IntoIterator<Item = (&'a str, StopReason)>) -> Self {
+        Self {
+            replies: RefCell::new(replies.into_iter().map(|(reply, stop_reason)| Ok((reply.to_string(), stop_reason))).collect()),
+            requests: Requests::default(),
+        }
+    }
+
+    /// Replies that each finish the model's turn, or errors the backend fails with instead
+    pub fn with_errors<'a>(replies: impl IntoIterator<Item = Result<&'a str, LLMApiError>>) -> Self {
+        Self {
+            replies: RefCell::new(replies.into_iter().map(|reply| reply.map(|reply| (reply.to_string(), StopReason::EndTurn))).collect()),
+            requests: Requests::default(),
+        }
+    }
+
+    /// A handle to the recorded requests that outlives handing the backend to an `LLM`
+    pub fn requests(&self) -> Requests {
+        self.requests.clone()
+    }
+}
+
+impl LLMApi for MockApi {
+    fn prompt(&self, _system_msg: &str, msgs: impl IntoIterator<Item = Message>) -> Result<ApiResponse, LLMApiError> {
+        self.requests.borrow_mut().push(msgs.into_iter().collect());
+        let (resp, stop_reason) = self.replies.borrow_mut().pop_front().unwrap_or(Err(LLMApiError::Other))?;
+        Ok(
+            ApiResponse {
+                resp,
+                stop_reason,
+                usage: Usage {
+                    n_input_tokens: 100,
+                    n_output_tokens: 10,
+                    ..Usage::default()
+                },
+                alternatives: Vec::new(),
+            }
+        )
+    }
+
+    fn model_info(&self) -> &dyn ModelInfo {
+        &anthropic::Model::Haiku3_5
+    }
+}
+
+/// Options for sessions nobody watches
+pub fn quiet_options() -> SessionOptions {
+    SessionOptions {
+        echo: false,
+        non_interactive: true,
+        prompt_timeout: Duration::from_millis(100),
+        ..SessionOptions::default()
+    }
+}
+
+/// A session in a real shell answered by `api`
+pub fn session(api: MockApi, options: SessionOptions) -> (Session<MockApi>, Requests) {
+    let requests = api.requests();
+    let llm = LLM::new(api, "Test system prompt".to_string());
+    let terminal = Terminal::new().expect("Failed to start the shell");
+    (Session::new(llm, terminal, options), requests)
+}
+
+/// The text of every message in the session's context, masked or not, in order
+pub fn texts<T: TerminalLike>(session: &Session<MockApi, T>) -> Vec<String> {
+    (0..session.llm().num_msgs())
+        .filter_map(|id| session.llm().get_msg(id))
+        .map(|msg| String::try_from(&msg.get_message().content).unwrap_or_default())
+        .collect()
+}
+
+/// Commands a `MockTerminal` received, in order
+pub type Commands = Rc<RefCell<Vec<String>>>;
+
+/// What a `MockTerminal` does with the next command
+pub enum Scripted {
+    /// The command finishes with this output and exit code
+    Complete(&'static str, i32),
+    /// The command prints this, then nothing until it is interrupted
+    Partial(&'static str),
+    /// The shell exits while running the command, like under `set -e`
+    Exits,
+}
+
+/// A shell that answers commands with scripted results in order and records the commands.
+/// Once the script runs out every command succeeds without output
+pub struct MockTerminal {
+    script: VecDeque<Scripted>,
+    commands: Commands,
+    last_exit_code: Option<i32>,
+    is_alive: bool,
+    /// Output of the streamed command that has not been read yet
+    stream: Option<StreamChunk>,
+}
+
+impl MockTerminal {
+    pub fn new(script: impl IntoIterator<Item = Scripted>) -> Self {
+        Self {
+            script: script.into_iter().collect(),
+            commands: Commands::default(),
+            last_exit_code: None,
+            is_alive: true,
+            stream: None,
+        }
+    }
+
+    /// A handle to the recorded commands that outlives handing the terminal to a `Session`
+    pub fn commands(&self) -> Commands {
+        self.commands.clone()
+    }
+
+    /// Records the command and takes the next result of the script
+    fn next(&mut self, command: &str) -> Scripted {
+        self.commands.borrow_mut().push(command.to_string());
+        self.script.pop_front().unwrap_or(Scripted::Complete("", 0))
+    }
+}
+
+impl TerminalLike for MockTerminal {
+    fn run_command(&mut self, command: &str, _timeout: Duration) -> Result<CommandOutput, Box<dyn std::error::Error>> {
+        match self.next(command) {
+            Scripted::Complete(output, exit_code) => {
+                self.last_exit_code = Some(exit_code);
+                Ok(CommandOutput::Complete(output.to_string()))
+            },
+            Scripted::Partial(output) => {
+                self.last_exit_code = None;
+                Ok(CommandOutput::Partial(output.to_string()))
+            },
+            Scripted::Exits => {
+                self.is_alive = false;
+                Err("the shell exited".into())
+            },
+        }
+    }
+
+    fn start_command(&mut self, line: &str) -> Result<(), Error> {
+        self.stream = match self.next(line) {
+            Scripted::Complete(output, exit_code) => {
+                self.last_exit_code = Some(exit_code);
+                Some(StreamChunk::Done(output.to_string()))
+            },
+            Scripted::Partial(output) => Some(StreamChunk::Running(output.to_string())),
+            Scripted::Exits => {
+                self.is_alive = false;
+                None
+            },
+        };
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, _idle_timeout: Duration, _max_chars: usize) -> Result<StreamChunk, Error> {
+        match self.stream.take() {
+            Some(chunk) => Ok(chunk),
+            None if self.is_alive => Ok(StreamChunk::Running(String::new())),
+            // Only a conversion on unix, on Windows the terminal's errors are io errors
+            #[allow(clippy::useless_conversion)]
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    /// Input is scripted like a command, the next result is what the command prints after reading it
+    fn send_input(&mut self, line: &str) -> Result<(), Error> {
+        self.start_command(line)
+    }
+
+    fn interrupt(&mut self) -> Result<Interruption, Error> {
+        self.stream = None;
+        self.last_exit_code = None;
+        Ok(Interruption::CtrlC)
+    }
+
+    fn last_exit_code(&self) -> Option<i32> {
+        self.last_exit_code
+    }
+
+    fn is_alive(&self) -> bool {
+        self.is_alive
+    }
+
+    fn is_responsive(&mut self, _timeout: Duration) -> bool {
+        self.is_alive
+    }
+
+    fn restart(&mut self) -> Result<(), TerminalError> {
+        self.is_alive = true;
+        self.last_exit_code = None;
+        Ok(())
+    }
+}
+
+/// A session without a shell, its terminal answers with `terminal`'s script
+pub fn mock_session(api: MockApi, terminal: MockTerminal, options: SessionOptions) -> (Session<MockApi, MockTerminal>, Requests, Commands) {
+    let requests = api.requests();
+    let commands = terminal.commands();
+    let llm = LLM::new(api, "Test system prompt".to_string());
+    (Session::new(llm, terminal, options), requests, commands)
+}
+
+/// Answers a single HTTP request on a local port with `response` as json.
+/// Returns the server's URL and a handle to the request line and body it received
+pub fn serve_once(response: serde_json::Value) -> (String, JoinHandle<(String, serde_json::Value)>) {
+    let (url, server) = serve_bytes_once("application/json", response.to_string().into_bytes());
+    let server = thread::spawn(move || {
+        let (request_line, body) = server.join().unwrap();
+        (request_line, serde_json::from_slice(&body).unwrap())
+    });
+    (url, server)
+}
+
+/// Answers a single HTTP request on a local port with `body` as `content_type`.
+/// Returns the server's URL and a handle to the request line and body it received
+pub fn serve_bytes_once(content_type: &str, body: Vec<u8>) -> (String, JoinHandle<(String, Vec<u8>)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let content_type = content_type.to_string();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut request_body = vec![0; content_length];
+        reader.read_exact(&mut request_body).unwrap();
+
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+        stream.write_all(&body).unwrap();
+        (request_line.trim_end().to_string(), request_body)
+    });
+    (url, server)
+}
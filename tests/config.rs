@@ -0,0 +1,52 @@
+use std::path::Path;
+use agentic_terminal::config::{Config, ConfigError};
+
+#[test]
+fn every_setting_can_be_given() {
+    let config = Config::parse(r#"
+        api = "openai"
+        model = "gpt-4o-mini"
+        command_timeout = 10
+        log_dir = "/tmp/agentic_logs"
+        max_log_files = 50
+        max_log_age_days = 30
+
+        [anthropic]
+        api_key = "sk-ant-test"
+
+        [openai]
+        api_key = "sk-test"
+        base_url = "https://api.groq.com/openai/v1"
+
+        [gemini]
+        api_key = "gemini-test"
+
+        [ollama]
+        host = "http://gpu-box:11434"
+    "#).unwrap();
+
+    assert_eq!(config.api.as_deref(), Some("openai"));
+    assert_eq!(config.model.as_deref(), Some("gpt-4o-mini"));
+    assert_eq!(config.command_timeout, Some(10));
+    assert_eq!(config.log_dir.as_deref(), Some(Path::new("/tmp/agentic_logs")));
+    assert_eq!(config.max_log_files, Some(50));
+    assert_eq!(config.max_log_age_days, Some(30));
+    assert_eq!(config.anthropic.api_key.as_deref(), Some("sk-ant-test"));
+    assert_eq!(config.anthropic.base_url, None);
+    assert_eq!(config.openai.base_url.as_deref(), Some("https://api.groq.com/openai/v1"));
+    assert_eq!(config.gemini.api_key.as_deref(), Some("gemini-test"));
+    assert_eq!(config.ollama.host.as_deref(), Some("http://gpu-box:11434"));
+}
+
+#[test]
+fn misspelled_settings_are_errors() {
+    assert!(Config::parse("modle = \"gpt-4o\"").is_err());
+    assert!(Config::parse("[gemini]\nbase_url = \"http://localhost\"").is_err());
+}
+
+#[test]
+fn only_a_missing_file_that_was_asked_for_is_an_error() {
+    let path = std::env::temp_dir().join(format!("agentic_terminal_no_config_{}.toml", std::process::id()));
+
+    assert!(matches!(Config::load(Some(&path)), Err(ConfigError::Io(..))));
+}
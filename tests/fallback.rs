@@ -0,0 +1,61 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use serde_json::json;
+use agentic_terminal::fallback::{AnyApi, FallbackApi};
+use agentic_terminal::http::Transport;
+use agentic_terminal::llm::{LLMApiError, Message, Role, LLM};
+use agentic_terminal::openai::{Model, OAIApi};
+
+/// An OpenAI backend that replays a single answer with `status` and `response`
+fn replaying_api(name: &str, status: u16, response: serde_json::Value) -> (AnyApi, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("agentic_terminal_fallback_{name}_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let exchange = json!({"url": "https://api.openai.com/v1/chat/completions", "request": {}, "status": status, "response": response.to_string()});
+    fs::write(dir.join("0000.json"), exchange.to_string()).unwrap();
+
+    let api = OAIApi::new("sk-test".to_string(), Model::GPT4O).with_transport(Transport::replay(dir.clone()));
+    (AnyApi::OpenAI(api), dir)
+}
+
+fn completion(content: &str) -> serde_json::Value {
+    json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 1736000000,
+        "model": "gpt-4o-2024-08-06",
+        "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}],
+        "usage": {"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15},
+    })
+}
+
+/// Prompts a chain of `primary` then `fallback` once
+fn prompt_chain(primary: (AnyApi, PathBuf), fallback: (AnyApi, PathBuf)) -> Result<String, LLMApiError> {
+    let api = FallbackApi::new(primary.0, vec![fallback.0]);
+    let mut llm = LLM::new(api, "Test system prompt".to_string());
+    llm.add_msg(Message { role: Role::User, content: "0>>".into() });
+    let result = llm.prompt_text(Duration::from_secs(5)).map(|(text, _)| text);
+    fs::remove_dir_all(&primary.1).unwrap();
+    fs::remove_dir_all(&fallback.1).unwrap();
+    result
+}
+
+#[test]
+fn server_errors_fall_back_to_the_next_backend() {
+    let primary = replaying_api("server_error", 500, json!({"error": {"message": "The server had an error", "type": "server_error"}}));
+    let fallback = replaying_api("server_error_fallback", 200, completion("from the fallback"));
+
+    let result = prompt_chain(primary, fallback).unwrap();
+
+    assert_eq!(result, "from the fallback");
+}
+
+#[test]
+fn authentication_errors_do_not_fall_back() {
+    let primary = replaying_api("auth", 401, json!({"error": {"message": "Incorrect API key provided", "type": "invalid_request_error"}}));
+    let fallback = replaying_api("auth_fallback", 200, completion("from the fallback"));
+
+    let result = prompt_chain(primary, fallback);
+
+    assert!(matches!(result, Err(LLMApiError::AuthenticationError)), "{result:?}");
+}
@@ -0,0 +1,67 @@
+use std::fs;
+use serde_json::json;
+use agentic_terminal::gemini::{GeminiApi, GeminiContent, Model};
+use agentic_terminal::http::Transport;
+use agentic_terminal::llm::{ApiResponse, Content, ContentItem, Image, ImageType, LLMApi, LLMApiError, Message, Role, StopReason};
+
+/// Replays `response` as the answer to a single prompt
+fn prompt_with_response(name: &str, response: serde_json::Value) -> Result<ApiResponse, LLMApiError> {
+    let dir = std::env::temp_dir().join(format!("agentic_terminal_gemini_{name}_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let exchange = json!({"url": "https://generativelanguage.googleapis.com", "request": {}, "status": 200, "response": response.to_string()});
+    fs::write(dir.join("0000.json"), exchange.to_string()).unwrap();
+
+    let api = GeminiApi::new("test-key".to_string(), Model::Gemini1_5Flash).with_transport(Transport::replay(dir.clone()));
+    let msg = Message { role: Role::User, content: Content::Single(ContentItem::Text("0>>".to_string())) };
+    let result = api.prompt("Test system prompt", [msg]);
+    fs::remove_dir_all(&dir).unwrap();
+    result
+}
+
+#[test]
+fn messages_serialize_to_contents_and_parts() {
+    let msg = Message {
+        role: Role::Assistant,
+        content: Content::Multiple(vec![
+            ContentItem::Text("look".to_string()),
+            ContentItem::Image(Image { image_type: ImageType::Jpeg, data: "AAAA".to_string() }),
+        ]),
+    };
+
+    assert_eq!(serde_json::to_value(GeminiContent::from(msg)).unwrap(), json!({
+        "role": "model",
+        "parts": [{"text": "look"}, {"inlineData": {"mimeType": "image/jpeg", "data": "AAAA"}}],
+    }));
+}
+
+#[test]
+fn responses_are_read_into_the_trait_types() {
+    let result = prompt_with_response("ok", json!({
+        "candidates": [{"content": {"role": "model", "parts": [{"text": "{\"Command\":"}, {"text": "\"ls\"}"}]}, "finishReason": "MAX_TOKENS"}],
+        "usageMetadata": {"promptTokenCount": 120, "candidatesTokenCount": 8, "totalTokenCount": 128},
+    })).unwrap();
+
+    assert_eq!(result.resp, r#"{"Command":"ls"}"#);
+    assert!(matches!(result.stop_reason, StopReason::MaxTokens));
+    assert_eq!(result.usage.n_input_tokens, 120);
+    assert_eq!(result.usage.n_output_tokens, 8);
+}
+
+#[test]
+fn blocked_responses_are_content_filtered() {
+    let result = prompt_with_response("blocked", json!({
+        "candidates": [{"finishReason": "SAFETY"}],
+        "usageMetadata": {"promptTokenCount": 120, "totalTokenCount": 120},
+    }));
+
+    assert!(matches!(result, Err(LLMApiError::ContentFiltered)));
+}
+
+#[test]
+fn errors_map_to_api_errors() {
+    let result = prompt_with_response("error", json!({
+        "error": {"code": 429, "message": "Resource has been exhausted", "status": "RESOURCE_EXHAUSTED"},
+    }));
+
+    assert!(matches!(result, Err(LLMApiError::RateLimitExceeded)));
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use std::path::PathBuf;
+use std::time::Duration;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use agentic_terminal::llm::{Image, ImageLoadError, ImageType};
+use common::serve_bytes_once;
+
+/// Writes a blank PNG of the given size to a temporary file
+fn blank_png(name: &str, width: u32, height: u32) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("agentic_terminal_{name}_{}.png", std::process::id()));
+    image::RgbImage::new(width, height).save(&path).unwrap();
+    path
+}
+
+fn dimensions(image: &Image) -> (u32, u32) {
+    let bytes = BASE64.decode(&image.data).unwrap();
+    let image = image::load_from_memory(&bytes).unwrap();
+    (image.width(), image.height())
+}
+
+#[test]
+fn large_images_are_scaled_to_the_max_dimension() {
+    let path = blank_png("large", 400, 300);
+
+    let image = Image::from_file_scaled(&path, 100).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(image.image_type, ImageType::Png));
+    assert_eq!(dimensions(&image), (100, 75));
+}
+
+#[test]
+fn small_images_are_sent_as_they_are() {
+    let path = blank_png("small", 80, 60);
+
+    let scaled = Image::from_file_scaled(&path, 100).unwrap();
+    let original = Image::from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(scaled.data, original.data);
+}
+
+#[test]
+fn the_type_comes_from_the_contents_rather_than_the_extension() {
+    let path = std::env::temp_dir().join(format!("agentic_terminal_mislabeled_{}.png", std::process::id()));
+    image::RgbImage::new(8, 8).save_with_format(&path, image::ImageFormat::Jpeg).unwrap();
+    let png = blank_png("extensionless", 8, 8);
+    let screenshot = png.with_extension("");
+    std::fs::rename(&png, &screenshot).unwrap();
+
+    let mislabeled = Image::from_file(&path).map(|image| image.image_type);
+    let extensionless = Image::from_file(&screenshot).map(|image| image.image_type);
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&screenshot).unwrap();
+
+    assert!(matches!(mislabeled, Ok(ImageType::Jpeg)));
+    assert!(matches!(extensionless, Ok(ImageType::Png)));
+}
+
+#[test]
+fn signatures_of_every_supported_format_are_known() {
+    assert!(matches!(ImageType::from_magic_bytes(b"GIF89a\x01\x00"), Some(ImageType::Gif)));
+    assert!(matches!(ImageType::from_magic_bytes(b"GIF87a\x01\x00"), Some(ImageType::Gif)));
+    assert!(matches!(ImageType::from_magic_bytes(b"RIFF\x24\x00\x00\x00WEBPVP8 "), Some(ImageType::Webp)));
+    assert!(ImageType::from_magic_bytes(b"RIFF\x24\x00\x00\x00WAVEfmt ").is_none());
+    assert!(ImageType::from_magic_bytes(b"\x89PN").is_none());
+}
+
+#[test]
+fn images_are_downloaded_from_urls() {
+    let path = blank_png("served", 8, 8);
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let (url, server) = serve_bytes_once("application/octet-stream", bytes.clone());
+
+    let image = Image::from_url(&format!("{url}/shot.jpg"), 1024 * 1024, Duration::from_secs(10)).unwrap();
+
+    assert_eq!(server.join().unwrap().0, "GET /shot.jpg HTTP/1.1");
+    assert!(matches!(image.image_type, ImageType::Png));
+    assert_eq!(BASE64.decode(&image.data).unwrap(), bytes);
+}
+
+#[test]
+fn downloads_over_the_limit_are_refused() {
+    let (url, server) = serve_bytes_once("image/png", vec![0; 2048]);
+
+    let result = Image::from_url(&url, 1024, Duration::from_secs(10));
+
+    server.join().unwrap();
+    assert!(matches!(result, Err(ImageLoadError::DownloadTooLarge { max_bytes: 1024 })));
+}
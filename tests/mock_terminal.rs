@@ -0,0 +1,440 @@
+mod common;
+
+use agentic_terminal::cache::OutputCache;
+use std::io::{self, BufRead};
+use agentic_terminal::policy::{confirm_hook, pattern_hook, SudoPolicy};
+use agentic_terminal::llm::LLMApiError;
+use agentic_terminal::session::{SessionError, SessionOptions, StepOutcome};
+use common::{mock_session, quiet_options, texts, MockApi, MockTerminal, Scripted};
+
+#[test]
+fn commands_reach_the_terminal_in_order() {
+    let api = MockApi::new([r#"{"Command":"make"}"#, r#"{"Command":"make install"}"#, r#""Exit""#]);
+    let terminal = MockTerminal::new([Scripted::Complete("built", 0), Scripted::Complete("installed", 0)]);
+    let (mut session, requests, commands) = mock_session(api, terminal, quiet_options());
+
+    session.run().unwrap();
+
+    assert_eq!(*commands.borrow(), ["make", "make install"]);
+    assert_eq!(requests.borrow().len(), 3);
+    let texts = texts(&session);
+    assert!(texts.iter().any(|text| text == "built"));
+    assert!(texts.iter().any(|text| text == "installed"));
+}
+
+#[test]
+fn step_result_reports_the_exit_code() {
+    let api = MockApi::new([r#"{"Command":"grep -q needle haystack"}"#]);
+    let terminal = MockTerminal::new([Scripted::Complete("", 1)]);
+    let (mut session, _, _) = mock_session(api, terminal, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(result.command.as_deref(), Some("grep -q needle haystack"));
+    assert_eq!(result.exit_code, Some(1));
+    assert_eq!(result.outcome, StepOutcome::Continue);
+}
+
+#[test]
+fn conditional_follows_the_exit_code() {
+    let api = MockApi::new([r#"{"Conditional":{"command":"test -f done","on_success":"cat done","on_failure":"touch done"}}"#]);
+    let terminal = MockTerminal::new([Scripted::Complete("", 1), Scripted::Complete("", 0)]);
+    let (mut session, _, commands) = mock_session(api, terminal, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(*commands.borrow(), ["test -f done", "touch done"]);
+    assert_eq!(result.exit_code, Some(0));
+    assert!(texts(&session).iter().any(|text| text == "Exit code 1, running: touch done"));
+}
+
+#[test]
+fn conditional_without_a_matching_branch_stops() {
+    let api = MockApi::new([r#"{"Conditional":{"command":"cargo build","on_success":"cargo test"}}"#]);
+    let terminal = MockTerminal::new([Scripted::Complete("error[E0308]", 101)]);
+    let (mut session, _, commands) = mock_session(api, terminal, quiet_options());
+
+    session.step().unwrap();
+
+    assert_eq!(*commands.borrow(), ["cargo build"]);
+}
+
+#[test]
+fn partial_output_has_no_exit_code() {
+    let api = MockApi::new([r#"{"Command":"cargo build"}"#]);
+    let terminal = MockTerminal::new([Scripted::Partial("Compiling serde")]);
+    let (mut session, _, _) = mock_session(api, terminal, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(result.exit_code, None);
+    assert_eq!(result.output.as_deref(), Some("Compiling serde"));
+    let msg = texts(&session).last().cloned().unwrap();
+    assert!(msg.starts_with("Partial output"), "{msg}");
+    assert!(msg.ends_with("Compiling serde"), "{msg}");
+}
+
+#[test]
+fn several_responses_in_one_reply_run_in_order() {
+    let api = MockApi::new([r#"{"Command":"mkdir out"}{"Command":"ls out"}"#]);
+    let (mut session, _, commands) = mock_session(api, MockTerminal::new([]), quiet_options());
+
+    session.step().unwrap();
+
+    assert_eq!(*commands.borrow(), ["mkdir out", "ls out"]);
+    assert!(texts(&session).last().unwrap().contains("held 2 json objects"));
+}
+
+#[test]
+fn denied_commands_never_reach_the_terminal() {
+    let api = MockApi::new([r#"{"Command":"rm -rf /"}"#, r#"{"Command":"ls"}"#]);
+    let options = SessionOptions {
+        command_hook: Some(pattern_hook(Vec::new(), vec!["rm -rf".to_string()])),
+        ..quiet_options()
+    };
+    let (mut session, _, commands) = mock_session(api, MockTerminal::new([]), options);
+
+    let result = session.step().unwrap();
+    assert!(result.command.is_none());
+    assert!(texts(&session).last().unwrap().contains("Command blocked"));
+
+    session.step().unwrap();
+    assert_eq!(*commands.borrow(), ["ls"]);
+}
+
+#[test]
+fn confirmed_commands_can_be_rejected_or_edited() {
+    let api = MockApi::new([r#"{"Command":"rm -rf /"}"#, r#"{"Command":"make"}"#, r#"{"Command":"ls"}"#]);
+    let mut input = io::Cursor::new("n\nit deletes everything\ne\nmake -j4\ny\n");
+    let options = SessionOptions {
+        command_hook: Some(confirm_hook(move |line: &mut String| input.read_line(line), io::sink())),
+        ..quiet_options()
+    };
+    let (mut session, _, commands) = mock_session(api, MockTerminal::new([]), options);
+
+    session.step().unwrap();
+    assert!(commands.borrow().is_empty());
+    assert_eq!(texts(&session).last().unwrap(), "The user rejected the command: it deletes everything");
+
+    session.step().unwrap();
+    session.step().unwrap();
+    assert_eq!(*commands.borrow(), ["make -j4", "ls"]);
+}
+
+#[test]
+fn sudo_is_made_non_interactive() {
+    let api = MockApi::new([r#"{"Command":"sudo apt-get update"}"#]);
+    let (mut session, _, commands) = mock_session(api, MockTerminal::new([]), quiet_options());
+
+    session.step().unwrap();
+
+    assert_eq!(*commands.borrow(), ["sudo -n apt-get update"]);
+}
+
+#[test]
+fn rejected_sudo_is_not_run() {
+    let api = MockApi::new([r#"{"Command":"sudo reboot"}"#]);
+    let options = SessionOptions {
+        sudo_policy: SudoPolicy::Reject,
+        ..quiet_options()
+    };
+    let (mut session, _, commands) = mock_session(api, MockTerminal::new([]), options);
+
+    session.step().unwrap();
+
+    assert!(commands.borrow().is_empty());
+}
+
+#[test]
+fn cached_commands_run_once_until_something_changes() {
+    let api = MockApi::new([
+        r#"{"Command":"ls"}"#,
+        r#"{"Command":"ls"}"#,
+        r#"{"Command":"touch new"}"#,
+        r#"{"Command":"ls"}"#,
+    ]);
+    let terminal = MockTerminal::new([
+        Scripted::Complete("old", 0),
+        Scripted::Complete("", 0),
+        Scripted::Complete("new\nold", 0),
+    ]);
+    let options = SessionOptions {
+        cached_commands: Some(OutputCache::default_read_only()),
+        ..quiet_options()
+    };
+    let (mut session, _, commands) = mock_session(api, terminal, options);
+
+    session.step().unwrap();
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("old"));
+    assert!(texts(&session).last().unwrap().contains("(cached"));
+
+    session.step().unwrap();
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("new\nold"));
+    assert_eq!(*commands.borrow(), ["ls", "touch new", "ls"]);
+}
+
+#[test]
+fn cached_commands_keep_their_exit_code() {
+    let api = MockApi::new([
+        r#"{"Command":"cat missing"}"#,
+        r#"{"Command":"cat missing"}"#,
+        r#"{"Command":"cat done"}"#,
+        r#"{"Conditional":{"command":"cat done","on_success":"echo yes","on_failure":"echo no"}}"#,
+    ]);
+    let terminal = MockTerminal::new([
+        Scripted::Complete("cat: missing: No such file or directory", 1),
+        Scripted::Complete("missing", 0),
+        Scripted::Complete("done", 0),
+    ]);
+    let options = SessionOptions {
+        cached_commands: Some(OutputCache::default_read_only()),
+        ..quiet_options()
+    };
+    let (mut session, _, commands) = mock_session(api, terminal, options);
+
+    for _ in 0..4 {
+        session.step().unwrap();
+    }
+
+    assert_eq!(*commands.borrow(), ["cat missing", "cat missing", "cat done", "echo yes"]);
+}
+
+#[test]
+fn numbered_output() {
+    let api = MockApi::new([r#"{"Command":"cat list"}"#]);
+    let terminal = MockTerminal::new([Scripted::Complete("a\nb", 0)]);
+    let options = SessionOptions {
+        number_output: true,
+        ..quiet_options()
+    };
+    let (mut session, _, _) = mock_session(api, terminal, options);
+
+    let result = session.step().unwrap();
+
+    // The caller gets the output as printed, only the model sees the numbers
+    assert_eq!(result.output.as_deref(), Some("a\nb"));
+    assert_eq!(texts(&session).last().unwrap(), "1\ta\n2\tb");
+}
+
+#[test]
+fn exited_shell_is_restarted() {
+    let api = MockApi::new([r#"{"Command":"false"}"#, r#"{"Command":"echo again"}"#]);
+    let terminal = MockTerminal::new([Scripted::Exits, Scripted::Complete("again", 0)]);
+    let (mut session, _, _) = mock_session(api, terminal, quiet_options());
+
+    let result = session.step().unwrap();
+    assert_eq!(result.outcome, StepOutcome::Continue);
+    assert!(texts(&session).last().unwrap().starts_with("The shell exited"));
+
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("again"));
+}
+
+#[test]
+fn streamed_output_is_shown_when_done() {
+    let api = MockApi::new([r#"{"Stream":"cargo test"}"#]);
+    let terminal = MockTerminal::new([Scripted::Complete("test result: ok", 0)]);
+    let (mut session, _, commands) = mock_session(api, terminal, quiet_options());
+
+    session.step().unwrap();
+
+    assert_eq!(*commands.borrow(), ["cargo test"]);
+    assert!(texts(&session).iter().any(|text| text.contains("test result: ok")));
+}
+
+#[test]
+fn large_outputs_are_masked_after_the_retention_window() {
+    let big = "x".repeat(4000);
+    let big: &'static str = Box::leak(big.into_boxed_str());
+    let api = MockApi::new([
+        r#"{"Command":"cat big"}"#,
+        r#"{"Command":"wc -c big"}"#,
+        r#"{"Command":"cat big"}"#,
+        r#"{"Retain":7}"#,
+        r#"{"Command":"true"}"#,
+        r#"{"Command":"true"}"#,
+    ]);
+    let terminal = MockTerminal::new([Scripted::Complete(big, 0), Scripted::Complete("4000 big", 0), Scripted::Complete(big, 0)]);
+    let options = SessionOptions {
+        auto_mask_over: Some(500),
+        ..quiet_options()
+    };
+    let (mut session, _, _) = mock_session(api, terminal, options);
+
+    // The model gets one turn with the output before it is masked
+    session.step().unwrap();
+    assert!(!session.llm().get_msg(2).unwrap().is_masked());
+    session.step().unwrap();
+    assert!(session.llm().get_msg(2).unwrap().is_masked());
+
+    // Unless it retains it in that turn
+    session.step().unwrap();
+    let id = session.llm().num_msgs() - 1;
+    assert_eq!(id, 7);
+    for _ in 0..3 {
+        session.step().unwrap();
+    }
+    assert!(!session.llm().get_msg(id).unwrap().is_masked());
+}
+
+#[test]
+fn failing_commands_show_their_exit_code() {
+    let api = MockApi::new([r#"{"Command":"grep needle haystack"}"#, r#"{"Command":"echo found"}"#]);
+    let terminal = MockTerminal::new([Scripted::Complete("", 1), Scripted::Complete("found", 0)]);
+    let (mut session, _, _) = mock_session(api, terminal, quiet_options());
+
+    session.step().unwrap();
+    assert_eq!(texts(&session).last().unwrap(), "(exit code 1)");
+
+    session.step().unwrap();
+    assert_eq!(texts(&session).last().unwrap(), "found");
+}
+
+#[test]
+fn input_answers_a_streamed_prompt() {
+    let api = MockApi::new([r#"{"Stream":"read -p 'Name: ' name; echo hi $name"}"#, r#"{"Input":"Alice"}"#]);
+    let terminal = MockTerminal::new([Scripted::Partial("Name: "), Scripted::Complete("hi Alice", 0)]);
+    let (mut session, _, commands) = mock_session(api, terminal, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(*commands.borrow(), ["read -p 'Name: ' name; echo hi $name", "Alice"]);
+    assert_eq!(result.exit_code, Some(0));
+    assert_eq!(texts(&session).last().unwrap(), "hi Alice");
+}
+
+#[test]
+fn context_warning_counts_output_the_model_has_not_seen() {
+    let api = MockApi::new([r#"{"Command":"cat huge.log"}"#]);
+    let huge: &'static str = "x".repeat(800_000).leak();
+    let terminal = MockTerminal::new([Scripted::Complete(huge, 0)]);
+    let options = SessionOptions { max_message_bytes: None, ..quiet_options() };
+    let (mut session, _, _) = mock_session(api, terminal, options);
+
+    session.step().unwrap();
+
+    assert!(session.llm().estimated_prompt_tokens() > 180_000);
+    assert!(texts(&session).iter().any(|text| text.contains("over 90% of token context is used")));
+}
+
+#[test]
+fn auto_compact_masks_old_output_before_the_request_overflows() {
+    let api = MockApi::new([r#"{"Command":"cat old.log"}"#, r#"{"Command":"cat new.log"}"#, r#""Exit""#]);
+    let old: &'static str = "o".repeat(300_000).leak();
+    let new: &'static str = "n".repeat(200_000).leak();
+    let terminal = MockTerminal::new([Scripted::Complete(old, 0), Scripted::Complete(new, 0)]);
+    let options = SessionOptions { max_message_bytes: None, auto_compact: Some(0.5), ..quiet_options() };
+    let (mut session, requests, _) = mock_session(api, terminal, options);
+
+    session.run().unwrap();
+
+    let last_request = requests.borrow().last().unwrap().iter()
+        .filter_map(|msg| String::try_from(&msg.content).ok())
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert!(!last_request.contains(old));
+    assert!(last_request.contains(new));
+    assert!(last_request.contains("automatically masked messages"));
+}
+
+#[test]
+fn max_iterations_stops_a_looping_model() {
+    let api = MockApi::new([r#"{"Command":"make"}"#, r#"{"Command":"make"}"#, r#"{"Command":"make"}"#]);
+    let terminal = MockTerminal::new([Scripted::Complete("error", 2), Scripted::Complete("error", 2)]);
+    let options = SessionOptions { max_iterations: Some(2), ..quiet_options() };
+    let (mut session, requests, commands) = mock_session(api, terminal, options);
+
+    let result = session.run();
+
+    assert!(matches!(result, Err(SessionError::IterationLimit(2))));
+    assert_eq!(requests.borrow().len(), 2);
+    assert_eq!(commands.borrow().len(), 2);
+    assert!(texts(&session).iter().any(|text| text.contains("the session is stopped")));
+}
+
+#[test]
+fn max_iterations_counts_turns_while_streaming() {
+    let api = MockApi::new([r#"{"Stream":"tail -f log"}"#, r#""Continue""#, r#""Continue""#]);
+    let terminal = MockTerminal::new([Scripted::Partial("line")]);
+    let options = SessionOptions { max_iterations: Some(2), ..quiet_options() };
+    let (mut session, requests, _) = mock_session(api, terminal, options);
+
+    let result = session.run();
+
+    assert!(matches!(result, Err(SessionError::IterationLimit(2))));
+    assert_eq!(requests.borrow().len(), 2);
+}
+
+#[test]
+fn empty_replies_while_streaming_are_retried() {
+    // The backend retries an empty reply once before it is an error
+    let api = MockApi::new([r#"{"Stream":"tail -f log"}"#, "", "", r#""Stop""#]);
+    let terminal = MockTerminal::new([Scripted::Partial("line")]);
+    let (mut session, requests, _) = mock_session(api, terminal, quiet_options());
+
+    session.step().unwrap();
+
+    assert_eq!(requests.borrow().len(), 4);
+    let texts = texts(&session);
+    assert!(texts.iter().any(|text| text.starts_with("The model returned no content")));
+    assert_eq!(texts.last().unwrap(), "Command interrupted.");
+}
+
+#[test]
+fn filtered_replies_while_streaming_are_retried() {
+    let api = MockApi::with_errors([Ok(r#"{"Stream":"tail -f log"}"#), Err(LLMApiError::ContentFiltered), Ok(r#""Stop""#)]);
+    let terminal = MockTerminal::new([Scripted::Partial("line")]);
+    let options = SessionOptions { filter_retries: 1, ..quiet_options() };
+    let (mut session, requests, _) = mock_session(api, terminal, options);
+
+    session.step().unwrap();
+
+    assert_eq!(requests.borrow().len(), 3);
+    let texts = texts(&session);
+    assert!(texts.iter().any(|text| text.starts_with("Your response was filtered")));
+    assert_eq!(texts.last().unwrap(), "Command interrupted.");
+}
+
+#[test]
+fn empty_replies_count_against_max_iterations() {
+    // The backend retries an empty reply once before it is an error
+    let api = MockApi::with_errors([Err(LLMApiError::EmptyResponse), Err(LLMApiError::EmptyResponse), Err(LLMApiError::EmptyResponse), Err(LLMApiError::EmptyResponse), Ok(r#""Exit""#)]);
+    let terminal = MockTerminal::new([]);
+    let options = SessionOptions { max_iterations: Some(2), ..quiet_options() };
+    let (mut session, requests, _) = mock_session(api, terminal, options);
+
+    let result = session.run();
+
+    assert!(matches!(result, Err(SessionError::IterationLimit(2))));
+    assert_eq!(requests.borrow().len(), 4);
+}
+
+#[test]
+fn sessions_stopped_by_max_iterations_are_summarized() {
+    let api = MockApi::new([r#"{"Command":"make"}"#, "Ran make, it failed."]);
+    let terminal = MockTerminal::new([Scripted::Complete("error", 2)]);
+    let options = SessionOptions { max_iterations: Some(1), summarize_on_exit: true, ..quiet_options() };
+    let (mut session, _, _) = mock_session(api, terminal, options);
+
+    let result = session.run();
+
+    assert!(matches!(result, Err(SessionError::IterationLimit(1))));
+    assert_eq!(session.metadata().summary.as_deref(), Some("Ran make, it failed."));
+}
+
+#[test]
+fn dry_run_reports_commands_without_running_them() {
+    let api = MockApi::new([r#"{"Command":"rm -rf build"}"#, r#"{"Stream":"make"}"#, r#""Exit""#]);
+    let terminal = MockTerminal::new([]);
+    let options = SessionOptions { dry_run: true, ..quiet_options() };
+    let (mut session, _, commands) = mock_session(api, terminal, options);
+
+    session.run().unwrap();
+
+    assert!(commands.borrow().is_empty());
+    let texts = texts(&session);
+    assert!(texts.iter().any(|text| text == "[dry-run] command not executed: rm -rf build"));
+    assert!(texts.iter().any(|text| text == "[dry-run] command not executed: make"));
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use serde_json::json;
+use agentic_terminal::http::Transport;
+use agentic_terminal::llm::{ApiResponse, Content, ContentItem, Image, ImageType, LLMApi, LLMApiError, Message, ModelInfo, Role, StopReason, Usage};
+use agentic_terminal::ollama::{Model, OllamaApi, OllamaMessage};
+
+/// Replays `response` with `status` as the answer to a single prompt
+fn prompt_with_response(name: &str, status: u16, response: serde_json::Value) -> Result<ApiResponse, LLMApiError> {
+    let dir = std::env::temp_dir().join(format!("agentic_terminal_ollama_{name}_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let exchange = json!({"url": "http://localhost:11434/api/chat", "request": {}, "status": status, "response": response.to_string()});
+    fs::write(dir.join("0000.json"), exchange.to_string()).unwrap();
+
+    let api = OllamaApi::new(Model::new("llama3.1", 8192)).with_transport(Transport::replay(dir.clone()));
+    let msg = Message { role: Role::User, content: Content::Single(ContentItem::Text("0>>".to_string())) };
+    let result = api.prompt("Test system prompt", [msg]);
+    fs::remove_dir_all(&dir).unwrap();
+    result
+}
+
+#[test]
+fn images_are_sent_beside_the_text() {
+    let msg = Message {
+        role: Role::User,
+        content: Content::Multiple(vec![
+            ContentItem::Text("look".to_string()),
+            ContentItem::Image(Image { image_type: ImageType::Png, data: "AAAA".to_string() }),
+        ]),
+    };
+
+    assert_eq!(serde_json::to_value(OllamaMessage::from(msg)).unwrap(), json!({
+        "role": "user",
+        "content": "look",
+        "images": ["AAAA"],
+    }));
+}
+
+#[test]
+fn system_messages_keep_their_role() {
+    let msg = Message { role: Role::System, content: Content::Single(ContentItem::Text("Be brief".to_string())) };
+
+    assert_eq!(serde_json::to_value(OllamaMessage::from(msg)).unwrap(), json!({
+        "role": "system",
+        "content": "Be brief",
+    }));
+}
+
+#[test]
+fn responses_are_read_into_the_trait_types() {
+    let result = prompt_with_response("ok", 200, json!({
+        "model": "llama3.1",
+        "created_at": "2025-01-05T12:00:00Z",
+        "message": {"role": "assistant", "content": "{\"Command\":\"ls\"}"},
+        "done": true,
+        "done_reason": "stop",
+        "prompt_eval_count": 120,
+        "eval_count": 8,
+    })).unwrap();
+
+    assert_eq!(result.resp, r#"{"Command":"ls"}"#);
+    assert!(matches!(result.stop_reason, StopReason::EndTurn));
+    assert_eq!(result.usage.n_input_tokens, 120);
+    assert_eq!(result.usage.n_output_tokens, 8);
+}
+
+#[test]
+fn missing_models_are_not_found_errors() {
+    let result = prompt_with_response("missing", 404, json!({"error": "model \"llama3.1\" not found, try pulling it first"}));
+
+    assert!(matches!(result, Err(LLMApiError::NotFoundError)));
+}
+
+#[test]
+fn the_context_size_is_configurable() {
+    let model = Model::new("qwen2.5-coder:32b", 32768);
+
+    assert_eq!(model.name(), "qwen2.5-coder:32b");
+    assert_eq!(model.max_context_tokens(), 32768);
+    assert_eq!(model.cost(&Usage { n_input_tokens: 1000, n_output_tokens: 1000, ..Usage::default() }), 0.0);
+}
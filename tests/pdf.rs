@@ -0,0 +1,60 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use agentic_terminal::llm::{ContentItem, Image};
+use agentic_terminal::session::SessionOptions;
+use common::{mock_session, quiet_options, texts, MockApi, MockTerminal};
+
+/// A fake pdftoppm, so poppler is not needed. It renders a three page document as blank PNGs
+/// named the way pdftoppm names them
+fn fake_renderer() -> &'static PathBuf {
+    static SCRIPT: OnceLock<PathBuf> = OnceLock::new();
+    SCRIPT.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("agentic_terminal_fake_poppler_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("blank.png");
+        image::RgbImage::new(85, 110).save(&page).unwrap();
+
+        // Called as pdftoppm -png -r 100 -f FIRST -l LAST input prefix
+        let script = dir.join("pdftoppm");
+        fs::write(&script, format!(
+            "#!/bin/sh\ni=$5\nwhile [ $i -le $7 ] && [ $i -le 3 ]; do cp {} \"$9-$i.png\"; i=$((i + 1)); done\n",
+            page.display(),
+        )).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script
+    })
+}
+
+fn pdf_path() -> PathBuf {
+    std::env::temp_dir().join("agentic_terminal_doc.pdf")
+}
+
+#[test]
+fn pages_past_the_end_are_left_out() {
+    let pages = Image::from_pdf_with(fake_renderer(), pdf_path(), 2..=5).unwrap();
+
+    assert_eq!(pages.len(), 2);
+}
+
+#[test]
+fn pdf_pages_are_sent_together_up_to_the_image_limit() {
+    let path = pdf_path().display().to_string();
+    let reply = format!(r#"{{"LLMSee":"{path}"}}"#);
+    let api = MockApi::new([reply.as_str()]);
+    let options = SessionOptions { max_images: Some(2), pdf_renderer: fake_renderer().clone(), ..quiet_options() };
+    let (mut session, _, _) = mock_session(api, MockTerminal::new([]), options);
+
+    session.step().unwrap();
+
+    let n_msgs = session.llm().num_msgs();
+    let pages = session.llm().get_msg(n_msgs - 2).unwrap().get_message().content.items().to_vec();
+    assert!(matches!(&pages[0], ContentItem::Text(text) if *text == format!("Pages 1 to 2 of {path}")));
+    assert_eq!(pages.iter().filter(|item| matches!(item, ContentItem::Image(_))).count(), 2);
+    assert!(texts(&session).last().unwrap().contains(&format!("mask message {} with maskcontent", n_msgs - 2)));
+}
@@ -0,0 +1,500 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use agentic_terminal::llm::{LLMResponse, LLM, StopReason};
+use agentic_terminal::log::{load_session_log, Environment, Redactor, SessionMetadata};
+use agentic_terminal::session::{Session, SessionError, SessionOptions, StepOutcome};
+use agentic_terminal::terminal::Terminal;
+use common::{quiet_options, session, texts, MockApi};
+
+#[test]
+fn exit_finishes_the_session() {
+    let api = MockApi::new([r#"{"Command":"echo hello"}"#, r#""Exit""#]);
+    let (mut session, requests) = session(api, quiet_options());
+
+    session.run().unwrap();
+
+    assert_eq!(session.metadata().n_steps, 2);
+    assert_eq!(requests.borrow().len(), 2);
+    assert!(texts(&session).iter().any(|text| text == "hello"));
+}
+
+#[test]
+fn exit_command_finishes_the_session() {
+    let api = MockApi::new([r#"{"Command":"exit"}"#]);
+    let (mut session, _) = session(api, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(result.outcome, StepOutcome::Finished);
+}
+
+#[test]
+fn running_out_of_replies_is_an_error() {
+    let (mut session, _) = session(MockApi::new([]), quiet_options());
+
+    assert!(matches!(session.run(), Err(SessionError::Api(_))));
+}
+
+#[test]
+fn max_tokens_replies_are_joined() {
+    let api = MockApi::with_stop_reasons([
+        (r#"{"Command":"echo hel"#, StopReason::MaxTokens),
+        (r#"lo"}"#, StopReason::EndTurn),
+    ]);
+    let (mut session, requests) = session(api, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(result.command.as_deref(), Some("echo hello"));
+    assert_eq!(result.output.as_deref(), Some("hello"));
+    assert_eq!(result.exit_code, Some(0));
+    assert_eq!(requests.borrow().len(), 2);
+    // The continuation is sent the partial reply to build on
+    let continuation = requests.borrow()[1].last().map(|msg| String::try_from(&msg.content).unwrap());
+    assert!(continuation.unwrap().ends_with(r#"{"Command":"echo hel"#));
+}
+
+#[test]
+fn invalid_json_gets_a_notice() {
+    let api = MockApi::new(["I will now list the files", r#"{"Command":"echo recovered"}"#]);
+    let (mut session, _) = session(api, quiet_options());
+
+    let result = session.step().unwrap();
+    assert_eq!(result.outcome, StepOutcome::Continue);
+    assert!(result.response.is_none());
+    assert!(texts(&session).last().unwrap().starts_with("Invalid output"));
+
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("recovered"));
+}
+
+#[test]
+fn repairable_json_runs_and_is_noted() {
+    let api = MockApi::new(["```json\n{\"Command\":\"echo repaired\",}\n```"]);
+    let (mut session, _) = session(api, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(result.output.as_deref(), Some("repaired"));
+    let note = (0..session.llm().num_msgs())
+        .filter_map(|id| session.llm().get_msg(id))
+        .find(|msg| msg.is_local())
+        .unwrap();
+    assert!(String::try_from(&note.get_message().content).unwrap().starts_with("Repaired invalid json"));
+}
+
+#[test]
+fn masked_messages_are_not_sent() {
+    let api = MockApi::new([
+        r#"{"Command":"seq 1 5"}"#,
+        r#"{"MaskContent":2}"#,
+        r#""Exit""#,
+    ]);
+    let (mut session, requests) = session(api, quiet_options());
+
+    session.step().unwrap();
+    assert_eq!(texts(&session)[2], "1\n2\n3\n4\n5");
+    session.step().unwrap();
+    assert!(session.llm().get_msg(2).unwrap().is_masked());
+    session.step().unwrap();
+
+    let last_request = requests.borrow().last().cloned().unwrap();
+    let sent: Vec<String> = last_request.iter().map(|msg| String::try_from(&msg.content).unwrap()).collect();
+    assert!(sent.iter().all(|text| !text.starts_with("2>>")));
+    assert!(sent.iter().any(|text| text.ends_with("message 2 is masked")));
+}
+
+#[test]
+fn pinned_messages_cannot_be_masked() {
+    let api = MockApi::new([r#"{"MaskContent":0}"#]);
+    let (mut session, _) = session(api, quiet_options());
+
+    session.step().unwrap();
+
+    assert!(!session.llm().get_msg(0).unwrap().is_masked());
+    assert!(texts(&session).last().unwrap().starts_with("Cannot mask message 0"));
+}
+
+#[test]
+fn masking_a_missing_message_is_noted() {
+    let api = MockApi::new([r#"{"MaskContent":99}"#]);
+    let (mut session, _) = session(api, quiet_options());
+
+    session.step().unwrap();
+
+    assert_eq!(texts(&session).last().unwrap(), "There is no message 99.");
+}
+
+#[test]
+fn user_control_takes_typed_responses_until_handed_back() {
+    let api = MockApi::new([r#""UserControl""#, r#"{"Command":"echo agent"}"#]);
+    let options = SessionOptions {
+        non_interactive: false,
+        ..quiet_options()
+    };
+    let (mut session, requests) = session(api, options);
+
+    session.step().unwrap();
+    assert!(session.metadata().user_control);
+
+    let result = session.step_with_input(r#"{"Command":"echo user"}"#).unwrap();
+    assert_eq!(result.output.as_deref(), Some("user"));
+    assert!(result.usage.is_none());
+    // Notes are kept in the log without running anything
+    let result = session.step_with_input("# checked the output").unwrap();
+    assert!(result.command.is_none());
+
+    session.step_with_input(r#""AgentControl""#).unwrap();
+    assert!(!session.metadata().user_control);
+    assert_eq!(requests.borrow().len(), 1);
+
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("agent"));
+    assert!(texts(&session).iter().any(|text| text == "user"));
+}
+
+#[test]
+fn user_control_fails_when_non_interactive() {
+    let api = MockApi::new([r#""UserControl""#]);
+    let (mut session, _) = session(api, quiet_options());
+
+    assert!(matches!(session.step(), Err(SessionError::InteractionRequired(_))));
+}
+
+#[test]
+fn resumed_user_control_fails_when_non_interactive() {
+    let api = MockApi::new([]);
+    let llm = LLM::new(api, "Test system prompt".to_string());
+    let metadata = SessionMetadata { user_control: true, ..SessionMetadata::default() };
+    let mut session = Session::with_metadata(llm, Terminal::new().unwrap(), quiet_options(), metadata);
+
+    assert!(matches!(session.step(), Err(SessionError::InteractionRequired(_))));
+}
+
+#[test]
+fn pausing_fails_when_non_interactive() {
+    let api = MockApi::new([r#""Exit""#]);
+    let (mut session, requests) = session(api, quiet_options());
+    session.pause_handle().store(true, Ordering::SeqCst);
+
+    assert!(matches!(session.run(), Err(SessionError::InteractionRequired(_))));
+    assert!(requests.borrow().is_empty());
+}
+
+#[test]
+fn silent_commands_are_interrupted_with_partial_output() {
+    let api = MockApi::new([r#"{"Command":"echo started; sleep 30"}"#, r#"{"Command":"echo after"}"#]);
+    let options = SessionOptions {
+        command_timeout: Duration::from_millis(500),
+        ..quiet_options()
+    };
+    let (mut session, _) = session(api, options);
+
+    let result = session.step().unwrap();
+    assert_eq!(result.exit_code, None);
+    assert_eq!(result.output.as_deref(), Some("started"));
+    let msg = texts(&session).last().cloned().unwrap();
+    assert!(msg.starts_with("Partial output"), "{msg}");
+    assert!(msg.contains("started"), "{msg}");
+
+    // The shell is usable again once the command is interrupted
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("after"));
+}
+
+#[test]
+fn commands_ignoring_ctrl_c_are_terminated() {
+    let api = MockApi::new([r#"{"Command":"sh -c \"trap '' INT; sleep 60\""}"#, r#"{"Command":"echo after"}"#]);
+    let options = SessionOptions {
+        command_timeout: Duration::from_millis(500),
+        ..quiet_options()
+    };
+    let (mut session, _) = session(api, options);
+
+    session.step().unwrap();
+    let msg = texts(&session).last().cloned().unwrap();
+    assert!(msg.contains("stopped with SIGTERM"), "{msg}");
+
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("after"));
+}
+
+#[test]
+fn chatty_commands_ignoring_ctrl_c_are_terminated() {
+    let api = MockApi::new([
+        r#"{"Stream":"sh -c \"trap '' INT; while true; do echo hi; sleep 0.05; done\""}"#,
+        r#"{"Command":"echo after"}"#,
+    ]);
+    // The command never stops printing, so it is only interrupted by the stream deadline
+    let options = SessionOptions {
+        stream_chunk_size: 10,
+        stream_deadline: Duration::ZERO,
+        ..quiet_options()
+    };
+    let (mut session, _) = session(api, options);
+
+    session.step().unwrap();
+    let msg = texts(&session).last().cloned().unwrap();
+    assert!(msg.contains("stopped with SIGTERM"), "{msg}");
+
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("after"));
+}
+
+#[test]
+fn running_commands_are_killed_with_the_shell() {
+    let pid_file = std::env::temp_dir().join(format!("agentic_terminal_shut_down_{}", std::process::id()));
+    let mut terminal = Terminal::new().unwrap();
+    terminal.start_command(&format!(
+        "sh -c 'trap \"\" HUP TERM; echo $$ > {}; while true; do sleep 0.1; done'", pid_file.display()
+    )).unwrap();
+    let start = Instant::now();
+    let pid = loop {
+        if let Some(pid) = fs::read_to_string(&pid_file).ok().filter(|pid| pid.ends_with('\n')) {
+            break pid.trim().to_string();
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "the command never started");
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    drop(terminal);
+    fs::remove_file(&pid_file).unwrap();
+
+    // The command may be a zombie until it is reaped by whoever inherited it
+    let start = Instant::now();
+    let is_running = || fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| !stat.contains(") Z "));
+    while is_running() && start.elapsed() < Duration::from_secs(2) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!is_running(), "the command outlived the shell");
+}
+
+#[test]
+fn busy_shell_loops_are_interrupted() {
+    let api = MockApi::new([r#"{"Command":"while true; do :; done"}"#, r#"{"Command":"echo after"}"#]);
+    let options = SessionOptions {
+        command_timeout: Duration::from_millis(500),
+        ..quiet_options()
+    };
+    let (mut session, _) = session(api, options);
+
+    let start = Instant::now();
+    session.step().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(4), "{:?}", start.elapsed());
+
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("after"));
+}
+
+#[test]
+fn commands_ignoring_ctrl_c_read_at_most_one_marker() {
+    let input_file = std::env::temp_dir().join(format!("agentic_terminal_read_markers_{}", std::process::id()));
+    let command = format!(r#"{{"Command":"sh -c \"trap '' INT; while read line; do echo x >> {}; done\""}}"#, input_file.display());
+    let api = MockApi::new([command.as_str(), r#"{"Command":"echo after"}"#]);
+    let options = SessionOptions {
+        command_timeout: Duration::from_millis(500),
+        ..quiet_options()
+    };
+    let (mut session, _) = session(api, options);
+
+    session.step().unwrap();
+    let n_lines_read = fs::read_to_string(&input_file).unwrap_or_default().lines().count();
+    let _ = fs::remove_file(&input_file);
+    assert!(n_lines_read <= 1, "{n_lines_read} lines read");
+
+    let result = session.step().unwrap();
+    assert_eq!(result.output.as_deref(), Some("after"));
+}
+
+#[test]
+fn the_middle_of_large_output_is_dropped() {
+    let api = MockApi::new([r#"{"Command":"seq 1 20000"}"#]);
+    let (mut session, _) = session(api, quiet_options());
+
+    let result = session.step().unwrap();
+
+    let output = result.output.unwrap();
+    assert!(output.len() < 20 * 1024, "{} bytes", output.len());
+    assert!(output.starts_with("1\n2\n3\n"), "{output}");
+    assert!(output.ends_with("19999\n20000"), "{output}");
+    assert!(output.contains(" bytes omitted]..."), "{output}");
+}
+
+#[test]
+fn cwd_follows_the_output_when_asked_for() {
+    let api = MockApi::new([r#"{"Command":"cd /tmp && echo moved"}"#]);
+    let options = SessionOptions {
+        show_cwd: true,
+        ..quiet_options()
+    };
+    let (mut session, _) = session(api, options);
+
+    session.step().unwrap();
+
+    assert_eq!(texts(&session).last().unwrap(), "moved\n[cwd: /tmp]");
+}
+
+#[test]
+fn duration_follows_the_output_when_asked_for() {
+    let api = MockApi::new([r#"{"Command":"sleep 0.3; echo slept"}"#]);
+    let options = SessionOptions {
+        show_duration: true,
+        ..quiet_options()
+    };
+    let (mut session, _) = session(api, options);
+
+    session.step().unwrap();
+
+    let text = texts(&session).last().unwrap().clone();
+    let seconds: f64 = text
+        .strip_prefix("slept\n(took ")
+        .and_then(|rest| rest.strip_suffix("s)"))
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or_else(|| panic!("no duration in {text:?}"));
+    assert!((0.3..2.0).contains(&seconds), "{text}");
+}
+
+#[test]
+fn duration_is_kept_when_a_streamed_command_changes_directory() {
+    let api = MockApi::new([r#"{"Stream":"cd / && sleep 0.3 && echo slept"}"#]);
+    let options = SessionOptions {
+        show_duration: true,
+        ..quiet_options()
+    };
+    let (mut session, _) = session(api, options);
+
+    session.step().unwrap();
+
+    let text = texts(&session).last().unwrap().clone();
+    assert!(text.starts_with("slept\n(took "), "{text}");
+}
+
+#[test]
+fn colors_are_stripped_from_output() {
+    let api = MockApi::new([r#"{"Command":"printf '\\033[1;31mred\\033[0m plain\\n'"}"#]);
+    let (mut session, _) = session(api, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(result.output.as_deref(), Some("red plain"));
+}
+
+#[test]
+fn env_values_are_passed_literally() {
+    let api = MockApi::new([r#"{"Command":"printf '%s|%s\\n' \"$GREETING\" \"$PRICE\""}"#]);
+    let vars = HashMap::from([
+        ("GREETING".to_string(), "it's a \"quoted\" value".to_string()),
+        ("PRICE".to_string(), "$5 `date`".to_string()),
+    ]);
+    let terminal = Terminal::with_env(vars).expect("Failed to start the shell");
+    let mut session = Session::new(LLM::new(api, "Test system prompt".to_string()), terminal, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert_eq!(result.output.as_deref(), Some("it's a \"quoted\" value|$5 `date`"));
+}
+
+#[test]
+fn conditional_runs_the_matching_branch() {
+    let api = MockApi::new([r#"{"Conditional":{"command":"false","on_success":"echo yes","on_failure":"echo no"}}"#]);
+    let (mut session, _) = session(api, quiet_options());
+
+    let result = session.step().unwrap();
+
+    assert!(matches!(result.response, Some(LLMResponse::Conditional { .. })));
+    assert_eq!(result.output.as_deref(), Some("no"));
+    assert!(texts(&session).iter().any(|text| text.contains("Exit code 1, running: echo no")));
+}
+
+#[test]
+fn saved_log_holds_the_conversation() {
+    let api = MockApi::new([r#"{"Command":"echo logged"}"#, r#""Exit""#]);
+    let (mut session, _) = session(api, quiet_options());
+    session.run().unwrap();
+
+    let path = session.save_log().unwrap();
+    let log = load_session_log(&path);
+    fs::remove_file(&path).unwrap();
+    let log = log.unwrap();
+
+    assert_eq!(log.system_msg, "Test system prompt");
+    assert_eq!(log.messages.len(), session.llm().num_msgs());
+    assert_eq!(log.metadata.n_steps, 2);
+    assert_eq!(log.metadata.model.as_deref(), Some("claude-3-5-haiku-latest"));
+    assert!(log.metadata.total_cost > 0.0);
+    assert_eq!(log.metadata.total_input_tokens, 200);
+    assert_eq!(log.metadata.total_output_tokens, 20);
+    assert!(log.messages.iter().any(|msg| String::try_from(&msg.get_message().content).unwrap() == "logged"));
+}
+
+#[test]
+fn transferred_paths_are_relative_to_the_shell() {
+    let root = std::env::temp_dir().join(format!("agentic_terminal_transfer_{}", std::process::id()));
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let root = root.canonicalize().unwrap();
+    let cd = format!(r#"{{"Command":"cd {}"}}"#, root.join("sub").display());
+    let api = MockApi::new([
+        cd.as_str(),
+        r#"{"PutFile":{"path":"data.bin","base64":"AAEC"}}"#,
+        r#"{"GetFile":"../.."}"#,
+    ]);
+    let options = SessionOptions { transfer_root: root.clone(), ..quiet_options() };
+    let (mut session, _) = session(api, options);
+
+    for _ in 0..3 {
+        session.step().unwrap();
+    }
+    let written = fs::read(root.join("sub").join("data.bin"));
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(written.unwrap(), [0, 1, 2]);
+    let texts = texts(&session);
+    assert!(texts.iter().any(|text| text.contains(&format!("Wrote 3 bytes to {}", root.join("sub").join("data.bin").display()))), "{texts:?}");
+    assert!(texts.last().unwrap().contains(&format!("is outside {}", root.display())), "{texts:?}");
+}
+
+#[test]
+fn logs_are_saved_in_the_chosen_directory() {
+    let dir = std::env::temp_dir().join(format!("agentic_terminal_log_dir_{}", std::process::id())).join("project");
+    let api = MockApi::new([r#""Exit""#]);
+    let options = SessionOptions { log_dir: Some(dir.clone()), ..quiet_options() };
+    let (mut session, _) = session(api, options);
+    session.run().unwrap();
+
+    let path = session.save_log().unwrap();
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+
+    assert_eq!(path.parent(), Some(dir.as_path()));
+}
+
+#[test]
+fn redacted_logs_hold_no_secrets() {
+    let api = MockApi::new([r#"{"Command":"echo API_KEY=hunter2"}"#, r#""Exit""#]);
+    let options = SessionOptions { redactor: Some(Redactor::default()), ..quiet_options() };
+    let (mut session, _) = session(api, options);
+    session.run().unwrap();
+
+    let path = session.save_log().unwrap();
+    let log = fs::read_to_string(&path);
+    fs::remove_file(&path).unwrap();
+    let log = log.unwrap();
+
+    assert!(!log.contains("hunter2"));
+    assert!(log.contains("API_KEY=<redacted>"));
+}
+
+#[test]
+fn the_environment_is_captured_from_the_shell() {
+    let mut terminal = Terminal::new().unwrap();
+    terminal.run_command("cd /", Duration::from_secs(5)).unwrap();
+
+    let environment = Environment::capture(&mut terminal, Duration::from_secs(5));
+
+    assert_eq!(environment.working_dir.as_deref(), Some(std::path::Path::new("/")));
+    assert!(environment.shell.contains("bash"), "{}", environment.shell);
+    assert!(!environment.env_vars.contains_key("API_KEY"));
+}